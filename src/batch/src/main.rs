@@ -0,0 +1,213 @@
+// See LICENSE file for copyright and license details.
+
+//! Plays AI-vs-AI games without graphics and prints statistics.
+
+extern crate common;
+extern crate core;
+
+use std::env;
+use std::collections::{HashMap};
+use common::types::{PlayerId, Size2, ZInt, ZFloat};
+use core::core::{Core, CoreEvent, FireMode};
+use core::options::{CoreOptions, Controller};
+use core::unit::{UnitTypeId};
+
+const USAGE: &'static str = "\
+    Usage: batch [options]\n\
+    \n\
+    Options:\n\
+    \x20   --games N        number of games to play (default: 10)\n\
+    \x20   --max-turns N    game is a draw after N turns (default: 100)\n\
+    \x20   --seed N         seed of first game, game K uses seed N + K\n\
+    \x20   --map-size WxH   map size\n\
+    \x20   --json           print results as json instead of csv\n\
+";
+
+struct BatchOptions {
+    games_count: ZInt,
+    max_turns: ZInt,
+    seed: usize,
+    map_size: Option<Size2>,
+    is_json: bool,
+}
+
+struct GameResult {
+    winner: Option<PlayerId>,
+    turns: ZInt,
+    /// Unit type name -> killed soldiers count
+    casualties: HashMap<String, ZInt>,
+    reaction_fire_kills: ZInt,
+    total_kills: ZInt,
+}
+
+impl GameResult {
+    fn reaction_fire_kill_share(&self) -> ZFloat {
+        if self.total_kills == 0 {
+            0.0
+        } else {
+            self.reaction_fire_kills as ZFloat / self.total_kills as ZFloat
+        }
+    }
+}
+
+fn parse_int(s: &str) -> ZInt {
+    s.parse().ok().expect(USAGE)
+}
+
+fn parse_map_size(s: &str) -> Size2 {
+    let parts: Vec<&str> = s.split('x').collect();
+    if parts.len() != 2 {
+        panic!("{}", USAGE);
+    }
+    Size2{w: parse_int(parts[0]), h: parse_int(parts[1])}
+}
+
+fn get_batch_options() -> BatchOptions {
+    let mut options = BatchOptions {
+        games_count: 10,
+        max_turns: 100,
+        seed: 0,
+        map_size: None,
+        is_json: false,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--games" => options.games_count = parse_int(&args.next().expect(USAGE)),
+            "--max-turns" => options.max_turns = parse_int(&args.next().expect(USAGE)),
+            "--seed" => options.seed = parse_int(&args.next().expect(USAGE)) as usize,
+            "--map-size" => options.map_size = Some(parse_map_size(&args.next().expect(USAGE))),
+            "--json" => options.is_json = true,
+            _ => panic!("{}", USAGE),
+        }
+    }
+    options
+}
+
+fn get_core_options(options: &BatchOptions, game: ZInt) -> CoreOptions {
+    let mut core_options = CoreOptions::new()
+        .with_players(vec![Controller::Ai, Controller::Ai])
+        .with_seed(options.seed + game as usize)
+        .with_event_log(true);
+    if let Some(ref map_size) = options.map_size {
+        core_options = core_options.with_map_size(map_size.clone());
+    }
+    core_options
+}
+
+fn collect_casualties(core: &Core, result: &mut GameResult) {
+    let mut unit_types = HashMap::new();
+    for event in core.event_log() {
+        match event {
+            &CoreEvent::CreateUnit{ref unit_id, ref type_id, ..} => {
+                let name = core.db().unit_type(type_id).name.clone();
+                unit_types.insert(unit_id.clone(), name);
+            },
+            &CoreEvent::AttackUnit{ref defender_id, ref mode, killed, ..} => {
+                if killed == 0 {
+                    continue;
+                }
+                let name: &String = unit_types.get(defender_id)
+                    .expect("batch: Unknown defender");
+                *result.casualties.entry(name.clone()).or_insert(0) += killed;
+                result.total_kills += killed;
+                if let &FireMode::Reactive = mode {
+                    result.reaction_fire_kills += killed;
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+fn play_game(options: &BatchOptions, game: ZInt) -> GameResult {
+    let mut core = Core::new(get_core_options(options, game));
+    let players_count = core.players().len() as ZInt;
+    let mut player_turns = 0;
+    while core.winner().is_none() && player_turns < options.max_turns * players_count {
+        core.do_ai_turn();
+        player_turns += 1;
+    }
+    let mut result = GameResult {
+        winner: core.winner(),
+        turns: (player_turns + players_count - 1) / players_count,
+        casualties: HashMap::new(),
+        reaction_fire_kills: 0,
+        total_kills: 0,
+    };
+    collect_casualties(&core, &mut result);
+    result
+}
+
+fn unit_type_names(core: &Core) -> Vec<String> {
+    let db = core.db();
+    (0 .. db.unit_types_count())
+        .map(|id| db.unit_type(&UnitTypeId{id: id}).name.clone())
+        .collect()
+}
+
+fn winner_to_string(winner: &Option<PlayerId>) -> String {
+    match winner {
+        &Some(ref player_id) => format!("{}", player_id.id),
+        &None => "draw".to_string(),
+    }
+}
+
+fn print_csv(names: &[String], results: &[GameResult]) {
+    let mut header = "game,winner,turns,reaction_fire_kill_share".to_string();
+    for name in names {
+        header.push_str(&format!(",casualties_{}", name));
+    }
+    println!("{}", header);
+    for (game, result) in results.iter().enumerate() {
+        let mut line = format!("{},{},{},{:.3}",
+            game,
+            winner_to_string(&result.winner),
+            result.turns,
+            result.reaction_fire_kill_share());
+        for name in names {
+            let casualties = result.casualties.get(name).cloned().unwrap_or(0);
+            line.push_str(&format!(",{}", casualties));
+        }
+        println!("{}", line);
+    }
+}
+
+fn print_json(names: &[String], results: &[GameResult]) {
+    println!("[");
+    for (game, result) in results.iter().enumerate() {
+        let winner = match result.winner {
+            Some(ref player_id) => format!("{}", player_id.id),
+            None => "null".to_string(),
+        };
+        let casualties: Vec<String> = names.iter().map(|name| {
+            let casualties = result.casualties.get(name).cloned().unwrap_or(0);
+            format!("\"{}\": {}", name, casualties)
+        }).collect();
+        let separator = if game + 1 == results.len() { "" } else { "," };
+        println!("  {{\"game\": {}, \"winner\": {}, \"turns\": {}, \
+            \"reaction_fire_kill_share\": {:.3}, \"casualties\": {{{}}}}}{}",
+            game,
+            winner,
+            result.turns,
+            result.reaction_fire_kill_share(),
+            casualties.join(", "),
+            separator);
+    }
+    println!("]");
+}
+
+pub fn main() {
+    let options = get_batch_options();
+    let names = unit_type_names(&Core::new(get_core_options(&options, 0)));
+    let results: Vec<GameResult> = (0 .. options.games_count)
+        .map(|game| play_game(&options, game))
+        .collect();
+    if options.is_json {
+        print_json(&names, &results);
+    } else {
+        print_csv(&names, &results);
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
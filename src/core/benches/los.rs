@@ -0,0 +1,112 @@
+// See LICENSE file for copyright and license details.
+
+//! Line of sight benchmarks, run with `cargo bench` (needs nightly).
+//! Checks every pair of units on a big map with 120 units.
+
+#![feature(test)]
+
+extern crate test;
+extern crate rand;
+extern crate cgmath;
+extern crate common;
+extern crate core;
+
+use test::{Bencher, black_box};
+use rand::{Rng, SeedableRng, StdRng};
+use cgmath::{Vector2};
+use common::types::{ZInt, MapPos, Size2};
+use core::map::{Map, Terrain, distance};
+use core::db::{Db};
+use core::fov::{fov};
+use core::core::{los, LosCache};
+
+const UNITS_COUNT: usize = 120;
+
+fn make_map(rng: &mut StdRng) -> Map<Terrain> {
+    let size = Size2{w: 60, h: 60};
+    let mut map = Map::new(&size, Terrain::Plain);
+    for pos in map.get_iter() {
+        if rng.gen_range(0, 100) < 15 {
+            *map.tile_mut(&pos) = Terrain::Trees;
+        }
+    }
+    map
+}
+
+fn make_positions(rng: &mut StdRng, map: &Map<Terrain>) -> Vec<MapPos> {
+    (0 .. UNITS_COUNT).map(|_| MapPos{v: Vector2 {
+        x: rng.gen_range(0, map.size().w),
+        y: rng.gen_range(0, map.size().h),
+    }}).collect()
+}
+
+fn setup() -> (Map<Terrain>, Vec<MapPos>) {
+    let mut rng: StdRng = SeedableRng::from_seed(&[42][..]);
+    let map = make_map(&mut rng);
+    let positions = make_positions(&mut rng, &map);
+    (map, positions)
+}
+
+/// How `los` worked before: the whole fov for every check
+fn fov_los(
+    db: &Db,
+    map: &Map<Terrain>,
+    from: &MapPos,
+    to: &MapPos,
+    range: ZInt,
+) -> bool {
+    let mut v = false;
+    fov(db.shadow_tables(), map, from, range, &mut |p| if *p == *to { v = true });
+    v
+}
+
+#[bench]
+fn bench_los_full_fov(b: &mut Bencher) {
+    let (map, positions) = setup();
+    let db = Db::new();
+    let range = db.unit_type(&db.unit_type_id("soldier")).los_range;
+    b.iter(|| {
+        for from in &positions {
+            for to in &positions {
+                if distance(from, to) <= range {
+                    black_box(fov_los(&db, &map, from, to, range));
+                }
+            }
+        }
+    });
+}
+
+#[bench]
+fn bench_los_direct(b: &mut Bencher) {
+    let (map, positions) = setup();
+    let db = Db::new();
+    let unit_type = db.unit_type(&db.unit_type_id("soldier"));
+    b.iter(|| {
+        for from in &positions {
+            for to in &positions {
+                if distance(from, to) <= unit_type.los_range {
+                    black_box(los(&map, unit_type, from, to, false));
+                }
+            }
+        }
+    });
+}
+
+#[bench]
+fn bench_los_cached(b: &mut Bencher) {
+    let (map, positions) = setup();
+    let db = Db::new();
+    let unit_type = db.unit_type(&db.unit_type_id("soldier"));
+    let cache = LosCache::new();
+    b.iter(|| {
+        for from in &positions {
+            for to in &positions {
+                if distance(from, to) <= unit_type.los_range {
+                    black_box(cache.los(&map, unit_type, from, to, false));
+                }
+            }
+        }
+    });
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -0,0 +1,107 @@
+// See LICENSE file for copyright and license details.
+
+//! Pathfinder benchmarks, run with `cargo bench` (needs nightly).
+//! Soldier searches a path on a big map with obstacles in every 7th tile.
+
+#![feature(test)]
+
+extern crate test;
+extern crate cgmath;
+extern crate common;
+extern crate core;
+
+use test::{Bencher, black_box};
+use cgmath::{Vector2};
+use common::types::{ZInt, PlayerId, UnitId, MapPos, Size2};
+use core::map::{Map, Terrain};
+use core::db::{Db};
+use core::dir::{Dir, dirs};
+use core::unit::{Unit};
+use core::game_state::{GameState};
+use core::command::{MoveMode};
+use core::core::{CoreEvent};
+use core::pathfinder::{Pathfinder, move_cost};
+
+fn pos(x: ZInt, y: ZInt) -> MapPos {
+    MapPos{v: Vector2{x: x, y: y}}
+}
+
+/// Soldiers in every 7th tile, unit with id 0 is the one who moves
+fn make_state(db: &Db) -> GameState {
+    let map_size = Size2{w: 200, h: 200};
+    let mut state = GameState::new(&map_size, &PlayerId{id: 0});
+    state.disable_fow();
+    let mut id = 0;
+    for y in 0 .. map_size.h {
+        for x in 0 .. map_size.w {
+            if (x * 3 + y * 5) % 7 != 0 {
+                continue;
+            }
+            state.apply_event(db, &CoreEvent::CreateUnit {
+                unit_id: UnitId{id: id},
+                pos: pos(x, y),
+                type_id: db.unit_type_id("soldier"),
+                player_id: PlayerId{id: 0},
+            });
+            id += 1;
+        }
+    }
+    state
+}
+
+/// How `fill_map` worked before: FIFO queue that relaxes tiles again and again
+fn fifo_costs(db: &Db, state: &GameState, unit: &Unit) -> Map<ZInt> {
+    let unit_type = db.unit_type(&unit.type_id);
+    let mut costs = Map::new(state.map().size(), ZInt::max_value());
+    *costs.tile_mut(&unit.pos) = 0;
+    let mut queue = vec![unit.pos.clone()];
+    while !queue.is_empty() {
+        let pos = queue.remove(0);
+        for dir in dirs() {
+            let neighbour_pos = Dir::get_neighbour_pos(&pos, &dir);
+            if !costs.is_inboard(&neighbour_pos)
+                || !state.units_at(&neighbour_pos).is_empty()
+            {
+                continue;
+            }
+            let terrain = state.known_terrain(&neighbour_pos).unwrap_or(&Terrain::Plain);
+            let cost = *costs.tile(&pos)
+                + move_cost(unit_type, terrain, &MoveMode::Fast).n;
+            if *costs.tile(&neighbour_pos) > cost {
+                *costs.tile_mut(&neighbour_pos) = cost;
+                queue.push(neighbour_pos);
+            }
+        }
+    }
+    costs
+}
+
+#[bench]
+fn bench_fifo_fill_map(b: &mut Bencher) {
+    let db = Db::new();
+    let state = make_state(&db);
+    let unit = &state.units()[&UnitId{id: 0}];
+    b.iter(|| black_box(fifo_costs(&db, &state, unit)));
+}
+
+#[bench]
+fn bench_dijkstra_fill_map(b: &mut Bencher) {
+    let db = Db::new();
+    let state = make_state(&db);
+    let unit = &state.units()[&UnitId{id: 0}];
+    let mut pathfinder = Pathfinder::new(state.map().size());
+    b.iter(|| pathfinder.fill_map(&db, &state, unit, &MoveMode::Fast));
+}
+
+#[bench]
+fn bench_a_star_find_path(b: &mut Bencher) {
+    let db = Db::new();
+    let state = make_state(&db);
+    let unit = &state.units()[&UnitId{id: 0}];
+    let destination = pos(60, 60);
+    let mut pathfinder = Pathfinder::new(state.map().size());
+    b.iter(|| black_box(pathfinder.find_path(
+        &db, &state, unit, &destination, &MoveMode::Fast)));
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -3,12 +3,13 @@
 use common::types::{Size2, ZInt, PlayerId, MapPos};
 use game_state::{GameState};
 use map::{distance};
-use pathfinder::{MapPath, Pathfinder};
+use pathfinder::{MapPath, Pathfinder, PathCostMode};
 use dir::{Dir};
 use command::{Command, MoveMode};
 use unit::{Unit};
 use db::{Db};
-use core::{CoreEvent, los};
+use core::{CoreEvent, los, attack_odds};
+use options::{Rules};
 
 pub struct Ai {
     id: PlayerId,
@@ -18,51 +19,70 @@ pub struct Ai {
 
 impl Ai {
     pub fn new(id: &PlayerId, map_size: &Size2) -> Ai {
+        let mut pathfinder = Pathfinder::new(map_size);
+        // don`t walk past every enemy gun on the way to the target
+        pathfinder.set_cost_mode(PathCostMode::Safe);
         Ai {
             id: id.clone(),
             state: GameState::new(map_size, id),
-            pathfinder: Pathfinder::new(map_size),
+            pathfinder: pathfinder,
         }
     }
 
+    pub fn disable_fow(&mut self) {
+        self.state.disable_fow();
+    }
+
+    pub fn disable_zoc(&mut self) {
+        self.pathfinder.disable_zoc();
+    }
+
+    pub fn set_rules(&mut self, rules: &Rules) {
+        self.state.set_rules(rules);
+    }
+
+    pub fn set_last_seen_turns(&mut self, turns: ZInt) {
+        self.state.set_last_seen_turns(turns);
+    }
+
     pub fn apply_event(&mut self, db: &Db, event: &CoreEvent) {
         self.state.apply_event(db, event);
     }
 
-    // TODO: move fill_map here
-    fn get_best_pos(&self) -> Option<MapPos> {
-        let mut best_pos = None;
-        let mut best_cost = None;
+    /// Positions of visible enemies. If there are none,
+    /// the places where enemies were seen last time.
+    fn enemy_positions(&self) -> Vec<MapPos> {
+        let mut positions = Vec::new();
         for (_, enemy) in self.state.units() {
-            if enemy.player_id == self.id {
-                continue;
+            if enemy.player_id != self.id {
+                positions.push(enemy.pos.clone());
             }
+        }
+        if positions.is_empty() {
+            for (_, last_seen) in self.state.last_seen() {
+                positions.push(last_seen.pos.clone());
+            }
+        }
+        positions
+    }
+
+    /// Free tiles next to known enemies
+    fn attack_destinations(&self) -> Vec<MapPos> {
+        let mut destinations = Vec::new();
+        for enemy_pos in self.enemy_positions() {
             for i in 0 .. 6 {
                 let dir = Dir::from_int(i);
-                let destination = Dir::get_neighbour_pos(&enemy.pos, &dir);
+                let destination = Dir::get_neighbour_pos(&enemy_pos, &dir);
                 if !self.state.map().is_inboard(&destination) {
                     continue;
                 }
                 if self.state.is_tile_occupied(&destination) {
                     continue;
                 }
-                let path = match self.pathfinder.get_path(&destination) {
-                    Some(path) => path,
-                    None => continue,
-                };
-                let cost = path.total_cost().n;
-                if let Some(ref mut best_cost) = best_cost {
-                    if *best_cost > cost {
-                        *best_cost = cost;
-                        best_pos = Some(destination.clone());
-                    }
-                } else {
-                    best_cost = Some(cost);
-                    best_pos = Some(destination.clone());
-                }
+                destinations.push(destination);
             }
         }
-        best_pos
+        destinations
     }
 
     fn truncate_path(&self, path: MapPath, move_points: ZInt) -> MapPath {
@@ -96,15 +116,18 @@ impl Ai {
     }
 
     pub fn try_get_move_command(&mut self, db: &Db) -> Option<Command> {
+        let is_symmetric = self.state.is_los_symmetric();
         for (_, unit) in self.state.units() {
             if unit.player_id != self.id {
                 continue;
             }
             // println!("id: {}, ap: {}", unit.id.id, unit.attack_points);
-            if unit.attack_points <= 0 {
+            if unit.attack_points <= 0 || unit.morale < 50 {
                 continue;
             }
             let unit_type = db.unit_type(&unit.type_id);
+            let mut best_target_id = None;
+            let mut best_casualties = 0.0;
             for (_, target) in self.state.units() {
                 if target.player_id == self.id {
                     continue;
@@ -113,12 +136,21 @@ impl Ai {
                 if distance(&unit.pos, &target.pos) > max_distance {
                     continue;
                 }
-                if !los(self.state.map(), unit_type, &unit.pos, &target.pos) {
+                if !los(self.state.map(), unit_type, &unit.pos, &target.pos, is_symmetric) {
                     continue;
                 }
+                let odds = attack_odds(db, self.state.rules(), unit, target, &unit.pos);
+                if best_target_id.is_none()
+                    || odds.expected_casualties > best_casualties
+                {
+                    best_casualties = odds.expected_casualties;
+                    best_target_id = Some(target.id.clone());
+                }
+            }
+            if let Some(target_id) = best_target_id {
                 return Some(Command::AttackUnit {
                     attacker_id: unit.id.clone(),
-                    defender_id: target.id.clone(),
+                    defender_id: target_id,
                 });
             }
         }
@@ -133,18 +165,29 @@ impl Ai {
             if self.is_close_to_enemies(db, unit) {
                 continue;
             }
-            self.pathfinder.fill_map(db, &self.state, unit);
-            let destination = match self.get_best_pos() {
-                Some(destination) => destination,
-                None => continue,
-            };
-            let path = match self.pathfinder.get_path(&destination) {
-                Some(path) => path,
-                None => continue,
-            };
             if unit.move_points == 0 {
                 continue;
             }
+            let mut best_path: Option<MapPath> = None;
+            for destination in self.attack_destinations() {
+                let path = match self.pathfinder.find_path(
+                    db, &self.state, unit, &destination, &MoveMode::Fast)
+                {
+                    Some(path) => path,
+                    None => continue,
+                };
+                let is_better = match best_path {
+                    Some(ref best) => best.total_cost().n > path.total_cost().n,
+                    None => true,
+                };
+                if is_better {
+                    best_path = Some(path);
+                }
+            }
+            let path = match best_path {
+                Some(path) => path,
+                None => continue,
+            };
             let path = self.truncate_path(path, unit.move_points);
             return Some(Command::Move {
                 unit_id: unit.id.clone(),
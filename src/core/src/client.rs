@@ -0,0 +1,226 @@
+// See LICENSE file for copyright and license details.
+
+//! Network game client: sends commands to `Server`
+//! and receives its player`s fog-filtered events.
+//! Observer clients receive events of all perspectives
+//! and can switch between them at any time.
+
+use std::io::{BufRead, BufReader, Write};
+use std::collections::{HashMap, LinkedList};
+use std::net::{TcpStream};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use common::types::{ZInt, PlayerId, Size2};
+use core::{CoreEvent};
+use game_state::{Perspective};
+use command::{Command, CommandError};
+use options::{CoreOptions, Controller};
+use db::{Db};
+use protocol::{Message, PROTOCOL_VERSION, encode, decode};
+
+fn read_messages(reader: BufReader<TcpStream>, sender: Sender<Message>) {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let message = match decode(&line) {
+            Ok(message) => message,
+            Err(err) => Message::Error{text: err},
+        };
+        if sender.send(message).is_err() {
+            break;
+        }
+    }
+}
+
+fn send(stream: &mut TcpStream, message: &Message) -> Result<(), String> {
+    let line = format!("{}\n", encode(message));
+    stream.write_all(line.as_bytes()).map_err(|err| format!("{}", err))
+}
+
+pub struct Client {
+    stream: TcpStream,
+    receiver: Receiver<Message>,
+    /// None for observers
+    player_id: Option<PlayerId>,
+    /// Needed to rejoin the same slot, None for observers
+    token: Option<String>,
+    current_player_id: PlayerId,
+    perspective: Perspective,
+    options: CoreOptions,
+    db: Db,
+    events: HashMap<Perspective, LinkedList<CoreEvent>>,
+    /// Perspectives that server sends snapshots of, in order of snapshots
+    perspectives: Vec<Perspective>,
+}
+
+impl Client {
+    /// Joins game on server. If `player_id` is None
+    /// then server assigns any free slot.
+    /// Pass old player id and `token` to reconnect.
+    pub fn connect(address: &str, player_id: Option<PlayerId>, token: Option<String>)
+        -> Result<Client, String>
+    {
+        Client::handshake(address, &Message::Join {
+            version: PROTOCOL_VERSION,
+            player_id: player_id,
+            token: token,
+        })
+    }
+
+    /// Joins game as read-only observer
+    pub fn observe(address: &str) -> Result<Client, String> {
+        Client::handshake(address, &Message::Observe{version: PROTOCOL_VERSION})
+    }
+
+    fn handshake(address: &str, message: &Message) -> Result<Client, String> {
+        let mut stream = try!(TcpStream::connect(address)
+            .map_err(|err| format!("Can`t connect to {}: {}", address, err)));
+        try!(send(&mut stream, message));
+        let mut reader = BufReader::new(try!(stream.try_clone()
+            .map_err(|err| format!("{}", err))));
+        let mut line = String::new();
+        try!(reader.read_line(&mut line).map_err(|err| format!("{}", err)));
+        let (player_id, token, current_player_id, options) = match try!(decode(&line)) {
+            Message::Welcome {
+                player_id,
+                token,
+                current_player_id,
+                players_count,
+                map_size,
+                fow,
+                symmetric_los,
+            } => {
+                let mut options = CoreOptions::new()
+                    .with_map_size(map_size)
+                    .with_players(vec![Controller::Human; players_count as usize])
+                    .with_fow(fow);
+                options.rules.symmetric_los = symmetric_los;
+                (player_id, token, current_player_id, options)
+            },
+            Message::Error{text} => return Err(text),
+            _ => return Err("Unexpected answer to handshake".to_string()),
+        };
+        let perspective = match player_id {
+            Some(ref player_id) => Perspective::Player(player_id.clone()),
+            None => Perspective::Full,
+        };
+        let (sender, receiver) = channel();
+        thread::spawn(move || read_messages(reader, sender));
+        Ok(Client {
+            stream: stream,
+            receiver: receiver,
+            player_id: player_id,
+            token: token,
+            current_player_id: current_player_id,
+            perspective: perspective,
+            options: options,
+            db: Db::new(),
+            events: HashMap::new(),
+            perspectives: Vec::new(),
+        })
+    }
+
+    /// Player controlled by this client, None for observers
+    pub fn player_id(&self) -> &Option<PlayerId> {
+        &self.player_id
+    }
+
+    /// Secret of client`s slot, pass it to `connect` to rejoin
+    pub fn token(&self) -> &Option<String> {
+        &self.token
+    }
+
+    pub fn current_player_id(&self) -> &PlayerId {
+        &self.current_player_id
+    }
+
+    /// Whose events are returned by `get_event`
+    pub fn perspective(&self) -> &Perspective {
+        &self.perspective
+    }
+
+    /// Perspectives that server sent so far: full one and
+    /// human players` ones for observers, own one for players
+    pub fn perspectives(&self) -> &Vec<Perspective> {
+        &self.perspectives
+    }
+
+    /// Only observers can switch perspective
+    pub fn set_perspective(&mut self, perspective: Perspective) {
+        assert!(self.player_id.is_none());
+        self.perspective = perspective;
+    }
+
+    /// Options known to client: map size, number of players,
+    /// fow and line of sight rule
+    pub fn options(&self) -> &CoreOptions {
+        &self.options
+    }
+
+    /// Server doesn`t care how long client remembers hidden enemies
+    pub fn set_last_seen_turns(&mut self, turns: ZInt) {
+        self.options.last_seen_turns = turns;
+    }
+
+    pub fn map_size(&self) -> &Size2 {
+        &self.options.scenario.map_size
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// Only basic check, server validates commands itself
+    pub fn check_command(&self, _: &Command) -> Result<(), CommandError> {
+        match self.player_id {
+            None => Err(CommandError::ObserverCanNotCommand),
+            Some(ref player_id) if *player_id != self.current_player_id => {
+                Err(CommandError::NotYourTurn)
+            },
+            Some(_) => Ok(()),
+        }
+    }
+
+    pub fn do_command(&mut self, command: Command) {
+        if self.player_id.is_none() {
+            println!("CLIENT: {}", CommandError::ObserverCanNotCommand.description());
+            return;
+        }
+        if let Err(err) = send(&mut self.stream, &Message::Command{command: command}) {
+            println!("CLIENT: Can`t send command: {}", err);
+        }
+    }
+
+    fn queue(&mut self, perspective: Perspective) -> &mut LinkedList<CoreEvent> {
+        self.events.entry(perspective).or_insert(LinkedList::new())
+    }
+
+    /// Non-blocking. Returns next event of current perspective.
+    pub fn get_event(&mut self) -> Option<CoreEvent> {
+        while let Ok(message) = self.receiver.try_recv() {
+            match message {
+                Message::Event{perspective, event} => {
+                    self.queue(perspective).push_back(event);
+                },
+                Message::Snapshot{perspective, events} => {
+                    if !self.perspectives.contains(&perspective) {
+                        self.perspectives.push(perspective.clone());
+                    }
+                    self.queue(perspective).extend(events);
+                },
+                Message::Error{text} => println!("SERVER ERROR: {}", text),
+                _ => println!("CLIENT: Unexpected message"),
+            }
+        }
+        let perspective = self.perspective.clone();
+        let event = self.queue(perspective).pop_front();
+        if let Some(CoreEvent::EndTurn{ref new_id, ..}) = event {
+            self.current_player_id = new_id.clone();
+        }
+        event
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -2,6 +2,7 @@
 
 use common::types::{UnitId, MapPos};
 use pathfinder::{MapPath};
+use unit::{UnitTypeId};
 
 #[derive(Clone)]
 pub enum MoveMode {
@@ -12,9 +13,85 @@ pub enum MoveMode {
 #[derive(Clone)]
 pub enum Command {
     Move{unit_id: UnitId, path: MapPath, mode: MoveMode},
+    /// Moves of several units, resolved one by one in given order.
+    /// Paths are planned by `group_move::plan_group_move`.
+    MoveGroup{moves: Vec<(UnitId, MapPath)>, mode: MoveMode},
     EndTurn,
-    CreateUnit{pos: MapPos},
+    CreateUnit{pos: MapPos, type_id: UnitTypeId},
     AttackUnit{attacker_id: UnitId, defender_id: UnitId},
+    Assault{attacker_id: UnitId, target_pos: MapPos},
+    /// Revert last move of current player
+    Undo,
+    /// Unit will move through `waypoints` in this and next turns,
+    /// empty `waypoints` cancel the order. See `move_order`.
+    SetMoveOrder{unit_id: UnitId, waypoints: Vec<MapPos>},
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandError {
+    TileIsOccupied,
+    NotInReinforcementZone,
+    NotEnoughReinforcementPoints,
+    UnitIsImmobilized,
+    WeaponIsDisabled,
+    OnlyInfantryCanAssault,
+    TargetIsNotAdjacent,
+    NoEnemyAtTargetPos,
+    NoAttackPoints,
+    NoMovePoints,
+    UnitIsSuppressed,
+    NothingToUndo,
+    NotYourTurn,
+    ObserverCanNotCommand,
+    /// Path doesn`t start at unit or has gaps
+    BadPath,
+    /// Path costs don`t match terrain and move mode
+    BadPathCost,
+    NotEnoughMovePoints,
+    /// Unit doesn`t exist or is hidden from current player
+    NoSuchUnit,
+    NotYourUnit,
+    NoSuchUnitType,
+    /// Target doesn`t exist or is hidden from current player
+    TargetIsNotVisible,
+    CanNotAttackFriendlyUnit,
+    TargetIsOutOfRange,
+    NoLineOfSight,
+    WaypointIsOffMap,
+}
+
+impl CommandError {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            CommandError::TileIsOccupied => "Tile is occupied",
+            CommandError::NotInReinforcementZone
+                => "Can`t place reinforcements outside of deployment zone",
+            CommandError::NotEnoughReinforcementPoints
+                => "Not enough reinforcement points",
+            CommandError::UnitIsImmobilized => "Unit is immobilized",
+            CommandError::WeaponIsDisabled => "Weapon is disabled",
+            CommandError::OnlyInfantryCanAssault => "Only infantry can assault",
+            CommandError::TargetIsNotAdjacent => "Target is not adjacent",
+            CommandError::NoEnemyAtTargetPos => "No enemy in target tile",
+            CommandError::NoAttackPoints => "No attack points",
+            CommandError::NoMovePoints => "No move points",
+            CommandError::UnitIsSuppressed => "Unit is suppressed",
+            CommandError::NothingToUndo => "Nothing to undo",
+            CommandError::NotYourTurn => "Not your turn",
+            CommandError::ObserverCanNotCommand => "Observers can`t give commands",
+            CommandError::BadPath => "Bad path",
+            CommandError::BadPathCost => "Path cost doesn`t match terrain",
+            CommandError::NotEnoughMovePoints => "Not enough move points",
+            CommandError::NoSuchUnit => "No such unit",
+            CommandError::NotYourUnit => "Not your unit",
+            CommandError::NoSuchUnitType => "No such unit type",
+            CommandError::TargetIsNotVisible => "Target is not visible",
+            CommandError::CanNotAttackFriendlyUnit => "Can`t attack friendly unit",
+            CommandError::TargetIsOutOfRange => "Target is out of range",
+            CommandError::NoLineOfSight => "No line of sight",
+            CommandError::WaypointIsOffMap => "Waypoint is off the map",
+        }
+    }
 }
 
 // vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -0,0 +1,81 @@
+// See LICENSE file for copyright and license details.
+
+//! Debug checks of per-player fog-filtered event streams.
+
+use std::collections::{HashSet};
+use common::types::{PlayerId, UnitId};
+use internal_state::{InternalState};
+use game_state::{GameState};
+use fow::{Fow};
+
+/// Compares `shadow` (state built from filtered events only)
+/// with what player should see in authoritative `state`.
+/// Returns list of found divergences.
+pub fn check_consistency(
+    state: &InternalState,
+    fow: &Fow,
+    visible_enemies: &HashSet<UnitId>,
+    player_id: &PlayerId,
+    shadow: &GameState,
+) -> Vec<String> {
+    let mut report = Vec::new();
+    let mut expected_ids: Vec<&UnitId> = Vec::new();
+    for (id, unit) in state.units() {
+        if unit.player_id == *player_id || visible_enemies.contains(id) {
+            expected_ids.push(id);
+        }
+    }
+    if expected_ids.len() != shadow.units().len() {
+        report.push(format!("visible units count: expected {}, got {}",
+            expected_ids.len(), shadow.units().len()));
+    }
+    for &id in &expected_ids {
+        let unit = state.unit(id);
+        let shadow_unit = match shadow.units().get(id) {
+            Some(shadow_unit) => shadow_unit,
+            None => {
+                report.push(format!("unit {} at ({}, {}) is missing",
+                    id.id, unit.pos.v.x, unit.pos.v.y));
+                continue;
+            },
+        };
+        if shadow_unit.pos != unit.pos {
+            report.push(format!("unit {}: expected pos ({}, {}), got ({}, {})",
+                id.id,
+                unit.pos.v.x, unit.pos.v.y,
+                shadow_unit.pos.v.x, shadow_unit.pos.v.y));
+        }
+        if shadow_unit.player_id != unit.player_id {
+            report.push(format!("unit {}: expected player {}, got {}",
+                id.id, unit.player_id.id, shadow_unit.player_id.id));
+        }
+        if shadow_unit.type_id.id != unit.type_id.id {
+            report.push(format!("unit {}: expected type {}, got {}",
+                id.id, unit.type_id.id, shadow_unit.type_id.id));
+        }
+        // enemy`s count is unknown to player
+        if unit.player_id == *player_id && shadow_unit.count != unit.count {
+            report.push(format!("unit {}: expected count {}, got {}",
+                id.id, unit.count, shadow_unit.count));
+        }
+    }
+    for (id, unit) in shadow.units() {
+        if !expected_ids.contains(&id) {
+            report.push(format!("unit {} at ({}, {}) must be hidden",
+                id.id, unit.pos.v.x, unit.pos.v.y));
+        }
+    }
+    for pos in state.map().get_iter() {
+        if *fow.tile_visibility(&pos) != *shadow.fow().tile_visibility(&pos) {
+            report.push(format!("fow tile ({}, {}) differs", pos.v.x, pos.v.y));
+        }
+    }
+    let points = state.reinforcement_points(player_id);
+    if shadow.reinforcement_points(player_id) != points {
+        report.push(format!("reinforcement points: expected {}, got {}",
+            points, shadow.reinforcement_points(player_id)));
+    }
+    report
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
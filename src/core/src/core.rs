@@ -1,25 +1,37 @@
 // See LICENSE file for copyright and license details.
 
-use rand::{thread_rng, Rng};
-use std::collections::{HashMap, HashSet, LinkedList};
-use cgmath::{Vector2};
-use common::types::{Size2, ZInt, UnitId, PlayerId, MapPos};
+use std::cmp;
+use std::cell::{RefCell, RefMut};
+use rand::{thread_rng, Rng, SeedableRng, StdRng};
+use std::collections::{HashMap, HashSet, BTreeMap, LinkedList};
+use common::types::{Size2, ZInt, ZFloat, UnitId, PlayerId, MapPos};
 use common::misc::{clamp};
 use internal_state::{InternalState};
 use map::{Map, Terrain, distance};
-use pathfinder::{MapPath, PathNode, MoveCost};
-use command::{Command, MoveMode};
-use unit::{Unit, UnitType, UnitTypeId, UnitClass};
+use dir::{dirs, Dir};
+use pathfinder::{MapPath, PathNode, MoveCost, is_zoc, move_cost};
+use command::{Command, CommandError, MoveMode};
+use unit::{Unit, UnitType, UnitTypeId, UnitClass, VehicleDamage};
 use db::{Db};
 use player::{Player};
 use ai::{Ai};
-use fow::{Fow};
-use fov::{fov};
+use fow::{Fow, visibility_for, needs_spotting_roll, spotting_chance};
+use game_state::{GameState};
+use consistency::{check_consistency};
+use fov::{is_in_fov};
+use scenario::{Scenario};
+use options::{CoreOptions, Controller, Rules};
+use turn_packet::{TurnPacket, TurnPacketError, Signature, sign, is_same_mac};
+use protocol::{encode_event, encode_command};
 
 #[derive(Clone)]
 pub enum FireMode {
     Active,
     Reactive,
+    /// Fire of assaulted unit at assaulting unit, doesn`t cost attack points
+    Defensive,
+    /// Close assault, spends all attack points of assaulting unit
+    Melee,
 }
 
 #[derive(Clone)]
@@ -29,6 +41,12 @@ pub enum CoreEvent {
         path: MapPath,
         mode: MoveMode,
     },
+    /// Reverts `Move` event with the same fields
+    UndoMove {
+        unit_id: UnitId,
+        path: MapPath,
+        mode: MoveMode,
+    },
     EndTurn{old_id: PlayerId, new_id: PlayerId},
     CreateUnit {
         unit_id: UnitId,
@@ -43,16 +61,42 @@ pub enum CoreEvent {
         killed: ZInt,
         suppression: ZInt,
         remove_move_points: bool,
+        damage: Option<VehicleDamage>,
     },
     ShowUnit {
         unit_id: UnitId,
         pos: MapPos,
         type_id: UnitTypeId,
         player_id: PlayerId,
+        is_immobilized: bool,
+        is_weapon_disabled: bool,
     },
     HideUnit {
         unit_id: UnitId,
     },
+    SetReinforcementPoints {
+        player_id: PlayerId,
+        points: ZInt,
+    },
+    RepairUnit {
+        unit_id: UnitId,
+        damage: VehicleDamage,
+    },
+    /// Seen only by unit`s owner
+    SetMoveOrder {
+        unit_id: UnitId,
+        waypoints: Vec<MapPos>,
+    },
+}
+
+const CREW_SHAKEN_SUPPRESSION: ZInt = 40;
+
+const DEFENSIVE_FIRE_SHOTS: ZInt = 2;
+
+enum AssaultResult {
+    Repulsed,
+    Retreat,
+    Destroyed,
 }
 
 fn is_target_dead(state: &InternalState, event: &CoreEvent) -> bool {
@@ -64,30 +108,16 @@ fn is_target_dead(state: &InternalState, event: &CoreEvent) -> bool {
     }
 }
 
-fn get_visible_enemies(
-    db: &Db,
-    fow: &Fow,
-    units: &HashMap<UnitId, Unit>,
-    player_id: &PlayerId,
-) -> HashSet<UnitId> {
-    let mut visible_enemies = HashSet::new();
-    for (id, unit) in units {
-        let unit_type = db.unit_type(&unit.type_id);
-        if unit.player_id != *player_id && fow.is_visible(unit_type, &unit.pos) {
-            visible_enemies.insert(id.clone());
-        }
-    }
-    visible_enemies
-}
-
 fn show_or_hide_passive_enemies(
-    units: &HashMap<UnitId, Unit>,
+    units: &BTreeMap<UnitId, Unit>,
     active_unit_ids: &HashSet<UnitId>,
     old: &HashSet<UnitId>,
     new: &HashSet<UnitId>,
 ) -> LinkedList<CoreEvent> {
     let mut events = LinkedList::new();
-    let located_units = new.difference(old);
+    // sorted to make events order independent of hash seeds
+    let mut located_units: Vec<&UnitId> = new.difference(old).collect();
+    located_units.sort();
     for id in located_units {
         if active_unit_ids.contains(id) {
             continue;
@@ -98,9 +128,12 @@ fn show_or_hide_passive_enemies(
             pos: unit.pos.clone(),
             type_id: unit.type_id.clone(),
             player_id: unit.player_id.clone(),
+            is_immobilized: unit.is_immobilized,
+            is_weapon_disabled: unit.is_weapon_disabled,
         });
     }
-    let lost_units = old.difference(new);
+    let mut lost_units: Vec<&UnitId> = old.difference(new).collect();
+    lost_units.sort();
     for id in lost_units {
         if active_unit_ids.contains(id) {
             continue;
@@ -114,6 +147,13 @@ struct PlayerInfo {
     events: LinkedList<CoreEvent>,
     fow: Fow,
     visible_enemies: HashSet<UnitId>,
+    /// Results of spotting rolls for enemy units in tiles
+    /// with `Bad` visibility. Cleared when player`s turn starts.
+    spotting_rolls: RefCell<HashMap<UnitId, Vec<(MapPos, bool)>>>,
+    /// State that is built only from filtered events (debug mode)
+    shadow_state: Option<GameState>,
+    /// All filtered events. Empty if `CoreOptions::event_log` is disabled.
+    history: Vec<CoreEvent>,
 }
 
 pub struct Core {
@@ -121,60 +161,322 @@ pub struct Core {
     players: Vec<Player>,
     current_player_id: PlayerId,
     db: Db,
-    ai: Ai,
+    ais: HashMap<PlayerId, Ai>,
     players_info: HashMap<PlayerId, PlayerInfo>,
     next_unit_id: UnitId,
+    scenario: Scenario,
+    options: CoreOptions,
+    rng: RefCell<StdRng>,
+    los_cache: LosCache,
+    /// Units that moved or fired since their player`s turn started.
+    /// Reactive and defensive fire marks units while events are
+    /// computed, hence `RefCell`.
+    active_unit_ids: RefCell<HashSet<UnitId>>,
+    is_ai_running: bool,
+    event_log: Vec<CoreEvent>,
+    /// Reverts last move of current player while undo is allowed
+    undo_event: Option<CoreEvent>,
+    /// Number of turn packets exported or imported so far
+    turn_packets_count: ZInt,
+    /// All executed commands with their authors (with event log only)
+    command_log: Vec<(PlayerId, Command)>,
+    /// Commands of turn packet are replayed, ai turns are in the packet too
+    is_replaying: bool,
+    signatures: Vec<Signature>,
+    /// Divergences of filtered players` states (debug mode)
+    consistency_report: Vec<String>,
 }
 
-fn get_players_list() -> Vec<Player> {
-    vec!(
-        Player{id: PlayerId{id: 0}, is_ai: false},
-        // Player{id: PlayerId{id: 1}, is_ai: true},
-        Player{id: PlayerId{id: 1}, is_ai: false},
-    )
+/// Protection from ai that repeats commands that do nothing
+const MAX_AI_COMMANDS_PER_TURN: ZInt = 200;
+
+fn get_players_list(options: &CoreOptions) -> Vec<Player> {
+    let mut players = Vec::new();
+    for (id, controller) in options.players.iter().enumerate() {
+        players.push(Player {
+            id: PlayerId{id: id as ZInt},
+            is_ai: *controller == Controller::Ai,
+        });
+    }
+    players
 }
 
-fn get_player_info_lists(map_size: &Size2) -> HashMap<PlayerId, PlayerInfo> {
+fn get_player_info_lists(options: &CoreOptions, players: &Vec<Player>)
+    -> HashMap<PlayerId, PlayerInfo>
+{
+    let map_size = &options.scenario.map_size;
     let mut map = HashMap::new();
-    map.insert(PlayerId{id: 0}, PlayerInfo {
-        fow: Fow::new(map_size, &PlayerId{id: 0}),
-        events: LinkedList::new(),
-        visible_enemies: HashSet::new(),
-    });
-    map.insert(PlayerId{id: 1}, PlayerInfo {
-        fow: Fow::new(map_size, &PlayerId{id: 1}),
-        events: LinkedList::new(),
-        visible_enemies: HashSet::new(),
-    });
+    for player in players {
+        let mut fow = Fow::new(map_size, &player.id);
+        if !options.fow {
+            fow.disable();
+        }
+        if options.rules.symmetric_los {
+            fow.enable_symmetric_los();
+        }
+        let shadow_state = if options.check_consistency {
+            let mut state = GameState::new(map_size, &player.id);
+            if !options.fow {
+                state.disable_fow();
+            }
+            state.set_rules(&options.rules);
+            Some(state)
+        } else {
+            None
+        };
+        map.insert(player.id.clone(), PlayerInfo {
+            fow: fow,
+            events: LinkedList::new(),
+            visible_enemies: HashSet::new(),
+            spotting_rolls: RefCell::new(HashMap::new()),
+            shadow_state: shadow_state,
+            history: Vec::new(),
+        });
+    }
     map
 }
 
+fn get_ais(options: &CoreOptions, players: &Vec<Player>) -> HashMap<PlayerId, Ai> {
+    let mut ais = HashMap::new();
+    for player in players {
+        if player.is_ai {
+            let mut ai = Ai::new(&player.id, &options.scenario.map_size);
+            if !options.fow {
+                ai.disable_fow();
+            }
+            if !options.rules.zoc {
+                ai.disable_zoc();
+            }
+            ai.set_rules(&options.rules);
+            ai.set_last_seen_turns(options.last_seen_turns);
+            ais.insert(player.id.clone(), ai);
+        }
+    }
+    ais
+}
+
+fn get_rng(options: &CoreOptions) -> StdRng {
+    let seed = options.seed.expect("core: No seed");
+    StdRng::from_seed(&[seed][..])
+}
+
+/// Dice roll range of `hit_test`: [TEST_ROLL_MIN, TEST_ROLL_MAX)
+const TEST_ROLL_MIN: ZInt = -5;
+const TEST_ROLL_MAX: ZInt = 5;
+
+/// Range of killed soldiers per successful hit: [KILLED_MIN, KILLED_MAX)
+const KILLED_MIN: ZInt = 1;
+const KILLED_MAX: ZInt = 5;
+
+/// Values that dice rolls are compared against in `hit_test`
+struct TestValues {
+    hit: ZInt,
+    pierce: ZInt,
+    wound: ZInt,
+}
+
+fn get_test_values(db: &Db, attacker: &Unit, defender: &Unit) -> TestValues {
+    let attacker_type = db.unit_type(&attacker.type_id);
+    let defender_type = db.unit_type(&defender.type_id);
+    let weapon_type = db.weapon_type(&attacker_type.weapon_type_id);
+    TestValues {
+        hit: -15 + defender_type.size
+            + weapon_type.accuracy + attacker_type.weapon_skill,
+        pierce: 5 + -defender_type.armor + weapon_type.ap,
+        wound: -defender_type.toughness + weapon_type.damage,
+    }
+}
+
+/// Probability of `roll < needed` where roll is in [TEST_ROLL_MIN, TEST_ROLL_MAX)
+fn test_probability(needed: ZInt) -> ZFloat {
+    let range = TEST_ROLL_MAX - TEST_ROLL_MIN;
+    let successes = clamp(needed - TEST_ROLL_MIN, 0, range);
+    successes as ZFloat / range as ZFloat
+}
+
+pub struct AttackOdds {
+    pub hit: ZFloat,
+    pub pierce: ZFloat,
+    pub wound: ZFloat,
+    pub expected_casualties: ZFloat,
+}
+
+/// Analytical version of `Core::hit_test` + `Core::get_killed_count`
+pub fn attack_odds(
+    db: &Db,
+    rules: &Rules,
+    attacker: &Unit,
+    defender: &Unit,
+    firing_pos: &MapPos,
+) -> AttackOdds {
+    attack_odds_at(db, rules, attacker, firing_pos, defender, &defender.pos)
+}
+
+/// Same as `attack_odds`, but defender is placed in `defender_pos`
+pub fn attack_odds_at(
+    db: &Db,
+    rules: &Rules,
+    attacker: &Unit,
+    firing_pos: &MapPos,
+    defender: &Unit,
+    defender_pos: &MapPos,
+) -> AttackOdds {
+    let max_distance = db.unit_max_attack_dist(attacker);
+    if distance(firing_pos, defender_pos) > max_distance {
+        return AttackOdds {
+            hit: 0.0,
+            pierce: 0.0,
+            wound: 0.0,
+            expected_casualties: 0.0,
+        };
+    }
+    let values = get_test_values(db, attacker, defender);
+    let hit = test_probability(values.hit);
+    let pierce = test_probability(values.pierce);
+    let wound = test_probability(values.wound);
+    let defender_type = db.unit_type(&defender.type_id);
+    let casualties_per_hit = match defender_type.class {
+        UnitClass::Infantry => {
+            let mut sum = 0;
+            for killed in KILLED_MIN .. KILLED_MAX {
+                sum += clamp(killed, 1, defender.count);
+            }
+            sum as ZFloat / (KILLED_MAX - KILLED_MIN) as ZFloat
+        },
+        UnitClass::Vehicle => if !rules.vehicle_damage {
+            1.0
+        } else {
+            let table = db.vehicle_damage_table();
+            let mut total_weight = 0;
+            let mut destroyed_weight = 0;
+            for row in table {
+                total_weight += row.weight;
+                if row.damage == VehicleDamage::Destroyed {
+                    destroyed_weight += row.weight;
+                }
+            }
+            destroyed_weight as ZFloat / total_weight as ZFloat
+        },
+    };
+    AttackOdds {
+        hit: hit,
+        pierce: pierce,
+        wound: wound,
+        expected_casualties: hit * pierce * wound * casualties_per_hit,
+    }
+}
+
+/// `can_fire_at` without line of sight check
+fn can_fire_at_ignoring_los(db: &Db, attacker: &Unit, pos: &MapPos) -> bool {
+    if attacker.morale < 50 || attacker.is_weapon_disabled {
+        return false;
+    }
+    let max_distance = db.unit_max_attack_dist(attacker);
+    distance(&attacker.pos, pos) <= max_distance
+}
+
+/// Checks that do not depend on hidden information (attack/reactive points)
+pub fn can_fire_at(
+    db: &Db,
+    map: &Map<Terrain>,
+    attacker: &Unit,
+    pos: &MapPos,
+    is_los_symmetric: bool,
+) -> bool {
+    if !can_fire_at_ignoring_los(db, attacker, pos) {
+        return false;
+    }
+    let attacker_type = db.unit_type(&attacker.type_id);
+    los(map, attacker_type, &attacker.pos, pos, is_los_symmetric)
+}
+
+/// Shadow casting `fov` isn`t symmetric: `from` can see `to`
+/// while `to` can`t see `from`. Symmetric line of sight exists
+/// only if it exists in both directions.
 pub fn los(
     map: &Map<Terrain>,
     unit_type: &UnitType,
     from: &MapPos,
     to: &MapPos,
+    is_symmetric: bool,
 ) -> bool {
-    // TODO: profile and optimize!
-    let mut v = false;
     let range = unit_type.los_range;
-    fov(map, from, range, &mut |p| if *p == *to { v = true });
-    v
+    is_in_fov(map, from, range, to)
+        && (!is_symmetric || is_in_fov(map, to, range, from))
+}
+
+/// Results of `los` checks keyed by (from, to, range, symmetry).
+/// Terrain never changes, but `Core` clears the cache
+/// every turn anyway to keep it small.
+pub struct LosCache {
+    cache: RefCell<HashMap<(ZInt, ZInt, ZInt, ZInt, ZInt, bool), bool>>,
+}
+
+impl LosCache {
+    pub fn new() -> LosCache {
+        LosCache {
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn los(
+        &self,
+        map: &Map<Terrain>,
+        unit_type: &UnitType,
+        from: &MapPos,
+        to: &MapPos,
+        is_symmetric: bool,
+    ) -> bool {
+        let key = (from.v.x, from.v.y, to.v.x, to.v.y, unit_type.los_range, is_symmetric);
+        if let Some(is_visible) = self.cache.borrow().get(&key) {
+            return *is_visible;
+        }
+        let is_visible = los(map, unit_type, from, to, is_symmetric);
+        self.cache.borrow_mut().insert(key, is_visible);
+        is_visible
+    }
+
+    pub fn clear(&self) {
+        self.cache.borrow_mut().clear();
+    }
 }
 
 impl Core {
-    pub fn new() -> Core {
-        let map_size = Size2{w: 10, h: 8};
+    pub fn new(options: CoreOptions) -> Core {
+        assert!(!options.players.is_empty());
+        // seed is needed to replay the game from turn packets
+        let options = if options.seed.is_some() {
+            options
+        } else {
+            let seed = thread_rng().gen();
+            options.with_seed(seed)
+        };
+        let map_size = options.scenario.map_size.clone();
+        let players = get_players_list(&options);
         let mut core = Core {
             state: InternalState::new(&map_size),
-            players: get_players_list(),
+            players_info: get_player_info_lists(&options, &players),
+            ais: get_ais(&options, &players),
+            players: players,
             current_player_id: PlayerId{id: 0},
             db: Db::new(),
-            ai: Ai::new(&PlayerId{id:1}, &map_size),
-            players_info: get_player_info_lists(&map_size),
             next_unit_id: UnitId{id: 0},
+            scenario: options.scenario.clone(),
+            rng: RefCell::new(get_rng(&options)),
+            los_cache: LosCache::new(),
+            active_unit_ids: RefCell::new(HashSet::new()),
+            options: options,
+            is_ai_running: false,
+            event_log: Vec::new(),
+            undo_event: None,
+            turn_packets_count: 0,
+            command_log: Vec::new(),
+            is_replaying: false,
+            signatures: Vec::new(),
+            consistency_report: Vec::new(),
         };
         core.get_units();
+        core.init_reinforcement_points();
         core
     }
 
@@ -182,25 +484,58 @@ impl Core {
         &self.db
     }
 
-    // TODO: Move to scenario.json
+    pub fn options(&self) -> &CoreOptions {
+        &self.options
+    }
+
+    pub fn players(&self) -> &Vec<Player> {
+        &self.players
+    }
+
+    /// All unfiltered events. Empty if `CoreOptions::event_log` is disabled.
+    pub fn event_log(&self) -> &Vec<CoreEvent> {
+        &self.event_log
+    }
+
+    /// Returns id of the only player that still has units
+    pub fn winner(&self) -> Option<PlayerId> {
+        let mut alive_players = HashSet::new();
+        for (_, unit) in self.state.units() {
+            alive_players.insert(unit.player_id.clone());
+        }
+        if alive_players.len() == 1 {
+            alive_players.into_iter().next()
+        } else {
+            None
+        }
+    }
+
+    fn rng(&self) -> RefMut<StdRng> {
+        self.rng.borrow_mut()
+    }
+
     fn get_units(&mut self) {
-        let tank_id = self.db.unit_type_id("tank");
-        let soldier_id = self.db.unit_type_id("soldier");
-        let scout_id = self.db.unit_type_id("scout");
-        let p_id_0 = PlayerId{id: 0};
-        let p_id_1 = PlayerId{id: 1};
-        self.add_unit(&MapPos{v: Vector2{x: 0, y: 1}}, &tank_id, &p_id_0);
-        self.add_unit(&MapPos{v: Vector2{x: 0, y: 2}}, &soldier_id, &p_id_0);
-        self.add_unit(&MapPos{v: Vector2{x: 0, y: 3}}, &scout_id, &p_id_0);
-        self.add_unit(&MapPos{v: Vector2{x: 0, y: 4}}, &soldier_id, &p_id_0);
-        self.add_unit(&MapPos{v: Vector2{x: 0, y: 5}}, &tank_id, &p_id_0);
-        self.add_unit(&MapPos{v: Vector2{x: 0, y: 6}}, &tank_id, &p_id_0);
-        self.add_unit(&MapPos{v: Vector2{x: 9, y: 1}}, &tank_id, &p_id_1);
-        self.add_unit(&MapPos{v: Vector2{x: 9, y: 2}}, &soldier_id, &p_id_1);
-        self.add_unit(&MapPos{v: Vector2{x: 9, y: 3}}, &scout_id, &p_id_1);
-        self.add_unit(&MapPos{v: Vector2{x: 9, y: 4}}, &soldier_id, &p_id_1);
-        self.add_unit(&MapPos{v: Vector2{x: 9, y: 5}}, &tank_id, &p_id_1);
-        self.add_unit(&MapPos{v: Vector2{x: 9, y: 6}}, &tank_id, &p_id_1);
+        let units = self.scenario.units.clone();
+        for unit in &units {
+            let type_id = self.db.unit_type_id(&unit.type_name);
+            self.add_unit(&unit.pos, &type_id, &unit.player_id);
+        }
+    }
+
+    fn init_reinforcement_points(&mut self) {
+        let points = self.scenario.initial_reinforcement_points;
+        let player_ids: Vec<PlayerId> = self.players.iter()
+            .map(|player| player.id.clone()).collect();
+        for player_id in player_ids {
+            self.do_core_event(CoreEvent::SetReinforcementPoints {
+                player_id: player_id,
+                points: points,
+            });
+        }
+    }
+
+    pub fn scenario(&self) -> &Scenario {
+        &self.scenario
     }
 
     fn get_new_unit_id(&mut self) -> UnitId {
@@ -224,55 +559,65 @@ impl Core {
         self.state.map().size()
     }
 
-    fn get_killed_count(&self, attacker: &Unit, defender: &Unit) -> ZInt {
+    fn roll_vehicle_damage(&self) -> VehicleDamage {
+        let table = self.db.vehicle_damage_table();
+        let total_weight = table.iter().fold(0, |sum, row| sum + row.weight);
+        let mut n = self.rng().gen_range(0, total_weight);
+        for row in table {
+            if n < row.weight {
+                return row.damage.clone();
+            }
+            n -= row.weight;
+        }
+        panic!("Bad vehicle damage table");
+    }
+
+    fn get_killed_count(&self, attacker: &Unit, defender: &Unit)
+        -> (ZInt, Option<VehicleDamage>)
+    {
         let hit = self.hit_test(attacker, defender);
         if !hit {
-            return 0;
+            return (0, None);
         }
         let defender_type = self.db.unit_type(&defender.type_id);
         match defender_type.class {
             UnitClass::Infantry => {
-                clamp(thread_rng().gen_range(1, 5), 1, defender.count)
+                let killed = self.rng().gen_range(KILLED_MIN, KILLED_MAX);
+                (clamp(killed, 1, defender.count), None)
+            },
+            UnitClass::Vehicle => if !self.options.rules.vehicle_damage {
+                (1, None)
+            } else {
+                match self.roll_vehicle_damage() {
+                    VehicleDamage::Destroyed => (1, Some(VehicleDamage::Destroyed)),
+                    damage => (0, Some(damage)),
+                }
             },
-            UnitClass::Vehicle => 1,
         }
     }
 
     fn hit_test(&self, attacker: &Unit, defender: &Unit) -> bool {
-        fn test(needed: ZInt) -> bool {
-            let real = thread_rng().gen_range(-5i32, 5i32);
-            let result = real < needed;
-            // println!("real:{} < needed:{} = {}", real, needed, result);
-            result
-        }
-        // println!("");
-        let attacker_type = self.db.unit_type(&attacker.type_id);
-        let defender_type = self.db.unit_type(&defender.type_id);
-        let weapon_type = self.db.weapon_type(&attacker_type.weapon_type_id);
-        if distance(&attacker.pos, &defender.pos) > weapon_type.max_distance {
-            return false;
-        }
-        let hit_test_v = -15 + defender_type.size
-            + weapon_type.accuracy + attacker_type.weapon_skill;
-        let pierce_test_v = 5 + -defender_type.armor + weapon_type.ap;
-        let wound_test_v = -defender_type.toughness + weapon_type.damage;
-        // println!("hit_test = {}, pierce_test = {}, wound_test_v = {}",
-        //     hit_test_v, pierce_test_v, wound_test_v);
-        // print!("hit test: ");
-        if !test(hit_test_v) {
-            return false;
-        }
-        // print!("pierce test: ");
-        if !test(pierce_test_v) {
-            return false;
-        }
-        // print!("wound test: ");
-        if !test(wound_test_v) {
+        let test = |needed: ZInt| -> bool {
+            let real = self.rng().gen_range(TEST_ROLL_MIN, TEST_ROLL_MAX);
+            real < needed
+        };
+        let max_distance = self.db.unit_max_attack_dist(attacker);
+        if distance(&attacker.pos, &defender.pos) > max_distance {
             return false;
         }
-        // println!("HIT!");
-        true
-        // false
+        let values = get_test_values(&self.db, attacker, defender);
+        test(values.hit) && test(values.pierce) && test(values.wound)
+    }
+
+    pub fn attack_odds(
+        &self,
+        attacker_id: &UnitId,
+        defender_id: &UnitId,
+        firing_pos: &MapPos,
+    ) -> AttackOdds {
+        let attacker = self.state.unit(attacker_id);
+        let defender = self.state.unit(defender_id);
+        attack_odds(&self.db, &self.options.rules, attacker, defender, firing_pos)
     }
 
     pub fn player(&self) -> &Player {
@@ -284,13 +629,125 @@ impl Core {
     }
 
     pub fn get_event(&mut self) -> Option<CoreEvent> {
-        let mut i = self.players_info.get_mut(&self.current_player_id)
-            .expect("core: Can`t get current player`s info");
+        let player_id = self.current_player_id.clone();
+        self.get_player_event(&player_id)
+    }
+
+    /// Next fog-filtered event of any player (used by network server)
+    pub fn get_player_event(&mut self, player_id: &PlayerId) -> Option<CoreEvent> {
+        let mut i = self.players_info.get_mut(player_id)
+            .expect("core: Can`t get player`s info");
         i.events.pop_front()
     }
 
+    /// Best spotting skill of player`s units that can only
+    /// spot unit of `unit_type` in `pos`
+    fn best_spotting(&self, player_id: &PlayerId, unit_type: &UnitType, pos: &MapPos)
+        -> ZInt
+    {
+        let mut best_spotting = None;
+        for (_, unit) in self.state.units() {
+            if unit.player_id != *player_id {
+                continue;
+            }
+            let visibility = visibility_for(
+                &self.db,
+                self.state.map(),
+                unit,
+                pos,
+                self.options.rules.symmetric_los,
+            );
+            if !needs_spotting_roll(&visibility, unit_type) {
+                continue;
+            }
+            let spotting = self.db.unit_type(&unit.type_id).spotting;
+            if best_spotting.map_or(true, |best_spotting| spotting > best_spotting) {
+                best_spotting = Some(spotting);
+            }
+        }
+        // tile was seen only from the path of some moved unit
+        best_spotting.unwrap_or(0)
+    }
+
+    /// Can player see `unit` if it stands in `pos`. Units that
+    /// `needs_spotting_roll` are spotted with dice rolls. Results are kept
+    /// until the start of player`s next turn, so all checks agree.
+    fn is_unit_visible_at(&self, player_id: &PlayerId, unit: &Unit, pos: &MapPos) -> bool {
+        let i = &self.players_info[player_id];
+        let unit_type = self.db.unit_type(&unit.type_id);
+        if i.fow.is_visible(unit_type, pos) {
+            return true;
+        }
+        if !needs_spotting_roll(i.fow.tile_visibility(pos), unit_type) {
+            return false;
+        }
+        let mut rolls = i.spotting_rolls.borrow_mut();
+        let unit_rolls = rolls.entry(unit.id.clone()).or_insert(Vec::new());
+        for &(ref roll_pos, is_spotted) in unit_rolls.iter() {
+            if *roll_pos == *pos {
+                return is_spotted;
+            }
+        }
+        let spotting = self.best_spotting(player_id, unit_type, pos);
+        let is_active = self.active_unit_ids.borrow().contains(&unit.id);
+        let chance = spotting_chance(spotting, unit_type, is_active);
+        let is_spotted = self.rng().gen_range(0, 100) < chance;
+        unit_rolls.push((pos.clone(), is_spotted));
+        is_spotted
+    }
+
+    fn get_visible_enemies(&self, player_id: &PlayerId) -> HashSet<UnitId> {
+        let mut visible_enemies = HashSet::new();
+        for (id, unit) in self.state.units() {
+            if unit.player_id != *player_id
+                && self.is_unit_visible_at(player_id, unit, &unit.pos)
+            {
+                visible_enemies.insert(id.clone());
+            }
+        }
+        visible_enemies
+    }
+
+    fn mark_active_unit(&self, command: &Command) {
+        match command {
+            &Command::Move{ref unit_id, ..} => self.mark_unit_active(unit_id),
+            &Command::AttackUnit{ref attacker_id, ..} => self.mark_unit_active(attacker_id),
+            &Command::Assault{ref attacker_id, ..} => self.mark_unit_active(attacker_id),
+            _ => {},
+        }
+    }
+
+    /// Units that move or fire are easier to spot:
+    /// failed spotting rolls for them are made again
+    fn mark_unit_active(&self, unit_id: &UnitId) {
+        for (_, i) in &self.players_info {
+            if let Some(rolls) = i.spotting_rolls.borrow_mut().get_mut(unit_id) {
+                rolls.retain(|&(_, is_spotted)| is_spotted);
+            }
+        }
+        self.active_unit_ids.borrow_mut().insert(unit_id.clone());
+    }
+
+    /// Player takes a fresh look at the battlefield
+    /// and player`s units are not active anymore
+    fn reset_spotting(&mut self, player_id: &PlayerId) {
+        self.players_info[player_id].spotting_rolls.borrow_mut().clear();
+        let active_unit_ids: HashSet<UnitId> = {
+            let units = self.state.units();
+            let ids = self.active_unit_ids.borrow();
+            let active_unit_ids = ids.iter()
+                .filter(|id| units.get(*id)
+                    .map_or(false, |unit| unit.player_id != *player_id))
+                .cloned()
+                .collect();
+            active_unit_ids
+        };
+        *self.active_unit_ids.borrow_mut() = active_unit_ids;
+    }
+
     fn los(&self, unit_type: &UnitType, from: &MapPos, to: &MapPos) -> bool {
-        los(self.state.map(), unit_type, from, to)
+        let is_symmetric = self.options.rules.symmetric_los;
+        self.los_cache.los(self.state.map(), unit_type, from, to, is_symmetric)
     }
 
     fn command_attack_unit_to_event(
@@ -301,9 +758,23 @@ impl Core {
         fire_mode: FireMode,
         remove_move_points: bool,
     ) -> Vec<CoreEvent> {
-        let mut events = Vec::new();
         let attacker = self.state.unit(&attacker_id);
         let defender = self.state.unit(&defender_id);
+        self.attack_to_event(
+            attacker, defender, defender_pos, fire_mode, remove_move_points)
+    }
+
+    /// `attacker` and `defender` may differ from units in current state:
+    /// assault`s defensive fire is computed for already damaged attacker
+    fn attack_to_event(
+        &self,
+        attacker: &Unit,
+        defender: &Unit,
+        defender_pos: &MapPos,
+        fire_mode: FireMode,
+        remove_move_points: bool,
+    ) -> Vec<CoreEvent> {
+        let mut events = Vec::new();
         let attacker_type = self.db.unit_type(&attacker.type_id);
         let weapon_type = self.db.weapon_type(&attacker_type.weapon_type_id);
         if distance(&attacker.pos, defender_pos) > weapon_type.max_distance {
@@ -315,18 +786,29 @@ impl Core {
         if attacker.morale < 50 {
             return events;
         }
-        let killed = self.get_killed_count(attacker, defender);
-        let fow = &self.players_info[&defender.player_id].fow;
-        let is_ambush = !fow.is_visible(attacker_type, &attacker.pos)
-            && thread_rng().gen_range(1, 10) > 3;
+        if attacker.is_weapon_disabled {
+            return events;
+        }
+        let (killed, damage) = self.get_killed_count(attacker, defender);
+        let suppression = if let Some(VehicleDamage::CrewShaken) = damage {
+            10 + CREW_SHAKEN_SUPPRESSION
+        } else {
+            10 + 20 * killed
+        };
+        let is_ambush = self.options.rules.ambushes
+            && !self.is_unit_visible_at(&defender.player_id, attacker, &attacker.pos)
+            && self.rng().gen_range(1, 10) > 3;
         events.push(CoreEvent::AttackUnit {
-            attacker_id: if is_ambush { None } else { Some(attacker_id) },
-            defender_id: defender_id,
+            attacker_id: if is_ambush { None } else { Some(attacker.id.clone()) },
+            defender_id: defender.id.clone(),
             killed: killed,
             mode: fire_mode,
-            suppression: 10 + 20 * killed,
+            suppression: suppression,
             remove_move_points: remove_move_points,
+            damage: damage,
         });
+        // firing unit gives its position away
+        self.mark_unit_active(&attacker.id);
         events
     }
 
@@ -335,7 +817,6 @@ impl Core {
     {
         let mut events = Vec::new();
         let unit = self.state.unit(unit_id);
-        let unit_type = self.db.unit_type(&unit.type_id);
         for (_, enemy_unit) in self.state.units() {
             // TODO: check if unit is still alive
             if enemy_unit.player_id == self.current_player_id {
@@ -346,15 +827,10 @@ impl Core {
             if enemy_reactive_attack_points <= 0 {
                 continue;
             }
-            if enemy_unit.morale < 50 {
+            if !self.is_unit_visible_at(&enemy_unit.player_id, unit, pos) {
                 continue;
             }
-            let fow = &self.players_info[&enemy_unit.player_id].fow;
-            if !fow.is_visible(unit_type, pos) {
-                continue;
-            }
-            let max_distance = self.db.unit_max_attack_dist(enemy_unit);
-            if distance(&enemy_unit.pos, pos) > max_distance {
+            if !can_fire_at_ignoring_los(&self.db, enemy_unit, pos) {
                 continue;
             }
             let enemy_type = self.db.unit_type(&enemy_unit.type_id);
@@ -388,6 +864,9 @@ impl Core {
         move_mode: &MoveMode,
     ) -> Vec<CoreEvent> {
         let mut events = Vec::new();
+        if !self.options.rules.reaction_fire {
+            return events;
+        }
         let len = path.nodes().len();
         for i in 1 .. len {
             let pos = &path.nodes()[i].pos;
@@ -407,6 +886,451 @@ impl Core {
         events
     }
 
+    /// Sum of incomes of all objectives that are held by player's units.
+    /// Objective pays once no matter how many units hold it.
+    fn income(&self, player_id: &PlayerId) -> ZInt {
+        let mut income = 0;
+        for objective in &self.scenario.objectives {
+            let is_held = self.state.units_at(&objective.pos).iter()
+                .any(|unit| unit.player_id == *player_id);
+            if is_held {
+                income += objective.income;
+            }
+        }
+        income
+    }
+
+    /// End-of-turn repair rolls for player's damaged vehicles
+    fn repair_units(&self, player_id: &PlayerId) -> Vec<CoreEvent> {
+        let mut events = Vec::new();
+        let chance = self.db.vehicle_repair_chance();
+        for (_, unit) in self.state.units() {
+            if unit.player_id != *player_id {
+                continue;
+            }
+            let mut damages = Vec::new();
+            if unit.is_immobilized {
+                damages.push(VehicleDamage::Immobilized);
+            }
+            if unit.is_weapon_disabled {
+                damages.push(VehicleDamage::WeaponDisabled);
+            }
+            for damage in damages {
+                if self.rng().gen_range(0, 100) < chance {
+                    events.push(CoreEvent::RepairUnit {
+                        unit_id: unit.id.clone(),
+                        damage: damage,
+                    });
+                }
+            }
+        }
+        events
+    }
+
+    /// Path must start at unit`s position, pass through adjacent tiles
+    /// and have the same costs that `Pathfinder` gives with current
+    /// player`s knowledge of terrain
+    fn check_path(&self, unit_id: &UnitId, path: &MapPath, mode: &MoveMode)
+        -> Result<(), CommandError>
+    {
+        let unit = self.state.unit(unit_id);
+        let unit_type = self.db.unit_type(&unit.type_id);
+        let map = self.state.map();
+        let fow = &self.players_info[&self.current_player_id].fow;
+        let nodes = path.nodes();
+        if nodes.is_empty() || nodes[0].pos != unit.pos || nodes[0].cost.n != 0 {
+            return Err(CommandError::BadPath);
+        }
+        for i in 1 .. nodes.len() {
+            let pos = &nodes[i].pos;
+            if !map.is_inboard(pos) || distance(&nodes[i - 1].pos, pos) != 1 {
+                return Err(CommandError::BadPath);
+            }
+            let terrain = if fow.is_tile_explored(pos) {
+                map.tile(pos)
+            } else {
+                &Terrain::Plain
+            };
+            let cost = move_cost(unit_type, terrain, mode);
+            if nodes[i].cost.n != nodes[i - 1].cost.n + cost.n {
+                return Err(CommandError::BadPathCost);
+            }
+        }
+        if path.total_cost().n > unit.move_points {
+            return Err(CommandError::NotEnoughMovePoints);
+        }
+        Ok(())
+    }
+
+    /// Unit of current player. Hidden enemies are reported
+    /// as missing, so the error doesn`t reveal them.
+    fn own_unit(&self, unit_id: &UnitId) -> Result<&Unit, CommandError> {
+        let unit = match self.state.units().get(unit_id) {
+            Some(unit) => unit,
+            None => return Err(CommandError::NoSuchUnit),
+        };
+        if unit.player_id != self.current_player_id {
+            let visible_enemies = &self.players_info[&self.current_player_id]
+                .visible_enemies;
+            if !visible_enemies.contains(unit_id) {
+                return Err(CommandError::NoSuchUnit);
+            }
+            return Err(CommandError::NotYourUnit);
+        }
+        Ok(unit)
+    }
+
+    /// Enemy unit that current player sees
+    fn visible_enemy(&self, unit_id: &UnitId) -> Result<&Unit, CommandError> {
+        let unit = match self.state.units().get(unit_id) {
+            Some(unit) => unit,
+            None => return Err(CommandError::TargetIsNotVisible),
+        };
+        if unit.player_id == self.current_player_id {
+            return Err(CommandError::CanNotAttackFriendlyUnit);
+        }
+        let visible_enemies = &self.players_info[&self.current_player_id]
+            .visible_enemies;
+        if !visible_enemies.contains(unit_id) {
+            return Err(CommandError::TargetIsNotVisible);
+        }
+        Ok(unit)
+    }
+
+    pub fn check_command(&self, command: &Command) -> Result<(), CommandError> {
+        match command {
+            &Command::Move{ref unit_id, ref path, ref mode} => {
+                if try!(self.own_unit(unit_id)).is_immobilized {
+                    return Err(CommandError::UnitIsImmobilized);
+                }
+                self.check_path(unit_id, path, mode)
+            },
+            &Command::MoveGroup{ref moves, ref mode} => {
+                for &(ref unit_id, ref path) in moves {
+                    if try!(self.own_unit(unit_id)).is_immobilized {
+                        return Err(CommandError::UnitIsImmobilized);
+                    }
+                    try!(self.check_path(unit_id, path, mode));
+                }
+                Ok(())
+            },
+            &Command::AttackUnit{ref attacker_id, ref defender_id} => {
+                let attacker = try!(self.own_unit(attacker_id));
+                let defender = try!(self.visible_enemy(defender_id));
+                if attacker.is_weapon_disabled {
+                    return Err(CommandError::WeaponIsDisabled);
+                }
+                if attacker.attack_points <= 0 {
+                    return Err(CommandError::NoAttackPoints);
+                }
+                if attacker.morale < 50 {
+                    return Err(CommandError::UnitIsSuppressed);
+                }
+                let max_distance = self.db.unit_max_attack_dist(attacker);
+                if distance(&attacker.pos, &defender.pos) > max_distance {
+                    return Err(CommandError::TargetIsOutOfRange);
+                }
+                let attacker_type = self.db.unit_type(&attacker.type_id);
+                if !self.los(attacker_type, &attacker.pos, &defender.pos) {
+                    return Err(CommandError::NoLineOfSight);
+                }
+                Ok(())
+            },
+            &Command::Assault{ref attacker_id, ref target_pos} => {
+                let attacker = try!(self.own_unit(attacker_id));
+                let attacker_type = self.db.unit_type(&attacker.type_id);
+                if let UnitClass::Vehicle = attacker_type.class {
+                    return Err(CommandError::OnlyInfantryCanAssault);
+                }
+                if distance(&attacker.pos, target_pos) != 1 {
+                    return Err(CommandError::TargetIsNotAdjacent);
+                }
+                if attacker.attack_points <= 0 {
+                    return Err(CommandError::NoAttackPoints);
+                }
+                if attacker.move_points <= 0 {
+                    return Err(CommandError::NoMovePoints);
+                }
+                if attacker.morale < 50 {
+                    return Err(CommandError::UnitIsSuppressed);
+                }
+                // hidden enemies must not be revealed by error message
+                let visible_enemies = &self.players_info[&attacker.player_id]
+                    .visible_enemies;
+                let is_enemy_visible = self.state.units_at(target_pos).iter()
+                    .any(|unit| visible_enemies.contains(&unit.id));
+                if !is_enemy_visible {
+                    return Err(CommandError::NoEnemyAtTargetPos);
+                }
+                Ok(())
+            },
+            &Command::CreateUnit{ref pos, ref type_id} => {
+                if type_id.id < 0 || type_id.id >= self.db.unit_types_count() {
+                    return Err(CommandError::NoSuchUnitType);
+                }
+                let player_id = self.player_id();
+                if !self.state.map().is_inboard(pos)
+                    || !self.scenario.is_in_zone(player_id, pos)
+                {
+                    return Err(CommandError::NotInReinforcementZone);
+                }
+                if self.state.is_tile_occupied(pos) {
+                    return Err(CommandError::TileIsOccupied);
+                }
+                let cost = self.db.unit_type(type_id).cost;
+                if self.state.reinforcement_points(player_id) < cost {
+                    return Err(CommandError::NotEnoughReinforcementPoints);
+                }
+                Ok(())
+            },
+            &Command::Undo => {
+                if self.undo_event.is_none() {
+                    return Err(CommandError::NothingToUndo);
+                }
+                Ok(())
+            },
+            &Command::SetMoveOrder{ref unit_id, ref waypoints} => {
+                try!(self.own_unit(unit_id));
+                for pos in waypoints {
+                    if !self.state.map().is_inboard(pos) {
+                        return Err(CommandError::WaypointIsOffMap);
+                    }
+                }
+                Ok(())
+            },
+            &Command::EndTurn => Ok(()),
+        }
+    }
+
+    /// Index of the first path node (except the last one)
+    /// in zone of control of any enemy, hidden ones included
+    fn zoc_stop_index(&self, unit_id: &UnitId, path: &MapPath) -> Option<usize> {
+        if !self.options.rules.zoc {
+            return None;
+        }
+        let player_id = &self.state.unit(unit_id).player_id;
+        let len = path.nodes().len();
+        for i in 1 .. len {
+            let pos = &path.nodes()[i].pos;
+            if i + 1 < len && is_zoc(&self.db, self.state.units(), player_id, pos) {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    /// Recalculates path costs with real terrain of unexplored tiles
+    /// and truncates move at the first tile unit can`t afford.
+    /// Returns true if move was truncated.
+    fn apply_real_costs(&self, command: Command) -> (Command, bool) {
+        if let Command::Move{ref unit_id, ref path, ref mode} = command {
+            let unit = self.state.unit(unit_id);
+            let unit_type = self.db.unit_type(&unit.type_id);
+            let mut nodes = path.nodes().clone();
+            let mut is_truncated = false;
+            for i in 1 .. nodes.len() {
+                let terrain = self.state.map().tile(&nodes[i].pos);
+                let cost = move_cost(unit_type, terrain, mode);
+                let n = nodes[i - 1].cost.n + cost.n;
+                if n > unit.move_points {
+                    nodes.truncate(i);
+                    is_truncated = true;
+                    break;
+                }
+                nodes[i].cost = MoveCost{n: n};
+            }
+            let command = Command::Move {
+                unit_id: unit_id.clone(),
+                path: MapPath::new(nodes),
+                mode: mode.clone(),
+            };
+            return (command, is_truncated);
+        }
+        (command, false)
+    }
+
+    /// Truncates move at the first tile in enemy zone of control.
+    /// Returns true if move was truncated.
+    fn apply_zoc(&self, command: Command) -> (Command, bool) {
+        if let Command::Move{ref unit_id, ref path, ref mode} = command {
+            if let Some(i) = self.zoc_stop_index(unit_id, path) {
+                let mut nodes = path.nodes().clone();
+                nodes.truncate(i + 1);
+                let command = Command::Move {
+                    unit_id: unit_id.clone(),
+                    path: MapPath::new(nodes),
+                    mode: mode.clone(),
+                };
+                return (command, true);
+            }
+        }
+        (command, false)
+    }
+
+    /// Move can be undone only if no other player saw any part of it
+    fn is_move_hidden_from_enemies(&self, unit_id: &UnitId, path: &MapPath) -> bool {
+        let unit = self.state.unit(unit_id);
+        for player in &self.players {
+            if player.id == unit.player_id {
+                continue;
+            }
+            for node in path.nodes() {
+                if self.is_unit_visible_at(&player.id, unit, &node.pos) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn melee_strength(&self, unit: &Unit) -> ZInt {
+        let unit_type = self.db.unit_type(&unit.type_id);
+        let strength = match unit_type.class {
+            UnitClass::Infantry => unit.count * 2,
+            UnitClass::Vehicle => 4,
+        };
+        if unit.morale < 50 {
+            strength - 2
+        } else {
+            strength
+        }
+    }
+
+    /// All units in assaulted tile fight together
+    fn roll_assault(&self, attacker: &Unit, defenders: &Vec<&Unit>, pos: &MapPos)
+        -> AssaultResult
+    {
+        let cover_bonus = match self.state.map().tile(pos) {
+            &Terrain::Trees => 3,
+            &Terrain::Plain => 0,
+        };
+        let defenders_strength = defenders.iter()
+            .fold(0, |sum, defender| sum + self.melee_strength(defender));
+        let attacker_score = self.melee_strength(attacker)
+            + self.rng().gen_range(0, 6);
+        let defender_score = defenders_strength
+            + cover_bonus + self.rng().gen_range(0, 6);
+        let diff = attacker_score - defender_score;
+        if diff >= 4 {
+            AssaultResult::Destroyed
+        } else if diff > 0 {
+            AssaultResult::Retreat
+        } else {
+            AssaultResult::Repulsed
+        }
+    }
+
+    /// Free neighbour tile that is farthest from assaulting unit
+    /// and is not taken by other retreating units
+    fn get_retreat_pos(
+        &self,
+        defender: &Unit,
+        attacker_pos: &MapPos,
+        taken_positions: &Vec<MapPos>,
+    ) -> Option<MapPos> {
+        let mut best_pos = None;
+        let mut best_distance = 0;
+        for dir in dirs() {
+            let pos = Dir::get_neighbour_pos(&defender.pos, &dir);
+            if !self.state.map().is_inboard(&pos) {
+                continue;
+            }
+            if self.state.is_tile_occupied(&pos) || taken_positions.contains(&pos) {
+                continue;
+            }
+            let distance = distance(attacker_pos, &pos);
+            if distance > best_distance {
+                best_distance = distance;
+                best_pos = Some(pos);
+            }
+        }
+        best_pos
+    }
+
+    fn assault_to_events(&self, attacker_id: &UnitId, target_pos: &MapPos)
+        -> Vec<CoreEvent>
+    {
+        let mut events = Vec::new();
+        let attacker_pos = self.state.unit(attacker_id).pos.clone();
+        let attacker_move_points = self.state.unit(attacker_id).move_points;
+        // copy of attacker that is damaged by defensive fire
+        let mut attacker = self.state.unit(attacker_id).clone();
+        let defenders: Vec<&Unit> = self.state.units_at(target_pos).into_iter()
+            .filter(|unit| unit.player_id != attacker.player_id)
+            .collect();
+        for defender in &defenders {
+            for _ in 0 .. DEFENSIVE_FIRE_SHOTS {
+                let e = self.attack_to_event(
+                    defender,
+                    &attacker,
+                    &attacker_pos,
+                    FireMode::Defensive,
+                    false,
+                );
+                for event in e {
+                    if let CoreEvent::AttackUnit{ref killed, ref suppression, ..} = event {
+                        attacker.count -= *killed;
+                        attacker.morale -= *suppression;
+                    }
+                    events.push(event);
+                }
+                if attacker.count <= 0 {
+                    return events;
+                }
+            }
+        }
+        let result = self.roll_assault(&attacker, &defenders, target_pos);
+        let mut retreat_positions = Vec::new();
+        for defender in &defenders {
+            let retreat_pos = self.get_retreat_pos(
+                defender, &attacker_pos, &retreat_positions);
+            let is_destroyed = match result {
+                AssaultResult::Destroyed => true,
+                AssaultResult::Retreat => retreat_pos.is_none(),
+                AssaultResult::Repulsed => false,
+            };
+            events.push(CoreEvent::AttackUnit {
+                attacker_id: Some(attacker_id.clone()),
+                defender_id: defender.id.clone(),
+                mode: FireMode::Melee,
+                killed: if is_destroyed { defender.count } else { 0 },
+                suppression: if let AssaultResult::Repulsed = result { 10 } else { 40 },
+                remove_move_points: true,
+                damage: None,
+            });
+            if let AssaultResult::Repulsed = result {
+                continue;
+            }
+            if let (false, Some(retreat_pos)) = (is_destroyed, retreat_pos) {
+                retreat_positions.push(retreat_pos.clone());
+                events.push(CoreEvent::Move {
+                    unit_id: defender.id.clone(),
+                    path: MapPath::new(vec![
+                        PathNode{cost: MoveCost{n: 0}, pos: target_pos.clone()},
+                        PathNode{cost: MoveCost{n: 0}, pos: retreat_pos},
+                    ]),
+                    mode: MoveMode::Fast,
+                });
+            }
+        }
+        if let AssaultResult::Repulsed = result {
+            return events;
+        }
+        // Advance into vacated tile. This costs all remaining move points.
+        events.push(CoreEvent::Move {
+            unit_id: attacker_id.clone(),
+            path: MapPath::new(vec![
+                PathNode{cost: MoveCost{n: 0}, pos: attacker_pos},
+                PathNode{
+                    cost: MoveCost{n: attacker_move_points},
+                    pos: target_pos.clone(),
+                },
+            ]),
+            mode: MoveMode::Fast,
+        });
+        events
+    }
+
     // TODO: rename: simulation_step?
     // Apply events immediately after adding event to array.
     fn command_to_event(&mut self, command: Command) -> Vec<CoreEvent> {
@@ -420,17 +1344,32 @@ impl Core {
                 } else {
                     old_id + 1
                 };
+                let old_player_id = PlayerId{id: old_id};
+                let points = self.state.reinforcement_points(&old_player_id)
+                    + self.income(&old_player_id);
+                events.extend(self.repair_units(&old_player_id));
+                events.push(CoreEvent::SetReinforcementPoints {
+                    player_id: old_player_id,
+                    points: points,
+                });
                 events.push(CoreEvent::EndTurn {
                     old_id: PlayerId{id: old_id},
                     new_id: PlayerId{id: new_id},
                 });
             },
-            Command::CreateUnit{pos} => {
+            Command::CreateUnit{pos, type_id} => {
+                let player_id = self.current_player_id.clone();
+                let points = self.state.reinforcement_points(&player_id)
+                    - self.db.unit_type(&type_id).cost;
                 events.push(CoreEvent::CreateUnit {
                     unit_id: self.get_new_unit_id(),
                     pos: pos,
-                    type_id: self.db.unit_type_id("soldier"),
-                    player_id: self.current_player_id.clone(),
+                    type_id: type_id,
+                    player_id: player_id.clone(),
+                });
+                events.push(CoreEvent::SetReinforcementPoints {
+                    player_id: player_id,
+                    points: points,
                 });
             },
             Command::Move{ref unit_id, ref path, ref mode} => {
@@ -453,36 +1392,342 @@ impl Core {
                     attacker_id.clone(), defender_id, defender_pos, FireMode::Active, false);
                 let is_target_alive = !e.is_empty() && !is_target_dead(&self.state, &e[0]);
                 events.extend(e);
-                if is_target_alive {
+                if is_target_alive && self.options.rules.reaction_fire {
                     let pos = &self.state.unit(&attacker_id).pos;
                     events.extend(self.reaction_fire(&attacker_id, &MoveMode::Hunt, pos));
                 }
             },
+            Command::Assault{ref attacker_id, ref target_pos} => {
+                events.extend(self.assault_to_events(attacker_id, target_pos));
+            },
+            Command::Undo => {
+                events.push(self.undo_event.take()
+                    .expect("core: Nothing to undo"));
+            },
+            Command::MoveGroup{..} => {
+                panic!("core: Group move must be split by `do_group_move`");
+            },
+            Command::SetMoveOrder{unit_id, waypoints} => {
+                events.push(CoreEvent::SetMoveOrder {
+                    unit_id: unit_id,
+                    waypoints: waypoints,
+                });
+            },
         };
         events
     }
 
+    /// Part of path before the first tile occupied by unit that current
+    /// player sees. Hidden enemies are left to usual move resolution:
+    /// stopping in front of them would reveal them.
+    fn known_free_part_of_path(&self, path: &MapPath) -> MapPath {
+        let player_id = &self.current_player_id;
+        let visible_enemies = &self.players_info[player_id].visible_enemies;
+        let mut nodes = vec![path.nodes()[0].clone()];
+        for node in &path.nodes()[1 ..] {
+            let is_occupied = self.state.units_at(&node.pos).iter()
+                .any(|unit| unit.player_id == *player_id
+                    || visible_enemies.contains(&unit.id));
+            if is_occupied {
+                break;
+            }
+            nodes.push(node.clone());
+        }
+        MapPath::new(nodes)
+    }
+
+    /// Moves units one by one with usual reaction fire and zone of control.
+    /// Unit stops before tiles occupied by units that were stopped earlier
+    /// or by enemies that were spotted during previous moves.
+    fn do_group_move(&mut self, moves: Vec<(UnitId, MapPath)>, mode: MoveMode) {
+        for (unit_id, path) in moves {
+            // unit could be killed by reaction fire during previous moves
+            if self.state.units().get(&unit_id).is_none() {
+                continue;
+            }
+            let path = self.known_free_part_of_path(&path);
+            if path.nodes().len() < 2 {
+                continue;
+            }
+            self.execute_command(Command::Move {
+                unit_id: unit_id,
+                path: path,
+                mode: mode.clone(),
+            });
+        }
+    }
+
     pub fn do_command(&mut self, command: Command) {
+        if let Err(err) = self.check_command(&command) {
+            println!("BAD COMMAND: {}", err.description());
+            return;
+        }
+        if self.options.event_log {
+            self.command_log.push((self.current_player_id.clone(), command.clone()));
+        }
+        match command {
+            Command::MoveGroup{moves, mode} => self.do_group_move(moves, mode),
+            command => self.execute_command(command),
+        }
+    }
+
+    /// Applies already checked command
+    fn execute_command(&mut self, command: Command) {
+        let (command, is_stopped_by_terrain) = self.apply_real_costs(command);
+        let (command, is_stopped_by_zoc) = self.apply_zoc(command);
+        self.mark_active_unit(&command);
+        let is_end_turn = if let Command::EndTurn = command {
+            true
+        } else {
+            false
+        };
+        let undo_event = match command {
+            Command::Move{ref unit_id, ref path, ref mode}
+                if !is_stopped_by_zoc
+                    && !is_stopped_by_terrain
+                    && self.is_move_hidden_from_enemies(unit_id, path) =>
+            {
+                Some(CoreEvent::UndoMove {
+                    unit_id: unit_id.clone(),
+                    path: path.clone(),
+                    mode: mode.clone(),
+                })
+            },
+            _ => None,
+        };
+        let player_id = self.current_player_id.clone();
+        let old_visible_enemies = self.players_info[&player_id].visible_enemies.clone();
         let events = self.command_to_event(command);
         if events.is_empty() {
             println!("BAD COMMAND!");
         }
+        // reaction fire adds more events
+        let is_single_event = events.len() == 1;
         for event in events.into_iter() {
             self.do_core_event(event);
         }
+        let is_nothing_revealed = is_single_event
+            && self.players_info[&player_id].visible_enemies == old_visible_enemies;
+        self.undo_event = if is_nothing_revealed {
+            undo_event
+        } else {
+            None
+        };
+        if self.options.check_consistency {
+            self.check_consistency();
+        }
+        if is_end_turn && !self.is_ai_running && !self.is_replaying {
+            self.do_ai_turns();
+        }
+    }
+
+    fn seed(&self) -> usize {
+        self.options.seed.expect("core: No seed")
+    }
+
+    /// Exports the whole game signed with `player_id`'s secret.
+    /// Events are filtered for current player. Needs `CoreOptions::event_log`.
+    pub fn export_turn_packet(&mut self, player_id: &PlayerId, secret: &str)
+        -> TurnPacket
+    {
+        assert!(self.options.event_log, "core: Turn packets need event log");
+        self.turn_packets_count += 1;
+        let seed = self.seed();
+        self.signatures.retain(|signature| signature.player_id != *player_id);
+        self.signatures.push(Signature {
+            player_id: player_id.clone(),
+            commands_count: self.command_log.len(),
+            mac: sign(secret, seed, &self.command_log),
+        });
+        let recipient_id = self.current_player_id.clone();
+        TurnPacket {
+            turn: self.turn_packets_count,
+            author_id: player_id.clone(),
+            map_size: self.options.scenario.map_size.clone(),
+            players: self.options.players.clone(),
+            fow: self.options.fow,
+            rules: self.options.rules.clone(),
+            seed: seed,
+            events: self.players_info[&recipient_id].history.clone(),
+            recipient_id: recipient_id,
+            commands: self.command_log.clone(),
+            signatures: self.signatures.clone(),
+        }
+    }
+
+    fn is_same_game(&self, packet: &TurnPacket) -> bool {
+        let options = &self.options;
+        options.scenario.map_size.w == packet.map_size.w
+            && options.scenario.map_size.h == packet.map_size.h
+            && options.players == packet.players
+            && options.fow == packet.fow
+            && options.rules == packet.rules
+            && options.seed == Some(packet.seed)
+    }
+
+    /// Commands that importing player hasn`t signed must be given
+    /// by packet`s author or by ai: nobody can play for the importer
+    fn check_command_authors(&self, packet: &TurnPacket, player_id: &PlayerId)
+        -> Result<(), TurnPacketError>
+    {
+        let signed_count = packet.signatures.iter()
+            .find(|signature| signature.player_id == *player_id)
+            .map_or(0, |signature| signature.commands_count);
+        let first_unsigned = cmp::max(signed_count, self.command_log.len());
+        let commands = packet.commands.iter().enumerate().skip(first_unsigned);
+        for (i, &(ref author_id, _)) in commands {
+            let is_ai = self.options.players.get(author_id.id as usize)
+                .map_or(false, |controller| *controller == Controller::Ai);
+            if *author_id != packet.author_id && !is_ai {
+                return Err(TurnPacketError::ForeignCommand{index: i});
+            }
+        }
+        Ok(())
+    }
+
+    fn replay_commands(&mut self, commands: &[(PlayerId, Command)])
+        -> Result<(), TurnPacketError>
+    {
+        let known_count = self.command_log.len();
+        for (i, &(ref author_id, ref command)) in commands.iter().enumerate() {
+            if *author_id != self.current_player_id
+                || self.check_command(command).is_err()
+            {
+                return Err(TurnPacketError::BadCommand{index: known_count + i});
+            }
+            self.do_command(command.clone());
+        }
+        Ok(())
+    }
+
+    /// Replays new commands of turn packet. Their fog-filtered events
+    /// are returned by `get_event` as usual. New `Core` accepts packet
+    /// of any turn, after that packets must be imported in order.
+    /// `Core` must not be used after an error.
+    pub fn import_turn_packet(
+        &mut self,
+        packet: &TurnPacket,
+        player_id: &PlayerId,
+        secret: &str,
+    ) -> Result<(), TurnPacketError> {
+        assert!(self.options.event_log, "core: Turn packets need event log");
+        if !self.is_same_game(packet) {
+            return Err(TurnPacketError::WrongGame);
+        }
+        if self.turn_packets_count != 0 && packet.turn != self.turn_packets_count + 1 {
+            return Err(TurnPacketError::OutOfOrder {
+                expected: self.turn_packets_count + 1,
+                got: packet.turn,
+            });
+        }
+        for signature in &packet.signatures {
+            if signature.player_id != *player_id {
+                continue;
+            }
+            if signature.commands_count > packet.commands.len() {
+                return Err(TurnPacketError::Tampered);
+            }
+            let signed_commands = &packet.commands[.. signature.commands_count];
+            let mac = sign(secret, packet.seed, signed_commands);
+            if !is_same_mac(&mac, &signature.mac) {
+                return Err(TurnPacketError::Tampered);
+            }
+        }
+        let known_count = self.command_log.len();
+        if packet.commands.len() < known_count {
+            return Err(TurnPacketError::Forked);
+        }
+        try!(self.check_command_authors(packet, player_id));
+        for (known, command) in self.command_log.iter().zip(&packet.commands) {
+            if known.0 != command.0
+                || encode_command(&known.1) != encode_command(&command.1)
+            {
+                return Err(TurnPacketError::Forked);
+            }
+        }
+        self.is_replaying = true;
+        let result = self.replay_commands(&packet.commands[known_count ..]);
+        self.is_replaying = false;
+        try!(result);
+        if packet.recipient_id == *player_id {
+            let history = &self.players_info[player_id].history;
+            if history.len() != packet.events.len() {
+                return Err(TurnPacketError::Mismatch);
+            }
+            for (replayed, event) in history.iter().zip(&packet.events) {
+                if encode_event(replayed) != encode_event(event) {
+                    return Err(TurnPacketError::Mismatch);
+                }
+            }
+        }
+        self.turn_packets_count = packet.turn;
+        self.signatures = packet.signatures.clone();
+        self.undo_event = None;
+        Ok(())
+    }
+
+    /// Divergences found by `CoreOptions::check_consistency`, if any
+    pub fn consistency_report(&self) -> &Vec<String> {
+        &self.consistency_report
+    }
+
+    fn check_consistency(&mut self) {
+        let mut report = Vec::new();
+        for player in &self.players {
+            let i = &self.players_info[&player.id];
+            let shadow_state = i.shadow_state.as_ref()
+                .expect("core: No shadow state");
+            let divergences = check_consistency(
+                &self.state,
+                &i.fow,
+                &i.visible_enemies,
+                &player.id,
+                shadow_state,
+            );
+            for divergence in divergences {
+                report.push(format!("player {}: {}", player.id.id, divergence));
+            }
+        }
+        self.consistency_report.extend(report);
+    }
+
+    /// Lets ai players play until it`s human player`s turn.
+    /// Does nothing if there are no human players:
+    /// in this case `do_ai_turn` must be called explicitly.
+    fn do_ai_turns(&mut self) {
+        if self.players.iter().all(|player| player.is_ai) {
+            return;
+        }
+        while self.player().is_ai && self.winner().is_none() {
+            self.do_ai_turn();
+        }
     }
 
-    fn do_ai(&mut self) {
+    pub fn do_ai_turn(&mut self) {
+        assert!(self.player().is_ai);
+        self.is_ai_running = true;
+        let player_id = self.current_player_id.clone();
+        let mut commands_count = 0;
         loop {
             while let Some(event) = self.get_event() {
-                self.ai.apply_event(&self.db, &event);
+                let ai = self.ais.get_mut(&player_id)
+                    .expect("core: Can`t find ai");
+                ai.apply_event(&self.db, &event);
             }
-            let command = self.ai.get_command(&self.db);
+            let command = if commands_count < MAX_AI_COMMANDS_PER_TURN {
+                self.ais.get_mut(&player_id)
+                    .expect("core: Can`t find ai")
+                    .get_command(&self.db)
+            } else {
+                Command::EndTurn
+            };
+            commands_count += 1;
             self.do_command(command.clone());
             if let Command::EndTurn = command {
-                return;
+                break;
             }
         }
+        self.is_ai_running = false;
     }
 
     fn handle_end_turn_event(&mut self, old_id: &PlayerId, new_id: &PlayerId) {
@@ -494,9 +1739,6 @@ impl Core {
                 break;
             }
         }
-        if self.player().is_ai && *new_id == *self.player_id() {
-            self.do_ai();
-        }
     }
 
     fn create_show_unit_event(&self, unit: &Unit) -> CoreEvent {
@@ -505,32 +1747,61 @@ impl Core {
             pos: unit.pos.clone(),
             type_id: unit.type_id.clone(),
             player_id: unit.player_id.clone(),
+            is_immobilized: unit.is_immobilized,
+            is_weapon_disabled: unit.is_weapon_disabled,
         }
     }
 
-    fn filter_attack_event(
+    /// Alive participants of attack that player can`t see
+    fn hidden_attack_participants(
         &self,
         player_id: &PlayerId,
         attacker_id: &Option<UnitId>,
         defender_id: &UnitId,
-    ) -> Vec<CoreEvent> {
-        let fow = &self.players_info[player_id].fow;
-        let mut events = vec![];
+    ) -> Vec<&Unit> {
+        let mut units = Vec::new();
+        let mut ids = vec![defender_id.clone()];
         if let Some(attacker_id) = attacker_id.clone() {
-            let attacker = self.state.unit(&attacker_id);
-            let attacker_type = self.db.unit_type(&attacker.type_id);
-            if !fow.is_visible(attacker_type, &attacker.pos) {
-                events.push(self.create_show_unit_event(&attacker));
-            }
+            ids.push(attacker_id);
         }
-        // if defender is not dead...
-        if let Some(defender) = self.state.units().get(defender_id) {
-            let defender_type = self.db.unit_type(&defender.type_id);
-            if !fow.is_visible(defender_type, &defender.pos) {
-                events.push(self.create_show_unit_event(&defender));
+        for id in &ids {
+            // if unit is not dead...
+            if let Some(unit) = self.state.units().get(id) {
+                if unit.player_id != *player_id
+                    && !self.is_unit_visible_at(player_id, unit, &unit.pos)
+                {
+                    units.push(unit);
+                }
             }
         }
-        events
+        units
+    }
+
+    fn filter_attack_event(
+        &self,
+        player_id: &PlayerId,
+        attacker_id: &Option<UnitId>,
+        defender_id: &UnitId,
+    ) -> Vec<CoreEvent> {
+        self.hidden_attack_participants(player_id, attacker_id, defender_id)
+            .into_iter()
+            .map(|unit| self.create_show_unit_event(unit))
+            .collect()
+    }
+
+    /// Units that were shown only for the attack are hidden back.
+    /// They are not in player`s `visible_enemies`, so
+    /// `show_or_hide_passive_enemies` wouldn`t hide them.
+    fn hide_attack_participants(
+        &self,
+        player_id: &PlayerId,
+        attacker_id: &Option<UnitId>,
+        defender_id: &UnitId,
+    ) -> Vec<CoreEvent> {
+        self.hidden_attack_participants(player_id, attacker_id, defender_id)
+            .into_iter()
+            .map(|unit| CoreEvent::HideUnit{unit_id: unit.id.clone()})
+            .collect()
     }
 
     fn filter_move_event(
@@ -542,12 +1813,10 @@ impl Core {
     ) -> Vec<CoreEvent> {
         let mut events = vec![];
         let unit = self.state.unit(unit_id);
-        let fow = &self.players_info[player_id].fow;
-        let unit_type = self.db.unit_type(&unit.type_id);
         let len = path.nodes().len();
         let mut sub_path = Vec::new();
         let first_pos = path.nodes()[0].pos.clone();
-        if fow.is_visible(unit_type, &first_pos) {
+        if self.is_unit_visible_at(player_id, unit, &first_pos) {
             sub_path.push(PathNode {
                 cost: MoveCost{n: 0},
                 pos: first_pos,
@@ -556,14 +1825,16 @@ impl Core {
         for i in 1 .. len {
             let prev_node = path.nodes()[i - 1].clone();
             let next_node = path.nodes()[i].clone();
-            let prev_vis = fow.is_visible(unit_type, &prev_node.pos);
-            let next_vis = fow.is_visible(unit_type, &next_node.pos);
+            let prev_vis = self.is_unit_visible_at(player_id, unit, &prev_node.pos);
+            let next_vis = self.is_unit_visible_at(player_id, unit, &next_node.pos);
             if !prev_vis && next_vis {
                 events.push(CoreEvent::ShowUnit {
                     unit_id: unit.id.clone(),
                     pos: prev_node.pos.clone(),
                     type_id: unit.type_id.clone(),
                     player_id: unit.player_id.clone(),
+                    is_immobilized: unit.is_immobilized,
+                    is_weapon_disabled: unit.is_weapon_disabled,
                 });
                 sub_path.push(PathNode {
                     cost: MoveCost{n: 0},
@@ -604,7 +1875,6 @@ impl Core {
     {
         let mut active_unit_ids = HashSet::new();
         let mut events = vec![];
-        let fow = &self.players_info[player_id].fow;
         match event {
             &CoreEvent::Move{ref unit_id, ref path, ref mode} => {
                 let unit = self.state.unit(unit_id);
@@ -617,6 +1887,12 @@ impl Core {
                     active_unit_ids.insert(unit_id.clone());
                 }
             },
+            &CoreEvent::UndoMove{ref unit_id, ..} => {
+                // undone move is always hidden from other players
+                if self.state.unit(unit_id).player_id == *player_id {
+                    events.push(event.clone());
+                }
+            },
             &CoreEvent::EndTurn{..} => {
                 events.push(event.clone());
             },
@@ -627,9 +1903,8 @@ impl Core {
                 ..
             } => {
                 let unit = self.state.unit(unit_id);
-                let unit_type = self.db.unit_type(&unit.type_id);
                 if *player_id == *new_unit_player_id
-                    || fow.is_visible(unit_type, pos)
+                    || self.is_unit_visible_at(player_id, unit, pos)
                 {
                     events.push(event.clone());
                     active_unit_ids.insert(unit_id.clone());
@@ -644,6 +1919,26 @@ impl Core {
                 }
                 active_unit_ids.insert(defender_id.clone());
                 events.push(event.clone());
+                events.extend(self.hide_attack_participants(
+                    player_id, attacker_id, defender_id));
+            },
+            &CoreEvent::RepairUnit{ref unit_id, ..} => {
+                let unit = self.state.unit(unit_id);
+                if unit.player_id == *player_id
+                    || self.is_unit_visible_at(player_id, unit, &unit.pos)
+                {
+                    events.push(event.clone());
+                }
+            },
+            &CoreEvent::SetReinforcementPoints{player_id: ref owner_id, ..} => {
+                if *player_id == *owner_id {
+                    events.push(event.clone());
+                }
+            },
+            &CoreEvent::SetMoveOrder{ref unit_id, ..} => {
+                if self.state.unit(unit_id).player_id == *player_id {
+                    events.push(event.clone());
+                }
             },
             &CoreEvent::ShowUnit{..} => panic!(),
             &CoreEvent::HideUnit{..} => panic!(),
@@ -654,28 +1949,46 @@ impl Core {
     fn do_core_event(&mut self, event: CoreEvent) {
         if let CoreEvent::EndTurn{ref old_id, ref new_id} = event {
             self.handle_end_turn_event(old_id, new_id);
+            self.reset_spotting(new_id);
+            self.los_cache.clear();
         }
         self.state.apply_event(&self.db, &event);
+        if self.options.event_log {
+            self.event_log.push(event.clone());
+        }
         for player in &self.players {
             let (filtered_events, active_unit_ids)
                 = self.filter_events(&player.id, &event);
-            let mut i = self.players_info.get_mut(&player.id)
-                .expect("core: Can`t get player`s info");
             for event in filtered_events {
-                i.fow.apply_event(&self.db, &self.state, &event);
-                i.events.push_back(event);
-                let new_visible_enemies = get_visible_enemies(
-                    &self.db,
-                    &i.fow,
-                    self.state.units(),
-                    &player.id
-                );
+                {
+                    let mut i = self.players_info.get_mut(&player.id)
+                        .expect("core: Can`t get player`s info");
+                    i.fow.apply_event(&self.db, &self.state, &event);
+                    if let Some(ref mut shadow_state) = i.shadow_state {
+                        shadow_state.apply_event(&self.db, &event);
+                    }
+                    if self.options.event_log {
+                        i.history.push(event.clone());
+                    }
+                    i.events.push_back(event);
+                }
+                let new_visible_enemies = self.get_visible_enemies(&player.id);
+                let mut i = self.players_info.get_mut(&player.id)
+                    .expect("core: Can`t get player`s info");
                 let show_hide_events = show_or_hide_passive_enemies(
                     self.state.units(),
                     &active_unit_ids,
                     &i.visible_enemies,
                     &new_visible_enemies,
                 );
+                if let Some(ref mut shadow_state) = i.shadow_state {
+                    for event in &show_hide_events {
+                        shadow_state.apply_event(&self.db, event);
+                    }
+                }
+                if self.options.event_log {
+                    i.history.extend(show_hide_events.iter().cloned());
+                }
                 i.events.extend(show_hide_events);
                 i.visible_enemies = new_visible_enemies;
             }
@@ -683,4 +1996,396 @@ impl Core {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, StdRng};
+    use cgmath::{Vector2};
+    use common::types::{ZInt, ZFloat, MapPos, Size2, UnitId, PlayerId};
+    use map::{Map, Terrain};
+    use db::{Db};
+    use command::{Command, CommandError, MoveMode};
+    use pathfinder::{MapPath, PathNode, MoveCost};
+    use dir::{Dir};
+    use options::{CoreOptions, Controller};
+    use scenario::{Scenario, ScenarioUnit, Objective};
+    use unit::{Unit, UnitTypeId};
+    use super::{
+        Core,
+        CoreEvent,
+        FireMode,
+        can_fire_at,
+        test_probability,
+        TEST_ROLL_MIN,
+        TEST_ROLL_MAX,
+    };
+
+    fn map_pos(x: ZInt, y: ZInt) -> MapPos {
+        MapPos{v: Vector2{x: x, y: y}}
+    }
+
+    /// Scenario without objectives and zones, unit ids match units` indices
+    fn scenario(units: &[(ZInt, ZInt, &str, ZInt)]) -> Scenario {
+        Scenario {
+            map_size: Size2{w: 10, h: 8},
+            units: units.iter().map(|&(x, y, type_name, player_id)| ScenarioUnit {
+                pos: map_pos(x, y),
+                type_name: type_name.to_string(),
+                player_id: PlayerId{id: player_id},
+            }).collect(),
+            objectives: Vec::new(),
+            zones: Vec::new(),
+            initial_reinforcement_points: 0,
+        }
+    }
+
+    #[test]
+    fn test_probability_matches_dice() {
+        for needed in -10 .. 10 {
+            let mut successes = 0;
+            for real in TEST_ROLL_MIN .. TEST_ROLL_MAX {
+                if real < needed {
+                    successes += 1;
+                }
+            }
+            let range = (TEST_ROLL_MAX - TEST_ROLL_MIN) as ZFloat;
+            let expected = successes as ZFloat / range;
+            assert_eq!(expected, test_probability(needed as ZInt));
+        }
+    }
+
+    #[test]
+    fn filtered_events_are_consistent_in_ai_game() {
+        for seed in 0 .. 5 {
+            let options = CoreOptions::new()
+                .with_players(vec![Controller::Ai, Controller::Ai])
+                .with_seed(seed)
+                .with_check_consistency(true);
+            let mut core = Core::new(options);
+            for _ in 0 .. 20 {
+                if core.winner().is_some() {
+                    break;
+                }
+                core.do_ai_turn();
+                assert!(core.consistency_report().is_empty(),
+                    "seed {}:\n{}", seed, core.consistency_report().join("\n"));
+            }
+        }
+    }
+
+    #[test]
+    fn symmetric_reaction_fire_is_symmetric_on_random_maps() {
+        let db = Db::new();
+        let type_id = db.unit_type_id("soldier");
+        let mut rng: StdRng = SeedableRng::from_seed(&[3][..]);
+        let size = Size2{w: 16, h: 16};
+        let unit_at = |id, pos: &MapPos| Unit {
+            id: UnitId{id: id},
+            pos: pos.clone(),
+            player_id: PlayerId{id: id},
+            type_id: type_id.clone(),
+            move_points: 0,
+            attack_points: 0,
+            reactive_attack_points: None,
+            count: 1,
+            morale: 100,
+            is_immobilized: false,
+            is_weapon_disabled: false,
+        };
+        for _ in 0 .. 20 {
+            let mut map = Map::new(&size, Terrain::Plain);
+            let trees_percent = rng.gen_range(5, 40);
+            for pos in map.get_iter() {
+                if rng.gen_range(0, 100) < trees_percent {
+                    *map.tile_mut(&pos) = Terrain::Trees;
+                }
+            }
+            for _ in 0 .. 5 {
+                let unit_a = unit_at(0, &MapPos{v: Vector2 {
+                    x: rng.gen_range(0, size.w),
+                    y: rng.gen_range(0, size.h),
+                }});
+                for pos in map.get_iter() {
+                    let unit_b = unit_at(1, &pos);
+                    // A can fire at B if and only if B can fire at A
+                    assert_eq!(
+                        can_fire_at(&db, &map, &unit_a, &unit_b.pos, true),
+                        can_fire_at(&db, &map, &unit_b, &unit_a.pos, true));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn move_stops_in_zoc_of_hidden_enemy() {
+        // enemy soldier in trees can only be spotted, even when adjacent
+        let units = [(1, 4, "tank", 0), (4, 4, "soldier", 1)];
+        let mut options = CoreOptions::new()
+            .with_scenario(scenario(&units))
+            .with_seed(0);
+        options.rules.reaction_fire = false;
+        let mut core = Core::new(options);
+        let player_id = PlayerId{id: 0};
+        let enemy_id = UnitId{id: 1};
+        // as if the spotting roll failed
+        {
+            let i = core.players_info.get_mut(&player_id).unwrap();
+            i.spotting_rolls.borrow_mut()
+                .insert(enemy_id.clone(), vec![(map_pos(4, 4), false)]);
+            i.visible_enemies.clear();
+        }
+        let zoc_pos = map_pos(3, 4);
+        let positions = [
+            map_pos(1, 4),
+            map_pos(2, 4),
+            zoc_pos.clone(),
+            Dir::get_neighbour_pos(&zoc_pos, &Dir::NorthWest),
+        ];
+        let nodes = positions.iter().enumerate().map(|(i, pos)| PathNode {
+            cost: MoveCost{n: i as ZInt},
+            pos: pos.clone(),
+        }).collect();
+        let unit_id = UnitId{id: 0};
+        let path = MapPath::new(nodes);
+        assert_eq!(core.zoc_stop_index(&unit_id, &path), Some(2));
+        core.do_command(Command::Move {
+            unit_id: unit_id.clone(),
+            path: path,
+            mode: MoveMode::Fast,
+        });
+        assert_eq!(core.state.unit(&unit_id).pos, zoc_pos);
+        assert!(!core.players_info[&player_id].visible_enemies.contains(&enemy_id));
+        assert_eq!(core.check_command(&Command::Undo), Err(CommandError::NothingToUndo));
+    }
+
+    #[test]
+    fn spotting_rolls_are_kept_until_unit_is_active() {
+        // enemy soldier in trees can only be spotted
+        let units = [(1, 4, "soldier", 0), (8, 1, "soldier", 1)];
+        let options = CoreOptions::new()
+            .with_scenario(scenario(&units))
+            .with_seed(0);
+        let core = Core::new(options);
+        let player_id = PlayerId{id: 0};
+        let enemy_id = UnitId{id: 1};
+        let pos = map_pos(4, 4);
+        let enemy = core.state.unit(&enemy_id);
+        let rolls_count = || core.players_info[&player_id].spotting_rolls.borrow()
+            .get(&enemy_id)
+            .map_or(0, |rolls| rolls.iter().filter(|&&(ref p, _)| *p == pos).count());
+        let is_visible = core.is_unit_visible_at(&player_id, enemy, &pos);
+        for _ in 0 .. 10 {
+            assert_eq!(core.is_unit_visible_at(&player_id, enemy, &pos), is_visible);
+        }
+        assert_eq!(rolls_count(), 1);
+        core.players_info[&player_id].spotting_rolls.borrow_mut()
+            .insert(enemy_id.clone(), vec![(pos.clone(), false)]);
+        assert!(!core.is_unit_visible_at(&player_id, enemy, &pos));
+        core.mark_unit_active(&enemy_id);
+        assert!(core.active_unit_ids.borrow().contains(&enemy_id));
+        assert_eq!(rolls_count(), 0);
+        core.is_unit_visible_at(&player_id, enemy, &pos);
+        assert_eq!(rolls_count(), 1);
+    }
+
+    #[test]
+    fn spotting_rolls_depend_only_on_seed() {
+        let units = [(1, 4, "soldier", 0), (8, 1, "soldier", 1)];
+        let rolls = |seed| {
+            let options = CoreOptions::new()
+                .with_scenario(scenario(&units))
+                .with_seed(seed);
+            let core = Core::new(options);
+            let enemy = core.state.unit(&UnitId{id: 1});
+            let rolls: Vec<bool> = (0 .. 20).map(|_| {
+                core.mark_unit_active(&enemy.id);
+                core.is_unit_visible_at(&PlayerId{id: 0}, enemy, &map_pos(4, 4))
+            }).collect();
+            rolls
+        };
+        assert_eq!(rolls(1), rolls(1));
+        assert_eq!(rolls(7), rolls(7));
+    }
+
+    #[test]
+    fn move_paths_are_checked_against_real_costs() {
+        let units = [(3, 4, "soldier", 0)];
+        let options = CoreOptions::new()
+            .with_scenario(scenario(&units))
+            .with_seed(0);
+        let core = Core::new(options);
+        let move_to = |x, y, cost| {
+            let nodes = vec![
+                PathNode{cost: MoveCost{n: 0}, pos: map_pos(3, 4)},
+                PathNode{cost: MoveCost{n: cost}, pos: map_pos(x, y)},
+            ];
+            Command::Move {
+                unit_id: UnitId{id: 0},
+                path: MapPath::new(nodes),
+                mode: MoveMode::Fast,
+            }
+        };
+        // (4, 4) is covered with trees
+        assert!(core.check_command(&move_to(4, 4, 2)).is_ok());
+        assert_eq!(core.check_command(&move_to(4, 4, 1)), Err(CommandError::BadPathCost));
+        assert_eq!(core.check_command(&move_to(6, 4, 1)), Err(CommandError::BadPath));
+    }
+
+    #[test]
+    fn commands_for_foreign_or_missing_units_are_rejected() {
+        let units = [(1, 1, "soldier", 0), (2, 1, "soldier", 1)];
+        let options = CoreOptions::new()
+            .with_scenario(scenario(&units))
+            .with_seed(0);
+        let core = Core::new(options);
+        let move_unit = |id| Command::Move {
+            unit_id: UnitId{id: id},
+            path: MapPath::new(vec![]),
+            mode: MoveMode::Fast,
+        };
+        let attack = |attacker_id, defender_id| Command::AttackUnit {
+            attacker_id: UnitId{id: attacker_id},
+            defender_id: UnitId{id: defender_id},
+        };
+        let set_move_order = Command::SetMoveOrder {
+            unit_id: UnitId{id: 1},
+            waypoints: vec![map_pos(3, 1)],
+        };
+        let create_unit = Command::CreateUnit {
+            pos: map_pos(0, 0),
+            type_id: UnitTypeId{id: 99},
+        };
+        assert_eq!(core.check_command(&move_unit(1)), Err(CommandError::NotYourUnit));
+        assert_eq!(core.check_command(&move_unit(9)), Err(CommandError::NoSuchUnit));
+        assert_eq!(core.check_command(&attack(1, 0)), Err(CommandError::NotYourUnit));
+        assert_eq!(core.check_command(&attack(0, 9)),
+            Err(CommandError::TargetIsNotVisible));
+        assert_eq!(core.check_command(&set_move_order), Err(CommandError::NotYourUnit));
+        assert_eq!(core.check_command(&create_unit), Err(CommandError::NoSuchUnitType));
+    }
+
+    #[test]
+    fn stacked_units_hold_objective_once() {
+        let units = [(1, 1, "soldier", 0), (1, 1, "scout", 0), (5, 5, "soldier", 1)];
+        let mut scenario = scenario(&units);
+        scenario.objectives.push(Objective{pos: map_pos(1, 1), income: 3});
+        let options = CoreOptions::new()
+            .with_scenario(scenario)
+            .with_seed(0);
+        let core = Core::new(options);
+        assert_eq!(core.income(&PlayerId{id: 0}), 3);
+        assert_eq!(core.income(&PlayerId{id: 1}), 0);
+    }
+
+    #[test]
+    fn assault_error_does_not_reveal_hidden_units() {
+        let units = [(1, 1, "soldier", 0), (2, 1, "soldier", 1)];
+        let options = CoreOptions::new()
+            .with_scenario(scenario(&units))
+            .with_seed(0);
+        let mut core = Core::new(options);
+        let assault = |x, y| Command::Assault {
+            attacker_id: UnitId{id: 0},
+            target_pos: map_pos(x, y),
+        };
+        assert!(core.check_command(&assault(2, 1)).is_ok());
+        // as if the enemy was not spotted
+        core.players_info.get_mut(&PlayerId{id: 0}).unwrap()
+            .visible_enemies.clear();
+        let empty_tile_result = core.check_command(&assault(0, 1));
+        assert!(empty_tile_result.is_err());
+        assert_eq!(core.check_command(&assault(2, 1)), empty_tile_result);
+    }
+
+    #[test]
+    fn assault_fights_all_units_in_tile() {
+        let units = [
+            (1, 1, "soldier", 0),
+            (2, 1, "scout", 1),
+            (2, 1, "scout", 1),
+        ];
+        let mut melee_count = 0;
+        for seed in 0 .. 40 {
+            let options = CoreOptions::new()
+                .with_scenario(scenario(&units))
+                .with_seed(seed)
+                .with_event_log(true);
+            let mut core = Core::new(options);
+            let first_event = core.event_log().len();
+            core.do_command(Command::Assault {
+                attacker_id: UnitId{id: 0},
+                target_pos: map_pos(2, 1),
+            });
+            let mut defensive_fire_killed = 0;
+            let mut melee_defender_ids = Vec::new();
+            let mut retreat_positions = Vec::new();
+            for event in &core.event_log()[first_event ..] {
+                match *event {
+                    CoreEvent::AttackUnit{ref defender_id, ref mode, ref killed, ..} => {
+                        match *mode {
+                            FireMode::Defensive => {
+                                assert_eq!(defender_id.id, 0);
+                                defensive_fire_killed += *killed;
+                            },
+                            FireMode::Melee => melee_defender_ids.push(defender_id.id),
+                            _ => panic!("Unexpected fire mode"),
+                        }
+                    },
+                    CoreEvent::Move{ref unit_id, ref path, ..} if unit_id.id != 0 => {
+                        retreat_positions.push(path.destination().clone());
+                    },
+                    _ => {},
+                }
+            }
+            // second shot is computed against already damaged attacker
+            assert!(defensive_fire_killed <= 4);
+            if defensive_fire_killed < 4 {
+                melee_defender_ids.sort();
+                assert_eq!(melee_defender_ids, vec![1, 2]);
+                melee_count += 1;
+            } else {
+                assert!(melee_defender_ids.is_empty());
+            }
+            if retreat_positions.len() == 2 {
+                assert!(retreat_positions[0] != retreat_positions[1]);
+            }
+        }
+        assert!(melee_count > 0);
+    }
+
+    #[test]
+    fn expected_casualties_match_mean_of_rolls() {
+        let units = [
+            (1, 1, "soldier", 0),
+            (2, 1, "tank", 0),
+            (1, 2, "soldier", 1),
+            (2, 2, "tank", 1),
+        ];
+        for &vehicle_damage in &[true, false] {
+            let mut options = CoreOptions::new()
+                .with_scenario(scenario(&units))
+                .with_seed(42);
+            options.rules.vehicle_damage = vehicle_damage;
+            let core = Core::new(options);
+            let samples = 20000;
+            for attacker_id in 0 .. 2 {
+                for defender_id in 2 .. 4 {
+                    let attacker = core.state.unit(&UnitId{id: attacker_id});
+                    let defender = core.state.unit(&UnitId{id: defender_id});
+                    let expected = core.attack_odds(
+                        &attacker.id, &defender.id, &attacker.pos).expected_casualties;
+                    let mut killed_sum = 0;
+                    for _ in 0 .. samples {
+                        let (killed, _) = core.get_killed_count(attacker, defender);
+                        killed_sum += killed;
+                    }
+                    let mean = killed_sum as ZFloat / samples as ZFloat;
+                    assert!((mean - expected).abs() < 0.05,
+                        "{} -> {}: mean {}, expected {}",
+                        attacker_id, defender_id, mean, expected);
+                }
+            }
+        }
+    }
+}
+
 // vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
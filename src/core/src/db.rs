@@ -1,7 +1,17 @@
 // See LICENSE file for copyright and license details.
 
+use std::cmp;
 use common::types::{ZInt};
-use unit::{Unit, UnitType, WeaponType, UnitClass, UnitTypeId, WeaponTypeId};
+use unit::{
+    Unit,
+    UnitType,
+    WeaponType,
+    UnitClass,
+    UnitTypeId,
+    WeaponTypeId,
+    VehicleDamage,
+};
+use fov::{ShadowTables};
 
 fn weapon_type_id(weapon_types: &Vec<WeaponType>, name: &str)
     -> WeaponTypeId
@@ -53,6 +63,9 @@ fn get_unit_types(weapon_types: &Vec<WeaponType>) -> Vec<UnitType> {
             reactive_attack_points: 1,
             los_range: 6,
             cover_los_range: 0,
+            spotting: -10,
+            cost: 10,
+            zoc: true,
         },
         UnitType {
             name: "soldier".to_string(),
@@ -68,6 +81,9 @@ fn get_unit_types(weapon_types: &Vec<WeaponType>) -> Vec<UnitType> {
             reactive_attack_points: 1,
             los_range: 6,
             cover_los_range: 1,
+            spotting: 0,
+            cost: 4,
+            zoc: true,
         },
         UnitType {
             name: "scout".to_string(),
@@ -83,25 +99,64 @@ fn get_unit_types(weapon_types: &Vec<WeaponType>) -> Vec<UnitType> {
             reactive_attack_points: 1,
             los_range: 8,
             cover_los_range: 2,
+            spotting: 30,
+            cost: 3,
+            zoc: false,
         },
     ]
 }
 
+/// One row of vehicle damage table
+pub struct VehicleDamageRoll {
+    pub damage: VehicleDamage,
+    pub weight: ZInt,
+}
+
+// TODO: read from json/toml config
+fn get_vehicle_damage_table() -> Vec<VehicleDamageRoll> {
+    vec![
+        VehicleDamageRoll{damage: VehicleDamage::Immobilized, weight: 3},
+        VehicleDamageRoll{damage: VehicleDamage::WeaponDisabled, weight: 2},
+        VehicleDamageRoll{damage: VehicleDamage::CrewShaken, weight: 2},
+        VehicleDamageRoll{damage: VehicleDamage::Destroyed, weight: 3},
+    ]
+}
+
 pub struct Db {
     unit_types: Vec<UnitType>,
     weapon_types: Vec<WeaponType>,
+    vehicle_damage_table: Vec<VehicleDamageRoll>,
+    vehicle_repair_chance: ZInt,
+    shadow_tables: ShadowTables,
+}
+
+fn max_los_range(unit_types: &Vec<UnitType>) -> ZInt {
+    unit_types.iter().fold(0, |max, unit_type| cmp::max(max, unit_type.los_range))
 }
 
 impl Db {
     pub fn new() -> Db {
         let weapon_types = get_weapon_types();
         let unit_types = get_unit_types(&weapon_types);
+        let shadow_tables = ShadowTables::new(max_los_range(&unit_types));
         Db {
             weapon_types: weapon_types,
             unit_types: unit_types,
+            vehicle_damage_table: get_vehicle_damage_table(),
+            vehicle_repair_chance: 30,
+            shadow_tables: shadow_tables,
         }
     }
 
+    pub fn max_los_range(&self) -> ZInt {
+        max_los_range(&self.unit_types)
+    }
+
+    /// Precalculated angles for `fov`, big enough for any unit type
+    pub fn shadow_tables(&self) -> &ShadowTables {
+        &self.shadow_tables
+    }
+
     pub fn unit_types_count(&self) -> ZInt {
         self.unit_types.len() as ZInt
     }
@@ -134,6 +189,15 @@ impl Db {
         weapon_type_id(&self.weapon_types, name)
     }
 
+    pub fn vehicle_damage_table(&self) -> &Vec<VehicleDamageRoll> {
+        &self.vehicle_damage_table
+    }
+
+    /// Chance (in percents) to repair one damage at the end of turn
+    pub fn vehicle_repair_chance(&self) -> ZInt {
+        self.vehicle_repair_chance
+    }
+
     pub fn unit_max_attack_dist(&self, unit: &Unit) -> ZInt {
         let attacker_type = self.unit_type(&unit.type_id);
         let weapon_type = &self
@@ -4,7 +4,7 @@
 
 use std::f32::consts::{PI};
 use num::{Float};
-use cgmath::{EuclideanVector};
+use cgmath::{Vector2, EuclideanVector};
 use common::types::{MapPos, ZInt, ZFloat};
 use map::{Map, Terrain, distance, spiral_iter};
 use geom;
@@ -30,35 +30,280 @@ fn is_obstacle(terrain: &Terrain) -> bool {
     }
 }
 
-// TODO: precalculate all 'atan2' and 'asin' stuff
+/// Obstacle in tile covers a bit more than the inscribed circle
+const OBSTACLE_RADIUS: ZFloat = geom::HEX_IN_RADIUS * 1.1;
+
+/// Pushes shadow of obstacle that is seen at `angle` and covers
+/// `half_width` on each side of it. Shadow that crosses PI is split in two.
+fn push_shadow(shadows: &mut Vec<Shadow>, angle: ZFloat, half_width: ZFloat) {
+    let shadow = Shadow{left: angle - half_width, right: angle + half_width};
+    if shadow.right > PI {
+        shadows.push(Shadow{left: -PI, right: shadow.right - PI * 2.0});
+    }
+    shadows.push(shadow);
+}
+
+/// Half of angle that obstacle shifted by `diff` from origin covers
+fn shadow_half_width(diff: &Vector2<ZFloat>) -> ZFloat {
+    (OBSTACLE_RADIUS / diff.length()).asin()
+}
+
+/// Precalculated 'atan2' and 'asin' stuff of tile that
+/// is shifted by `shift` from origin
+struct TileAngles {
+    shift: Vector2<ZInt>,
+    angle: ZFloat,
+    shadow_half_width: ZFloat,
+}
+
+/// World shift of `pos` from `origin`, computed as if origin were
+/// in the first column. Subtracting world positions of distant tiles
+/// gives results rounded differently, so tables and `is_in_fov`
+/// wouldn`t agree with each other far from the map origin.
+fn world_diff(origin: &MapPos, pos: &MapPos) -> Vector2<ZFloat> {
+    let base = MapPos{v: Vector2{x: 0, y: origin.v.y % 2}};
+    let shifted_pos = MapPos{v: base.v + (pos.v - origin.v)};
+    geom::map_pos_to_world_pos(&shifted_pos) - geom::map_pos_to_world_pos(&base)
+}
+
+fn tile_angles_table(origin: &MapPos, range: ZInt) -> Vec<TileAngles> {
+    spiral_iter(origin, range).map(|pos| {
+        let diff = world_diff(origin, &pos);
+        TileAngles {
+            shift: pos.v - origin.v,
+            angle: Float::atan2(diff.x, diff.y),
+            shadow_half_width: shadow_half_width(&diff),
+        }
+    }).collect()
+}
+
+/// Angular data of all tiles up to `range` around origin in
+/// `spiral_iter` order. Shifts of neighbour hexes depend on row
+/// parity, so origins in even and odd rows have separate tables.
+/// Angles depend only on shifts (see `world_diff`), so `fov` gives
+/// exactly the same result for any origin with the same surroundings.
+pub struct ShadowTables {
+    range: ZInt,
+    even_row: Vec<TileAngles>,
+    odd_row: Vec<TileAngles>,
+}
+
+impl ShadowTables {
+    pub fn new(range: ZInt) -> ShadowTables {
+        ShadowTables {
+            range: range,
+            even_row: tile_angles_table(&MapPos{v: Vector2{x: 0, y: 0}}, range),
+            odd_row: tile_angles_table(&MapPos{v: Vector2{x: 0, y: 1}}, range),
+        }
+    }
+}
+
 pub fn fov(
+    tables: &ShadowTables,
     map: &Map<Terrain>,
     origin: &MapPos,
     range: ZInt,
     callback: &mut FnMut(&MapPos),
 ) {
+    assert!(range <= tables.range);
     callback(origin);
+    let table = if origin.v.y % 2 == 0 {
+        &tables.even_row
+    } else {
+        &tables.odd_row
+    };
+    // every ring has six tiles more than the previous one
+    let tiles_count = (3 * range * (range + 1)) as usize;
     let mut shadows = vec!();
-    let origin3d = geom::map_pos_to_world_pos(origin);
-    for pos in spiral_iter(origin, range) {
+    for tile in &table[.. tiles_count] {
+        let pos = MapPos{v: origin.v + tile.shift};
         if !map.is_inboard(&pos) {
             continue;
         }
-        let pos3d = geom::map_pos_to_world_pos(&pos);
-        let diff = pos3d - origin3d;
-        let distance = diff.length();
-        let angle = Float::atan2(diff.x, diff.y); // TODO: optimize
-        if is_tile_visible(angle, &shadows) {
+        if is_tile_visible(tile.angle, &shadows) {
             callback(&pos);
         }
         if is_obstacle(map.tile(&pos)) {
-            let obstacle_radius = geom::HEX_IN_RADIUS * 1.1;
-            let a = (obstacle_radius / distance).asin();
-            let shadow = Shadow{left: angle - a, right: angle + a};
-            if shadow.right > PI {
-                shadows.push(Shadow{left: -PI, right: shadow.right - PI * 2.0});
+            push_shadow(&mut shadows, tile.angle, tile.shadow_half_width);
+        }
+    }
+}
+
+/// Obstacles that are farther than this from the line of sight
+/// can`t shadow the target. A bit bigger than obstacle radius
+/// to be safe from rounding errors.
+const MAX_SHADOWING_DISTANCE: ZFloat = OBSTACLE_RADIUS * 1.01;
+
+/// Same as checking that `fov` visits `target`, but doesn`t build
+/// the whole field of view: only obstacles near the line of sight
+/// that come before `target` in `spiral_iter` order are checked.
+pub fn is_in_fov(
+    map: &Map<Terrain>,
+    origin: &MapPos,
+    range: ZInt,
+    target: &MapPos,
+) -> bool {
+    if *target == *origin {
+        return true;
+    }
+    let target_distance = distance(origin, target);
+    if target_distance > range || !map.is_inboard(target) {
+        return false;
+    }
+    let target_diff = world_diff(origin, target);
+    let target_angle = Float::atan2(target_diff.x, target_diff.y);
+    let max_cross = MAX_SHADOWING_DISTANCE * target_diff.length();
+    let mut shadows = Vec::new();
+    for pos in spiral_iter(origin, target_distance) {
+        if pos == *target {
+            break;
+        }
+        if !map.is_inboard(&pos) || !is_obstacle(map.tile(&pos)) {
+            continue;
+        }
+        let diff = world_diff(origin, &pos);
+        let dot = diff.x * target_diff.x + diff.y * target_diff.y;
+        let cross = diff.x * target_diff.y - diff.y * target_diff.x;
+        if dot <= 0.0 || cross.abs() > max_cross {
+            continue;
+        }
+        shadows.clear();
+        let angle = Float::atan2(diff.x, diff.y);
+        push_shadow(&mut shadows, angle, shadow_half_width(&diff));
+        if !is_tile_visible(target_angle, &shadows) {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{PI};
+    use num::{Float};
+    use cgmath::{Vector2, EuclideanVector};
+    use rand::{Rng, SeedableRng, StdRng};
+    use common::types::{MapPos, Size2, ZInt};
+    use map::{Map, Terrain, spiral_iter};
+    use db::{Db};
+    use geom;
+    use super::{Shadow, ShadowTables, fov, is_in_fov, is_tile_visible, is_obstacle};
+
+    fn random_map(rng: &mut StdRng, size: &Size2) -> Map<Terrain> {
+        let mut map = Map::new(size, Terrain::Plain);
+        for pos in map.get_iter() {
+            if rng.gen_range(0, 100) < 20 {
+                *map.tile_mut(&pos) = Terrain::Trees;
+            }
+        }
+        map
+    }
+
+    fn random_pos(rng: &mut StdRng, size: &Size2) -> MapPos {
+        MapPos{v: Vector2{x: rng.gen_range(0, size.w), y: rng.gen_range(0, size.h)}}
+    }
+
+    /// `fov` as it was before `ShadowTables`, but world positions are
+    /// computed for shifts from origin moved to the first column, as
+    /// tables do. Old version subtracted world positions of distant
+    /// tiles, rounding made angles differ in the last bits.
+    fn fov_without_tables(
+        map: &Map<Terrain>,
+        origin: &MapPos,
+        range: ZInt,
+        callback: &mut FnMut(&MapPos),
+    ) {
+        callback(origin);
+        let mut shadows = vec!();
+        let base = MapPos{v: Vector2{x: 0, y: origin.v.y % 2}};
+        let origin3d = geom::map_pos_to_world_pos(&base);
+        for pos in spiral_iter(origin, range) {
+            if !map.is_inboard(&pos) {
+                continue;
+            }
+            let shifted_pos = MapPos{v: base.v + (pos.v - origin.v)};
+            let pos3d = geom::map_pos_to_world_pos(&shifted_pos);
+            let diff = pos3d - origin3d;
+            let distance = diff.length();
+            let angle = Float::atan2(diff.x, diff.y);
+            if is_tile_visible(angle, &shadows) {
+                callback(&pos);
+            }
+            if is_obstacle(map.tile(&pos)) {
+                let obstacle_radius = geom::HEX_IN_RADIUS * 1.1;
+                let a = (obstacle_radius / distance).asin();
+                let shadow = Shadow{left: angle - a, right: angle + a};
+                if shadow.right > PI {
+                    shadows.push(Shadow{left: -PI, right: shadow.right - PI * 2.0});
+                }
+                shadows.push(shadow);
+            }
+        }
+    }
+
+    #[test]
+    fn tables_give_same_fov_on_random_maps() {
+        let db = Db::new();
+        let max_range = db.max_los_range();
+        let mut rng: StdRng = SeedableRng::from_seed(&[2][..]);
+        let size = Size2{w: 20, h: 20};
+        for _ in 0 .. 20 {
+            let map = random_map(&mut rng, &size);
+            for _ in 0 .. 10 {
+                let origin = random_pos(&mut rng, &size);
+                let range = rng.gen_range(1, max_range + 1);
+                let mut expected = Map::new(&size, false);
+                fov_without_tables(&map, &origin, range,
+                    &mut |pos| *expected.tile_mut(pos) = true);
+                let mut visible = Map::new(&size, false);
+                fov(db.shadow_tables(), &map, &origin, range,
+                    &mut |pos| *visible.tile_mut(pos) = true);
+                for pos in map.get_iter() {
+                    assert_eq!(*expected.tile(&pos), *visible.tile(&pos));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tables_give_same_fov_far_from_map_origin() {
+        let db = Db::new();
+        let max_range = db.max_los_range();
+        let mut rng: StdRng = SeedableRng::from_seed(&[4][..]);
+        let size = Size2{w: 220, h: 220};
+        for _ in 0 .. 5 {
+            let map = random_map(&mut rng, &size);
+            for _ in 0 .. 20 {
+                let origin = MapPos{v: Vector2 {
+                    x: rng.gen_range(190, 210),
+                    y: rng.gen_range(190, 210),
+                }};
+                let range = rng.gen_range(1, max_range + 1);
+                let mut expected = Vec::new();
+                fov_without_tables(&map, &origin, range,
+                    &mut |pos| expected.push(pos.clone()));
+                let mut visible = Vec::new();
+                fov(db.shadow_tables(), &map, &origin, range,
+                    &mut |pos| visible.push(pos.clone()));
+                assert_eq!(expected, visible);
+            }
+        }
+    }
+
+    #[test]
+    fn is_in_fov_matches_fov_on_random_maps() {
+        let mut rng: StdRng = SeedableRng::from_seed(&[1][..]);
+        let size = Size2{w: 20, h: 20};
+        let tables = ShadowTables::new(9);
+        for _ in 0 .. 20 {
+            let map = random_map(&mut rng, &size);
+            let origin = random_pos(&mut rng, &size);
+            let range = rng.gen_range(1, 10);
+            let mut visible = Map::new(&size, false);
+            fov(&tables, &map, &origin, range, &mut |pos| *visible.tile_mut(pos) = true);
+            for pos in map.get_iter() {
+                assert_eq!(*visible.tile(&pos), is_in_fov(&map, &origin, range, &pos));
             }
-            shadows.push(shadow);
         }
     }
 }
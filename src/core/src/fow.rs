@@ -1,17 +1,20 @@
 // See LICENSE file for copyright and license details.
 
 use common::types::{PlayerId, MapPos, Size2, ZInt};
-use core::{CoreEvent};
+use common::misc::{clamp};
+use core::{CoreEvent, los};
 use internal_state::{InternalState};
 use map::{Map, Terrain, distance};
-use fov::{fov};
+use fov::{fov, is_in_fov};
 use db::{Db};
 use unit::{Unit, UnitType, UnitClass};
 
 #[derive(Clone, PartialEq, PartialOrd)]
 pub enum TileVisibility {
     No,
-    // Bad,
+    /// Units are seen only if spotted, see `spotting_chance`
+    Bad,
+    /// Light cover: vehicles are seen, infantry only if spotted
     Normal,
     Excellent,
 }
@@ -21,8 +24,9 @@ pub fn fov_unit(
     terrain: &Map<Terrain>,
     fow: &mut Map<TileVisibility>,
     unit: &Unit,
+    is_los_symmetric: bool,
 ) {
-    fov_unit_in_pos(db, terrain, fow, unit, &unit.pos);
+    fov_unit_in_pos(db, terrain, fow, unit, &unit.pos, is_los_symmetric);
 }
 
 pub fn fov_unit_in_pos(
@@ -31,14 +35,19 @@ pub fn fov_unit_in_pos(
     fow: &mut Map<TileVisibility>,
     unit: &Unit,
     origin: &MapPos,
+    is_los_symmetric: bool,
 ) {
     let unit_type = db.unit_type(&unit.type_id);
     let range = &unit_type.los_range;
     fov(
+        db.shadow_tables(),
         terrain,
         origin,
         *range,
         &mut |pos| {
+            if is_los_symmetric && !is_in_fov(terrain, pos, *range, origin) {
+                return;
+            }
             let distance = distance(origin, pos);
             let vis = calc_visibility(terrain.tile(pos), unit_type, &distance);
             if vis > *fow.tile_mut(pos) {
@@ -53,52 +62,165 @@ fn calc_visibility(terrain: &Terrain, unit_type: &UnitType, distance: &ZInt)
 {
     if *distance <= unit_type.cover_los_range {
         TileVisibility::Excellent
-    } else if *distance <= unit_type.los_range {
+    } else if *distance < unit_type.los_range {
         match terrain {
             &Terrain::Trees => TileVisibility::Normal,
             &Terrain::Plain => TileVisibility::Excellent,
         }
+    } else if *distance == unit_type.los_range {
+        TileVisibility::Bad
     } else {
         TileVisibility::No
     }
 }
 
+fn is_unit_visible(visibility: &TileVisibility, unit_type: &UnitType) -> bool {
+    match *visibility {
+        TileVisibility::Excellent => true,
+        TileVisibility::Normal => match unit_type.class {
+            UnitClass::Infantry => false,
+            UnitClass::Vehicle => true,
+        },
+        TileVisibility::Bad | TileVisibility::No => false,
+    }
+}
+
+/// Is unit of `unit_type` in tile with `visibility` seen only if spotted
+pub fn needs_spotting_roll(visibility: &TileVisibility, unit_type: &UnitType) -> bool {
+    match *visibility {
+        TileVisibility::Bad => true,
+        TileVisibility::Normal => match unit_type.class {
+            UnitClass::Infantry => true,
+            UnitClass::Vehicle => false,
+        },
+        TileVisibility::Excellent | TileVisibility::No => false,
+    }
+}
+
+/// Bonus to spotting chance of units that moved or fired this turn
+const ACTIVE_UNIT_SPOTTING_BONUS: ZInt = 30;
+
+/// Chance (in percents) that unit of `unit_type` type that
+/// `needs_spotting_roll` is spotted by observer with `spotting` skill.
+/// Big units and units that moved or fired this turn are easier to spot.
+pub fn spotting_chance(spotting: ZInt, unit_type: &UnitType, is_active: bool) -> ZInt {
+    let bonus = if is_active { ACTIVE_UNIT_SPOTTING_BONUS } else { 0 };
+    clamp(unit_type.size * 10 - 20 + spotting + bonus, 0, 100)
+}
+
+/// Visibility of `pos` tile for `observer` from its current position
+pub fn visibility_for(
+    db: &Db,
+    terrain: &Map<Terrain>,
+    observer: &Unit,
+    pos: &MapPos,
+    is_los_symmetric: bool,
+) -> TileVisibility {
+    let observer_type = db.unit_type(&observer.type_id);
+    if !los(terrain, observer_type, &observer.pos, pos, is_los_symmetric) {
+        return TileVisibility::No;
+    }
+    let distance = distance(&observer.pos, pos);
+    calc_visibility(terrain.tile(pos), observer_type, &distance)
+}
+
+/// Can `observer` see unit of `unit_type` type in `pos` from its current
+/// position. Units that `needs_spotting_roll` may be spotted,
+/// so they are counted as visible.
+pub fn is_visible_to(
+    db: &Db,
+    terrain: &Map<Terrain>,
+    observer: &Unit,
+    unit_type: &UnitType,
+    pos: &MapPos,
+    is_los_symmetric: bool,
+) -> bool {
+    let visibility = visibility_for(db, terrain, observer, pos, is_los_symmetric);
+    is_unit_visible(&visibility, unit_type)
+        || needs_spotting_roll(&visibility, unit_type)
+}
+
 /// Fog of War
 pub struct Fow {
     map: Map<TileVisibility>,
+    /// Tiles that were visible at least once. Unlike `map`,
+    /// isn`t reset and isn`t restored by `UndoMove`:
+    /// player can`t forget what they have seen.
+    explored: Map<bool>,
     player_id: PlayerId,
+    is_enabled: bool,
+    /// See `Rules::symmetric_los`
+    is_los_symmetric: bool,
+    /// Saved before last move of own unit, restored by `UndoMove` event
+    map_before_last_move: Option<Map<TileVisibility>>,
 }
 
 impl Fow {
     pub fn new(map_size: &Size2, player_id: &PlayerId) -> Fow {
         Fow {
             map: Map::new(map_size, TileVisibility::No),
+            explored: Map::new(map_size, false),
             player_id: player_id.clone(),
+            is_enabled: true,
+            is_los_symmetric: false,
+            map_before_last_move: None,
         }
     }
 
+    /// Makes all tiles visible forever
+    pub fn disable(&mut self) {
+        self.is_enabled = false;
+        self.clear();
+        self.update_explored();
+    }
+
+    pub fn enable_symmetric_los(&mut self) {
+        self.is_los_symmetric = true;
+    }
+
+    pub fn is_los_symmetric(&self) -> bool {
+        self.is_los_symmetric
+    }
+
     pub fn is_tile_visible(&self, pos: &MapPos) -> bool {
         match *self.map.tile(pos) {
             TileVisibility::Excellent => true,
             TileVisibility::Normal => true,
+            TileVisibility::Bad => true,
             TileVisibility::No => false,
         }
     }
 
+    pub fn is_tile_explored(&self, pos: &MapPos) -> bool {
+        *self.explored.tile(pos)
+    }
+
+    pub fn tile_visibility(&self, pos: &MapPos) -> &TileVisibility {
+        self.map.tile(pos)
+    }
+
+    /// Is unit certainly visible. Units that `needs_spotting_roll`
+    /// are spotted by `Core` with dice rolls.
     pub fn is_visible(&self, unit_type: &UnitType, pos: &MapPos) -> bool {
-        match *self.map.tile(pos) {
-            TileVisibility::Excellent => true,
-            TileVisibility::Normal => match unit_type.class {
-                UnitClass::Infantry => false,
-                UnitClass::Vehicle => true,
-            },
-            TileVisibility::No => false,
-        }
+        is_unit_visible(self.map.tile(pos), unit_type)
     }
 
     fn clear(&mut self) {
+        let visibility = if self.is_enabled {
+            TileVisibility::No
+        } else {
+            TileVisibility::Excellent
+        };
         for pos in self.map.get_iter() {
-            *self.map.tile_mut(&pos) = TileVisibility::No;
+            *self.map.tile_mut(&pos) = visibility.clone();
+        }
+    }
+
+    fn update_explored(&mut self) {
+        for pos in self.map.get_iter() {
+            if self.is_tile_visible(&pos) {
+                *self.explored.tile_mut(&pos) = true;
+            }
         }
     }
 
@@ -106,7 +228,7 @@ impl Fow {
         self.clear();
         for (_, unit) in state.units() {
             if unit.player_id == self.player_id {
-                fov_unit(db, state.map(), &mut self.map, &unit);
+                fov_unit(db, state.map(), &mut self.map, &unit, self.is_los_symmetric);
             }
         }
     }
@@ -121,13 +243,20 @@ impl Fow {
             &CoreEvent::Move{ref unit_id, ref path, ..} => {
                 let unit = state.unit(unit_id);
                 if unit.player_id == self.player_id {
+                    self.map_before_last_move = Some(self.map.clone());
                     for path_node in path.nodes() {
                         let p = &path_node.pos;
-                        fov_unit_in_pos(
-                            db, state.map(), &mut self.map, unit, p);
+                        fov_unit_in_pos(db, state.map(), &mut self.map,
+                            unit, p, self.is_los_symmetric);
                     }
                 }
             },
+            &CoreEvent::UndoMove{ref unit_id, ..} => {
+                if state.unit(unit_id).player_id == self.player_id {
+                    self.map = self.map_before_last_move.take()
+                        .expect("fow: Nothing to undo");
+                }
+            },
             &CoreEvent::EndTurn{ref new_id, ..} => {
                 if self.player_id == *new_id {
                     self.reset(db, state);
@@ -136,12 +265,85 @@ impl Fow {
             &CoreEvent::CreateUnit{ref unit_id, ref player_id, ..} => {
                 let unit = state.unit(unit_id);
                 if self.player_id == *player_id {
-                    fov_unit(db, state.map(), &mut self.map, unit);
+                    fov_unit(db, state.map(), &mut self.map, unit, self.is_los_symmetric);
                 }
             },
             &CoreEvent::AttackUnit{..} => {},
             &CoreEvent::ShowUnit{..} => {},
             &CoreEvent::HideUnit{..} => {},
+            &CoreEvent::RepairUnit{..} => {},
+            &CoreEvent::SetReinforcementPoints{..} => {},
+            &CoreEvent::SetMoveOrder{..} => {},
+        }
+        self.update_explored();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, StdRng};
+    use cgmath::{Vector2};
+    use common::types::{PlayerId, UnitId, MapPos, Size2};
+    use map::{Map, Terrain};
+    use unit::{Unit};
+    use db::{Db};
+    use super::{TileVisibility, fov_unit, spotting_chance};
+
+    #[test]
+    fn scouts_spot_big_and_active_units_better() {
+        let db = Db::new();
+        let soldier = db.unit_type(&db.unit_type_id("soldier"));
+        let scout = db.unit_type(&db.unit_type_id("scout"));
+        let tank = db.unit_type(&db.unit_type_id("tank"));
+        let chance = spotting_chance(soldier.spotting, soldier, false);
+        assert!(chance > 0);
+        assert!(spotting_chance(scout.spotting, soldier, false) > chance);
+        assert!(spotting_chance(soldier.spotting, tank, false) > chance);
+        assert!(spotting_chance(soldier.spotting, soldier, true) > chance);
+        assert!(spotting_chance(100, tank, true) <= 100);
+    }
+
+    #[test]
+    fn symmetric_fow_is_symmetric() {
+        let db = Db::new();
+        let type_id = db.unit_type_id("scout");
+        let mut rng: StdRng = SeedableRng::from_seed(&[4][..]);
+        let size = Size2{w: 16, h: 16};
+        let unit_at = |id, pos: &MapPos| Unit {
+            id: UnitId{id: id},
+            pos: pos.clone(),
+            player_id: PlayerId{id: id},
+            type_id: type_id.clone(),
+            move_points: 0,
+            attack_points: 0,
+            reactive_attack_points: None,
+            count: 1,
+            morale: 100,
+            is_immobilized: false,
+            is_weapon_disabled: false,
+        };
+        for _ in 0 .. 20 {
+            let mut map = Map::new(&size, Terrain::Plain);
+            for pos in map.get_iter() {
+                if rng.gen_range(0, 100) < 20 {
+                    *map.tile_mut(&pos) = Terrain::Trees;
+                }
+            }
+            let unit_a = unit_at(0, &MapPos{v: Vector2 {
+                x: rng.gen_range(0, size.w),
+                y: rng.gen_range(0, size.h),
+            }});
+            let mut fow_a = Map::new(&size, TileVisibility::No);
+            fov_unit(&db, &map, &mut fow_a, &unit_a, true);
+            for pos in map.get_iter() {
+                let unit_b = unit_at(1, &pos);
+                let mut fow_b = Map::new(&size, TileVisibility::No);
+                fov_unit(&db, &map, &mut fow_b, &unit_b, true);
+                // A sees B`s tile if and only if B sees A`s tile
+                assert_eq!(
+                    *fow_a.tile(&unit_b.pos) != TileVisibility::No,
+                    *fow_b.tile(&unit_a.pos) != TileVisibility::No);
+            }
         }
     }
 }
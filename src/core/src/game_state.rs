@@ -1,50 +1,435 @@
 // See LICENSE file for copyright and license details.
 
-use std::collections::{HashMap};
-use common::types::{PlayerId, UnitId, MapPos, Size2};
-use core::{CoreEvent};
-use unit::{Unit};
+use std::collections::{HashMap, BTreeMap};
+use common::types::{PlayerId, UnitId, MapPos, Size2, ZInt};
+use core::{CoreEvent, AttackOdds, attack_odds, attack_odds_at, can_fire_at};
+use unit::{Unit, UnitTypeId};
 use db::{Db};
-use map::{Map, Terrain};
+use map::{Map, Terrain, spiral_iter};
 use internal_state::{InternalState};
-use fow::{Fow};
+use move_order::{MoveOrder};
+use fow::{Fow, is_visible_to};
+use pathfinder::{MapPath};
+use options::{Rules};
+
+/// Whose view of the game is shown or sent
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub enum Perspective {
+    /// Unfiltered stream of events, for observers
+    Full,
+    /// Player`s fog-filtered stream of events
+    Player(PlayerId),
+}
+
+/// Known enemy that can react to move of unit to some path node
+pub struct ReactionFireThreat {
+    pub enemy_id: UnitId,
+    pub odds: AttackOdds,
+}
+
+/// What player knows about tile
+#[derive(Clone, PartialEq, Debug)]
+pub enum TileKnowledge {
+    /// Never seen, its terrain is unknown
+    Unexplored,
+    /// Seen before, but units there are hidden by fog of war
+    Fogged,
+    Visible,
+}
+
+/// How many own turns hidden enemies are remembered by default
+pub const DEFAULT_LAST_SEEN_TURNS: ZInt = 3;
+
+/// Where enemy unit was seen before it disappeared in fog of war
+#[derive(Clone)]
+pub struct LastSeen {
+    pub pos: MapPos,
+    pub type_id: UnitTypeId,
+    pub player_id: PlayerId,
+    /// Own turn when unit was seen for the last time
+    pub turn: ZInt,
+}
 
 pub struct GameState {
     state: InternalState,
+    /// Terrain as player knows it: unexplored tiles are plain.
+    /// `fow` still uses real terrain to match `Core`.
+    known_map: Map<Terrain>,
     fow: Fow,
+    player_id: PlayerId,
+    /// Number of own turns that were started
+    turn: ZInt,
+    last_seen: HashMap<UnitId, LastSeen>,
+    last_seen_turns: ZInt,
+    rules: Rules,
 }
 
 impl<'a> GameState {
     pub fn new(map_size: &Size2, player_id: &PlayerId) -> GameState {
         GameState {
             state: InternalState::new(map_size),
+            known_map: Map::new(map_size, Terrain::Plain),
             fow: Fow::new(map_size, player_id),
+            player_id: player_id.clone(),
+            turn: 0,
+            last_seen: HashMap::new(),
+            last_seen_turns: DEFAULT_LAST_SEEN_TURNS,
+            rules: Rules::new(),
+        }
+    }
+
+    /// Hidden enemies are forgotten after `turns` own turns
+    pub fn set_last_seen_turns(&mut self, turns: ZInt) {
+        self.last_seen_turns = turns;
+    }
+
+    pub fn turn(&self) -> ZInt {
+        self.turn
+    }
+
+    /// Enemies that are not visible now, but were seen recently
+    pub fn last_seen(&self) -> &HashMap<UnitId, LastSeen> {
+        &self.last_seen
+    }
+
+    pub fn disable_fow(&mut self) {
+        self.fow.disable();
+        self.update_known_map();
+    }
+
+    /// Must be called before any events are applied
+    pub fn set_rules(&mut self, rules: &Rules) {
+        if rules.symmetric_los {
+            self.fow.enable_symmetric_los();
         }
+        self.rules = rules.clone();
+    }
+
+    pub fn rules(&self) -> &Rules {
+        &self.rules
+    }
+
+    pub fn is_los_symmetric(&self) -> bool {
+        self.fow.is_los_symmetric()
     }
 
-    pub fn units(&self) -> &HashMap<UnitId, Unit> {
+    pub fn fow(&self) -> &Fow {
+        &self.fow
+    }
+
+    pub fn units(&self) -> &BTreeMap<UnitId, Unit> {
         &self.state.units()
     }
 
+    /// Orders of own units
+    pub fn move_orders(&self) -> &HashMap<UnitId, MoveOrder> {
+        self.state.move_orders()
+    }
+
+    /// Terrain known to player, unexplored tiles are plain.
+    /// All queries of `GameState` use it, so terrain isn`t
+    /// revealed before player explores it.
     pub fn map(&'a self) -> &Map<Terrain> {
-        &self.state.map()
+        &self.known_map
     }
 
     pub fn units_at(&'a self, pos: &MapPos) -> Vec<&'a Unit> {
         self.state.units_at(pos)
     }
 
+    pub fn reinforcement_points(&self, player_id: &PlayerId) -> ZInt {
+        self.state.reinforcement_points(player_id)
+    }
+
     pub fn is_tile_visible(&self, pos: &MapPos) -> bool {
         self.fow.is_tile_visible(pos)
     }
 
+    pub fn tile_knowledge(&self, pos: &MapPos) -> TileKnowledge {
+        if self.fow.is_tile_visible(pos) {
+            TileKnowledge::Visible
+        } else if self.fow.is_tile_explored(pos) {
+            TileKnowledge::Fogged
+        } else {
+            TileKnowledge::Unexplored
+        }
+    }
+
+    /// Terrain of explored tile
+    pub fn known_terrain(&self, pos: &MapPos) -> Option<&Terrain> {
+        if self.fow.is_tile_explored(pos) {
+            Some(self.known_map.tile(pos))
+        } else {
+            None
+        }
+    }
+
     pub fn is_tile_occupied(&self, pos: &MapPos) -> bool {
         self.state.is_tile_occupied(pos)
     }
 
+    pub fn attack_odds(
+        &self,
+        db: &Db,
+        attacker_id: &UnitId,
+        defender_id: &UnitId,
+        firing_pos: &MapPos,
+    ) -> AttackOdds {
+        let attacker = &self.units()[attacker_id];
+        let defender = &self.units()[defender_id];
+        attack_odds(db, &self.rules, attacker, defender, firing_pos)
+    }
+
+    /// Client-side estimation of `Core::reaction_fire_move`: for every
+    /// path node returns list of known enemies that can fire at the unit.
+    pub fn reaction_fire_danger(
+        &self,
+        db: &Db,
+        unit_id: &UnitId,
+        path: &MapPath,
+    ) -> Vec<Vec<ReactionFireThreat>> {
+        let unit = &self.units()[unit_id];
+        let unit_type = db.unit_type(&unit.type_id);
+        let is_symmetric = self.is_los_symmetric();
+        let mut danger = Vec::new();
+        for (i, node) in path.nodes().iter().enumerate() {
+            let mut threats = Vec::new();
+            if i == 0 {
+                danger.push(threats);
+                continue;
+            }
+            let pos = &node.pos;
+            for (_, enemy) in self.units() {
+                if enemy.player_id == unit.player_id {
+                    continue;
+                }
+                // enemy`s reactive attack points are unknown, so assume the worst
+                if let Some(reactive_attack_points) = enemy.reactive_attack_points {
+                    if reactive_attack_points <= 0 {
+                        continue;
+                    }
+                }
+                if !is_visible_to(db, self.map(), enemy, unit_type, pos, is_symmetric) {
+                    continue;
+                }
+                if !can_fire_at(db, self.map(), enemy, pos, is_symmetric) {
+                    continue;
+                }
+                threats.push(ReactionFireThreat {
+                    enemy_id: enemy.id.clone(),
+                    odds: attack_odds_at(db, &self.rules, enemy, &enemy.pos, unit, pos),
+                });
+            }
+            danger.push(threats);
+        }
+        danger
+    }
+
+    /// For every tile: number of known enemies that
+    /// can fire at `unit` if it stands on that tile
+    pub fn threat_map(&self, db: &Db, unit: &Unit) -> Map<ZInt> {
+        let unit_type = db.unit_type(&unit.type_id);
+        let is_symmetric = self.is_los_symmetric();
+        let mut threats = Map::new(self.map().size(), 0);
+        for (_, enemy) in self.units() {
+            if enemy.player_id == unit.player_id {
+                continue;
+            }
+            if let Some(reactive_attack_points) = enemy.reactive_attack_points {
+                if reactive_attack_points <= 0 {
+                    continue;
+                }
+            }
+            let max_distance = db.unit_max_attack_dist(enemy);
+            if max_distance < 1 {
+                continue;
+            }
+            for pos in spiral_iter(&enemy.pos, max_distance) {
+                if !self.map().is_inboard(&pos) {
+                    continue;
+                }
+                if !is_visible_to(db, self.map(), enemy, unit_type, &pos, is_symmetric) {
+                    continue;
+                }
+                if !can_fire_at(db, self.map(), enemy, &pos, is_symmetric) {
+                    continue;
+                }
+                *threats.tile_mut(&pos) += 1;
+            }
+        }
+        threats
+    }
+
+    fn forget_old_last_seen(&mut self) {
+        let mut old_ids = Vec::new();
+        for (unit_id, last_seen) in &self.last_seen {
+            if self.turn - last_seen.turn > self.last_seen_turns {
+                old_ids.push(unit_id.clone());
+            }
+        }
+        for unit_id in old_ids {
+            self.last_seen.remove(&unit_id);
+        }
+    }
+
+    /// Must be called before event is applied: hidden unit is still here
+    fn update_last_seen(&mut self, event: &CoreEvent) {
+        match *event {
+            CoreEvent::HideUnit{ref unit_id} => {
+                let unit = self.state.unit(unit_id);
+                if unit.player_id != self.player_id {
+                    self.last_seen.insert(unit_id.clone(), LastSeen {
+                        pos: unit.pos.clone(),
+                        type_id: unit.type_id.clone(),
+                        player_id: unit.player_id.clone(),
+                        turn: self.turn,
+                    });
+                }
+            },
+            CoreEvent::ShowUnit{ref unit_id, ..}
+                | CoreEvent::CreateUnit{ref unit_id, ..} =>
+            {
+                self.last_seen.remove(unit_id);
+            },
+            CoreEvent::EndTurn{ref new_id, ..} => {
+                if *new_id == self.player_id {
+                    self.turn += 1;
+                    self.forget_old_last_seen();
+                }
+            },
+            _ => {},
+        }
+    }
+
     pub fn apply_event(&mut self, db: &Db, event: &CoreEvent) {
+        self.update_last_seen(event);
         self.state.apply_event(db, event);
         self.fow.apply_event(db, &self.state, event);
+        self.update_known_map();
+    }
+
+    fn update_known_map(&mut self) {
+        for pos in self.known_map.get_iter() {
+            if self.fow.is_tile_explored(&pos) {
+                *self.known_map.tile_mut(&pos) = self.state.map().tile(&pos).clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Vector2};
+    use common::types::{ZInt, PlayerId, UnitId, MapPos, Size2};
+    use core::{CoreEvent};
+    use command::{MoveMode};
+    use db::{Db};
+    use map::{Terrain};
+    use pathfinder::{MapPath, PathNode, MoveCost};
+    use super::{GameState, TileKnowledge};
+
+    fn pos(x: ZInt, y: ZInt) -> MapPos {
+        MapPos{v: Vector2{x: x, y: y}}
+    }
+
+    #[test]
+    fn hidden_enemies_are_remembered_for_some_turns() {
+        let db = Db::new();
+        let player_0 = PlayerId{id: 0};
+        let player_1 = PlayerId{id: 1};
+        let enemy_id = UnitId{id: 0};
+        let enemy_pos = pos(1, 1);
+        let mut state = GameState::new(&Size2{w: 5, h: 5}, &player_0);
+        state.disable_fow();
+        state.set_last_seen_turns(1);
+        let show = CoreEvent::ShowUnit {
+            unit_id: enemy_id.clone(),
+            pos: enemy_pos.clone(),
+            type_id: db.unit_type_id("soldier"),
+            player_id: player_1.clone(),
+            is_immobilized: false,
+            is_weapon_disabled: false,
+        };
+        let hide = CoreEvent::HideUnit{unit_id: enemy_id.clone()};
+        let end_turn = CoreEvent::EndTurn {
+            old_id: player_1.clone(),
+            new_id: player_0.clone(),
+        };
+        state.apply_event(&db, &show);
+        state.apply_event(&db, &hide);
+        assert!(state.units().get(&enemy_id).is_none());
+        assert!(state.last_seen()[&enemy_id].pos == enemy_pos);
+        state.apply_event(&db, &show);
+        assert!(state.last_seen().get(&enemy_id).is_none());
+        state.apply_event(&db, &hide);
+        state.apply_event(&db, &end_turn);
+        assert!(state.last_seen().get(&enemy_id).is_some());
+        state.apply_event(&db, &end_turn);
+        assert!(state.last_seen().get(&enemy_id).is_none());
+    }
+
+    #[test]
+    fn explored_tiles_stay_known() {
+        let db = Db::new();
+        let player_id = PlayerId{id: 0};
+        let unit_id = UnitId{id: 0};
+        let start = pos(0, 0);
+        let finish = pos(9, 9);
+        let mut state = GameState::new(&Size2{w: 10, h: 10}, &player_id);
+        state.apply_event(&db, &CoreEvent::CreateUnit {
+            unit_id: unit_id.clone(),
+            pos: start.clone(),
+            type_id: db.unit_type_id("soldier"),
+            player_id: player_id.clone(),
+        });
+        assert_eq!(state.tile_knowledge(&start), TileKnowledge::Visible);
+        assert_eq!(state.tile_knowledge(&finish), TileKnowledge::Unexplored);
+        state.apply_event(&db, &CoreEvent::Move {
+            unit_id: unit_id.clone(),
+            path: MapPath::new(vec![
+                PathNode{cost: MoveCost{n: 0}, pos: start.clone()},
+                PathNode{cost: MoveCost{n: 0}, pos: finish.clone()},
+            ]),
+            mode: MoveMode::Fast,
+        });
+        state.apply_event(&db, &CoreEvent::EndTurn {
+            old_id: player_id.clone(),
+            new_id: player_id.clone(),
+        });
+        assert_eq!(state.tile_knowledge(&start), TileKnowledge::Fogged);
+        assert_eq!(state.tile_knowledge(&finish), TileKnowledge::Visible);
+        assert_eq!(state.tile_knowledge(&pos(0, 9)), TileKnowledge::Unexplored);
+    }
+
+    #[test]
+    fn unexplored_terrain_is_hidden() {
+        let db = Db::new();
+        let player_id = PlayerId{id: 0};
+        let unit_id = UnitId{id: 0};
+        let trees_pos = pos(6, 4);
+        let is_trees = |state: &GameState| match *state.map().tile(&trees_pos) {
+            Terrain::Trees => true,
+            Terrain::Plain => false,
+        };
+        let mut state = GameState::new(&Size2{w: 10, h: 10}, &player_id);
+        state.apply_event(&db, &CoreEvent::CreateUnit {
+            unit_id: unit_id.clone(),
+            pos: pos(0, 0),
+            type_id: db.unit_type_id("soldier"),
+            player_id: player_id.clone(),
+        });
+        assert!(state.known_terrain(&trees_pos).is_none());
+        assert!(!is_trees(&state));
+        state.apply_event(&db, &CoreEvent::Move {
+            unit_id: unit_id.clone(),
+            path: MapPath::new(vec![
+                PathNode{cost: MoveCost{n: 0}, pos: pos(0, 0)},
+                PathNode{cost: MoveCost{n: 0}, pos: pos(7, 4)},
+            ]),
+            mode: MoveMode::Fast,
+        });
+        assert!(state.known_terrain(&trees_pos).is_some());
+        assert!(is_trees(&state));
     }
 }
 
@@ -0,0 +1,165 @@
+// See LICENSE file for copyright and license details.
+
+//! Group moves: several units go to one area and try to keep
+//! their formation. Paths are planned with player`s knowledge,
+//! `Core` resolves them one by one.
+
+use std::iter;
+use cgmath::{Vector2};
+use common::types::{ZInt, UnitId, MapPos};
+use command::{MoveMode};
+use db::{Db};
+use game_state::{GameState};
+use map::{distance, spiral_iter};
+use pathfinder::{Pathfinder, MapPath};
+use unit::{Unit};
+
+/// How far from its place in formation unit may stop
+const MAX_FORMATION_SHIFT: ZInt = 3;
+
+// Axial coordinates: formation can be moved by adding vectors
+// without breaking distances between units.
+
+fn to_axial(pos: &MapPos) -> (ZInt, ZInt) {
+    (pos.v.x + pos.v.y / 2, pos.v.y)
+}
+
+fn from_axial(q: ZInt, r: ZInt) -> MapPos {
+    MapPos{v: Vector2{x: q - r / 2, y: r}}
+}
+
+/// Position of unit that is the closest to all other units of group
+fn center_pos(units: &[&Unit]) -> MapPos {
+    let mut best_pos = units[0].pos.clone();
+    let mut best_sum = None;
+    for unit in units {
+        let sum = units.iter().fold(0, |sum, other| sum + distance(&unit.pos, &other.pos));
+        if best_sum.map_or(true, |best_sum| sum < best_sum) {
+            best_sum = Some(sum);
+            best_pos = unit.pos.clone();
+        }
+    }
+    best_pos
+}
+
+/// Place of unit at `pos` in formation moved from `center` to `destination`
+fn formation_pos(center: &MapPos, destination: &MapPos, pos: &MapPos) -> MapPos {
+    let (center_q, center_r) = to_axial(center);
+    let (q, r) = to_axial(pos);
+    let (destination_q, destination_r) = to_axial(destination);
+    from_axial(destination_q + q - center_q, destination_r + r - center_r)
+}
+
+/// Paths for this turn that move units towards `destination` keeping
+/// their formation. Units that are closer to destination go first,
+/// so they rarely block others. Every unit gets its own final tile,
+/// units that can`t move are skipped.
+pub fn plan_group_move(
+    db: &Db,
+    state: &GameState,
+    pathfinder: &mut Pathfinder,
+    unit_ids: &[UnitId],
+    destination: &MapPos,
+    mode: &MoveMode,
+) -> Vec<(UnitId, MapPath)> {
+    let mut units: Vec<&Unit> = unit_ids.iter()
+        .filter_map(|unit_id| state.units().get(unit_id))
+        .filter(|unit| !unit.is_immobilized && unit.move_points > 0)
+        .collect();
+    if units.is_empty() {
+        return Vec::new();
+    }
+    let center = center_pos(&units);
+    units.sort_by(|a, b| {
+        distance(&a.pos, destination).cmp(&distance(&b.pos, destination))
+    });
+    let mut reserved: Vec<MapPos> = Vec::new();
+    let mut moves = Vec::new();
+    for unit in units {
+        let target = formation_pos(&center, destination, &unit.pos);
+        let max_move_points = db.unit_type(&unit.type_id).move_points;
+        pathfinder.fill_map(db, state, unit, mode);
+        let candidates = iter::once(target.clone())
+            .chain(spiral_iter(&target, MAX_FORMATION_SHIFT));
+        for pos in candidates {
+            if !state.map().is_inboard(&pos) || reserved.contains(&pos) {
+                continue;
+            }
+            if pos == unit.pos {
+                reserved.push(pos);
+                break;
+            }
+            let mut path = match pathfinder.get_path(&pos) {
+                Some(path) => path,
+                None => continue,
+            };
+            path.mark_turns(unit.move_points, max_move_points);
+            let path = path.first_turn();
+            if path.nodes().len() < 2
+                || path.total_cost().n > unit.move_points
+                || reserved.contains(path.destination())
+            {
+                continue;
+            }
+            reserved.push(path.destination().clone());
+            moves.push((unit.id.clone(), path));
+            break;
+        }
+    }
+    moves
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Vector2};
+    use common::types::{ZInt, PlayerId, UnitId, MapPos, Size2};
+    use command::{MoveMode};
+    use core::{CoreEvent};
+    use db::{Db};
+    use game_state::{GameState};
+    use pathfinder::{Pathfinder};
+    use super::{plan_group_move};
+
+    fn pos(x: ZInt, y: ZInt) -> MapPos {
+        MapPos{v: Vector2{x: x, y: y}}
+    }
+
+    #[test]
+    fn group_keeps_formation() {
+        let db = Db::new();
+        let map_size = Size2{w: 10, h: 10};
+        let mut state = GameState::new(&map_size, &PlayerId{id: 0});
+        state.disable_fow();
+        let positions = [pos(1, 1), pos(2, 1), pos(3, 1), pos(3, 3)];
+        for (id, unit_pos) in positions.iter().enumerate() {
+            state.apply_event(&db, &CoreEvent::CreateUnit {
+                unit_id: UnitId{id: id as ZInt},
+                pos: unit_pos.clone(),
+                type_id: db.unit_type_id("soldier"),
+                player_id: PlayerId{id: 0},
+            });
+        }
+        let unit_ids = [UnitId{id: 0}, UnitId{id: 1}, UnitId{id: 2}];
+        let mut pathfinder = Pathfinder::new(&map_size);
+        let moves = plan_group_move(
+            &db, &state, &mut pathfinder, &unit_ids, &pos(2, 3), &MoveMode::Fast);
+        assert_eq!(moves.len(), 3);
+        for &(ref unit_id, ref path) in &moves {
+            let unit = &state.units()[unit_id];
+            assert!(path.total_cost().n <= unit.move_points);
+            // (3, 3) is occupied, so the third unit stops near it
+            match unit_id.id {
+                0 => assert_eq!(*path.destination(), pos(1, 3)),
+                1 => assert_eq!(*path.destination(), pos(2, 3)),
+                _ => assert!(*path.destination() != pos(3, 3)),
+            }
+            for &(ref other_id, ref other_path) in &moves {
+                if other_id != unit_id {
+                    assert!(path.destination() != other_path.destination());
+                }
+            }
+        }
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
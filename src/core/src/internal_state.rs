@@ -1,13 +1,13 @@
 // See LICENSE file for copyright and license details.
 
-use std::collections::{HashMap};
+use std::collections::{HashMap, BTreeMap};
 use cgmath::{Vector2};
-use common::types::{PlayerId, UnitId, MapPos, Size2};
+use common::types::{PlayerId, UnitId, MapPos, Size2, ZInt};
 use core::{CoreEvent, FireMode};
-use unit::{Unit, UnitTypeId};
+use unit::{Unit, UnitTypeId, VehicleDamage};
 use db::{Db};
 use map::{Map, Terrain};
-use command::{MoveMode};
+use move_order::{MoveOrder};
 
 pub enum InfoLevel {
     Full,
@@ -15,29 +15,40 @@ pub enum InfoLevel {
 }
 
 pub struct InternalState {
-    units: HashMap<UnitId, Unit>,
+    units: BTreeMap<UnitId, Unit>,
     map: Map<Terrain>,
+    reinforcement_points: HashMap<PlayerId, ZInt>,
+    move_orders: HashMap<UnitId, MoveOrder>,
 }
 
 impl<'a> InternalState {
     pub fn new(map_size: &Size2) -> InternalState {
         let mut map = Map::new(map_size, Terrain::Plain);
         // TODO: read from scenario.json?
-        *map.tile_mut(&MapPos{v: Vector2{x: 4, y: 3}}) = Terrain::Trees;
-        *map.tile_mut(&MapPos{v: Vector2{x: 4, y: 4}}) = Terrain::Trees;
-        *map.tile_mut(&MapPos{v: Vector2{x: 4, y: 5}}) = Terrain::Trees;
-        *map.tile_mut(&MapPos{v: Vector2{x: 5, y: 5}}) = Terrain::Trees;
-        *map.tile_mut(&MapPos{v: Vector2{x: 6, y: 4}}) = Terrain::Trees;
+        let trees = [(4, 3), (4, 4), (4, 5), (5, 5), (6, 4)];
+        for &(x, y) in trees.iter() {
+            let pos = MapPos{v: Vector2{x: x, y: y}};
+            if map.is_inboard(&pos) {
+                *map.tile_mut(&pos) = Terrain::Trees;
+            }
+        }
         InternalState {
-            units: HashMap::new(),
+            units: BTreeMap::new(),
             map: map,
+            reinforcement_points: HashMap::new(),
+            move_orders: HashMap::new(),
         }
     }
 
-    pub fn units(&self) -> &HashMap<UnitId, Unit> {
+    pub fn units(&self) -> &BTreeMap<UnitId, Unit> {
         &self.units
     }
 
+    /// Orders of units that are known to this state`s owner
+    pub fn move_orders(&self) -> &HashMap<UnitId, MoveOrder> {
+        &self.move_orders
+    }
+
     pub fn unit(&'a self, id: &UnitId) -> &'a Unit {
         &self.units[id]
     }
@@ -46,6 +57,13 @@ impl<'a> InternalState {
         &self.map
     }
 
+    pub fn reinforcement_points(&self, player_id: &PlayerId) -> ZInt {
+        match self.reinforcement_points.get(player_id) {
+            Some(points) => *points,
+            None => 0,
+        }
+    }
+
     pub fn units_at(&'a self, pos: &MapPos) -> Vec<&'a Unit> {
         let mut units = Vec::new();
         for (_, unit) in &self.units {
@@ -79,10 +97,19 @@ impl<'a> InternalState {
         for (_, unit) in self.units.iter_mut() {
             if unit.player_id == *player_id {
                 let unit_type = db.unit_type(&unit.type_id);
-                unit.move_points = unit_type.move_points;
-                unit.attack_points = unit_type.attack_points;
-                if let Some(ref mut reactive_attack_points) = unit.reactive_attack_points {
-                    *reactive_attack_points = unit_type.reactive_attack_points;
+                unit.move_points = if unit.is_immobilized {
+                    0
+                } else {
+                    unit_type.move_points
+                };
+                let (attack_points, reactive_attack_points) = if unit.is_weapon_disabled {
+                    (0, 0)
+                } else {
+                    (unit_type.attack_points, unit_type.reactive_attack_points)
+                };
+                unit.attack_points = attack_points;
+                if let Some(ref mut points) = unit.reactive_attack_points {
+                    *points = reactive_attack_points;
                 }
                 unit.morale += 10;
             }
@@ -114,27 +141,47 @@ impl<'a> InternalState {
             },
             count: unit_type.count,
             morale: 100,
+            is_immobilized: false,
+            is_weapon_disabled: false,
         });
     }
 
     pub fn apply_event(&mut self, db: &Db, event: &CoreEvent) {
         match event {
-            &CoreEvent::Move{ref unit_id, ref path, ref mode} => {
+            &CoreEvent::Move{ref unit_id, ref path, ..} => {
                 let pos = path.destination().clone();
                 let unit = self.units.get_mut(unit_id)
                     .expect("Bad move unit id");
                 unit.pos = pos;
-                assert!(unit.move_points > 0);
-                if let &MoveMode::Fast = mode {
-                    unit.move_points -= path.total_cost().n;
-                } else {
-                    unit.move_points -= path.total_cost().n * 2;
-                }
+                // path costs already depend on move mode, see `Pathfinder`
+                let cost = path.total_cost().n;
+                // zero cost moves are forced (for example, retreats)
+                assert!(cost == 0 || unit.move_points > 0);
+                unit.move_points -= cost;
                 assert!(unit.move_points >= 0);
+                if let Some(order) = self.move_orders.get_mut(unit_id) {
+                    order.update(path);
+                }
+            },
+            &CoreEvent::UndoMove{ref unit_id, ref path, ..} => {
+                let unit = self.units.get_mut(unit_id)
+                    .expect("Bad undo move unit id");
+                unit.pos = path.nodes()[0].pos.clone();
+                unit.move_points += path.total_cost().n;
+                if let Some(order) = self.move_orders.get_mut(unit_id) {
+                    order.undo_update();
+                }
             },
             &CoreEvent::EndTurn{ref new_id, ref old_id} => {
                 self.refresh_units(db, new_id);
                 self.convert_ap(old_id);
+                let finished_ids: Vec<UnitId> = self.move_orders.iter()
+                    .filter(|&(_, order)| order.is_finished())
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                for id in &finished_ids {
+                    self.move_orders.remove(id);
+                }
             },
             &CoreEvent::CreateUnit {
                 ref unit_id,
@@ -151,6 +198,7 @@ impl<'a> InternalState {
                 ref killed,
                 ref suppression,
                 ref remove_move_points,
+                ref damage,
             } => {
                 {
                     let unit = self.units.get_mut(defender_id)
@@ -160,7 +208,25 @@ impl<'a> InternalState {
                     if *remove_move_points {
                         unit.move_points = 0;
                     }
+                    match damage {
+                        &Some(VehicleDamage::Immobilized) => {
+                            unit.is_immobilized = true;
+                            unit.move_points = 0;
+                        },
+                        &Some(VehicleDamage::WeaponDisabled) => {
+                            unit.is_weapon_disabled = true;
+                            unit.attack_points = 0;
+                            if let Some(ref mut points) = unit.reactive_attack_points {
+                                *points = 0;
+                            }
+                        },
+                        &Some(VehicleDamage::CrewShaken)
+                            | &Some(VehicleDamage::Destroyed)
+                            | &None => {},
+                    }
                 }
+                // unit under fire stops following its order
+                self.move_orders.remove(defender_id);
                 let count = self.units[defender_id].count.clone();
                 if count <= 0 {
                     assert!(self.units.get(defender_id).is_some());
@@ -176,6 +242,11 @@ impl<'a> InternalState {
                             assert!(unit.attack_points >= 1);
                             unit.attack_points -= 1;
                         },
+                        &FireMode::Melee => {
+                            // there is one event for every assaulted unit
+                            unit.attack_points = 0;
+                        },
+                        &FireMode::Defensive => {},
                         &FireMode::Reactive => {
                             if let Some(ref mut reactive_attack_points)
                                 = unit.reactive_attack_points
@@ -192,12 +263,45 @@ impl<'a> InternalState {
                 ref pos,
                 ref type_id,
                 ref player_id,
+                is_immobilized,
+                is_weapon_disabled,
             } => {
                 self.add_unit(db, unit_id, pos, type_id, player_id, InfoLevel::Partial);
+                let unit = self.units.get_mut(unit_id).expect("Can`t find shown unit");
+                unit.is_immobilized = is_immobilized;
+                unit.is_weapon_disabled = is_weapon_disabled;
+                if is_immobilized {
+                    unit.move_points = 0;
+                }
+                if is_weapon_disabled {
+                    unit.attack_points = 0;
+                }
             },
             &CoreEvent::HideUnit{ref unit_id} => {
                 assert!(self.units.get(unit_id).is_some());
                 self.units.remove(unit_id);
+                self.move_orders.remove(unit_id);
+            },
+            &CoreEvent::RepairUnit{ref unit_id, ref damage} => {
+                let unit = self.units.get_mut(unit_id)
+                    .expect("Can`t find repaired unit");
+                match damage {
+                    &VehicleDamage::Immobilized => unit.is_immobilized = false,
+                    &VehicleDamage::WeaponDisabled => unit.is_weapon_disabled = false,
+                    _ => panic!("Can`t repair this damage"),
+                }
+            },
+            &CoreEvent::SetReinforcementPoints{ref player_id, ref points} => {
+                assert!(*points >= 0);
+                self.reinforcement_points.insert(player_id.clone(), *points);
+            },
+            &CoreEvent::SetMoveOrder{ref unit_id, ref waypoints} => {
+                if waypoints.is_empty() {
+                    self.move_orders.remove(unit_id);
+                } else {
+                    let order = MoveOrder::new(waypoints.clone());
+                    self.move_orders.insert(unit_id.clone(), order);
+                }
             },
         }
     }
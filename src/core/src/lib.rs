@@ -4,9 +4,11 @@ extern crate num;
 extern crate cgmath;
 extern crate rand;
 extern crate common;
+extern crate crypto;
 
 pub mod geom;
 pub mod map;
+pub mod fov;
 pub mod command;
 pub mod db;
 pub mod unit;
@@ -14,11 +16,19 @@ pub mod dir;
 pub mod game_state;
 pub mod core;
 pub mod pathfinder;
+pub mod move_order;
+pub mod group_move;
+pub mod scenario;
+pub mod options;
+pub mod protocol;
+pub mod server;
+pub mod client;
+pub mod turn_packet;
 
 mod ai;
 mod player;
-mod fov;
 mod fow;
+mod consistency;
 mod internal_state;
 
 // vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
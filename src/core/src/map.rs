@@ -12,6 +12,7 @@ pub enum Terrain {
     Trees,
 }
 
+#[derive(Clone)]
 pub struct Map<T> {
     tiles: Vec<T>,
     size: Size2,
@@ -0,0 +1,194 @@
+// See LICENSE file for copyright and license details.
+
+//! Orders to move unit to a far destination: every turn unit
+//! passes as much of the route as its move points allow.
+//! Orders are given with `Command::SetMoveOrder` and are stored
+//! per unit in `InternalState`, so they survive saved games.
+
+use std::mem;
+use common::types::{MapPos};
+use command::{MoveMode};
+use db::{Db};
+use game_state::{GameState};
+use pathfinder::{Pathfinder, MapPath};
+use unit::{Unit};
+
+#[derive(Clone)]
+pub struct MoveOrder {
+    /// Points that are not reached yet, the last one is destination
+    waypoints: Vec<MapPos>,
+    destination: MapPos,
+    /// Waypoints passed by the last move, they are restored if it is undone
+    last_passed: Vec<MapPos>,
+}
+
+impl MoveOrder {
+    pub fn new(waypoints: Vec<MapPos>) -> MoveOrder {
+        let destination = waypoints.last()
+            .expect("Move order has no waypoints").clone();
+        MoveOrder {
+            waypoints: waypoints,
+            destination: destination,
+            last_passed: Vec::new(),
+        }
+    }
+
+    pub fn waypoints(&self) -> &Vec<MapPos> {
+        &self.waypoints
+    }
+
+    pub fn destination(&self) -> &MapPos {
+        &self.destination
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.waypoints.is_empty()
+    }
+
+    /// Removes waypoints that unit has passed. Called for every
+    /// resolved move of the unit, so moves that were stopped
+    /// by enemies don`t skip waypoints.
+    pub fn update(&mut self, path_passed: &MapPath) {
+        self.last_passed.clear();
+        for node in path_passed.nodes() {
+            if self.waypoints.first() == Some(&node.pos) {
+                self.last_passed.push(self.waypoints.remove(0));
+            }
+        }
+    }
+
+    /// Restores waypoints removed by the last `update`
+    pub fn undo_update(&mut self) {
+        let mut waypoints = mem::replace(&mut self.last_passed, Vec::new());
+        waypoints.extend(self.waypoints.iter().cloned());
+        self.waypoints = waypoints;
+    }
+
+    /// Whole route through all remaining waypoints with marked turns.
+    /// None if some waypoint is unreachable.
+    pub fn route(
+        &self,
+        db: &Db,
+        state: &GameState,
+        pathfinder: &mut Pathfinder,
+        unit: &Unit,
+    ) -> Option<MapPath> {
+        let mut route: Option<MapPath> = None;
+        for waypoint in &self.waypoints {
+            let path = match route {
+                Some(ref route) => pathfinder.find_path_from(
+                    db, state, unit, route.destination(), waypoint, &MoveMode::Fast),
+                None => pathfinder.find_path(
+                    db, state, unit, waypoint, &MoveMode::Fast),
+            };
+            let path = match path {
+                Some(path) => path,
+                None => return None,
+            };
+            route = match route {
+                Some(mut route) => {
+                    route.join(&path);
+                    Some(route)
+                },
+                None => Some(path),
+            };
+        }
+        let mut route = match route {
+            Some(route) => route,
+            None => return None,
+        };
+        let max_move_points = db.unit_type(&unit.type_id).move_points;
+        route.mark_turns(unit.move_points, max_move_points);
+        Some(route)
+    }
+
+    /// Part of the route that unit can pass in this turn
+    pub fn next_path(
+        &self,
+        db: &Db,
+        state: &GameState,
+        pathfinder: &mut Pathfinder,
+        unit: &Unit,
+    ) -> Option<MapPath> {
+        let path = match self.route(db, state, pathfinder, unit) {
+            Some(route) => route.first_turn(),
+            None => return None,
+        };
+        if path.nodes().len() < 2 {
+            return None;
+        }
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Vector2};
+    use common::types::{ZInt, PlayerId, UnitId, MapPos, Size2};
+    use core::{CoreEvent};
+    use db::{Db};
+    use game_state::{GameState};
+    use pathfinder::{Pathfinder, MapPath};
+    use super::{MoveOrder};
+
+    fn pos(x: ZInt, y: ZInt) -> MapPos {
+        MapPos{v: Vector2{x: x, y: y}}
+    }
+
+    fn state_with_soldier(db: &Db, map_size: &Size2) -> GameState {
+        let mut state = GameState::new(map_size, &PlayerId{id: 0});
+        state.disable_fow();
+        state.apply_event(db, &CoreEvent::CreateUnit {
+            unit_id: UnitId{id: 0},
+            pos: pos(0, 0),
+            type_id: db.unit_type_id("soldier"),
+            player_id: PlayerId{id: 0},
+        });
+        state
+    }
+
+    #[test]
+    fn route_passes_waypoints_and_marks_turns() {
+        let db = Db::new();
+        let map_size = Size2{w: 10, h: 10};
+        let state = state_with_soldier(&db, &map_size);
+        let unit = &state.units()[&UnitId{id: 0}];
+        let mut pathfinder = Pathfinder::new(&map_size);
+        let order = MoveOrder::new(vec![pos(3, 0), pos(9, 0)]);
+        let route = order.route(&db, &state, &mut pathfinder, unit)
+            .expect("No route");
+        assert_eq!(*route.destination(), pos(9, 0));
+        assert!(route.nodes().iter().any(|node| node.pos == pos(3, 0)));
+        let move_points = db.unit_type(&unit.type_id).move_points;
+        assert_eq!(route.turn_ends().len() as ZInt,
+            (route.total_cost().n - 1) / move_points);
+        let path = order.next_path(&db, &state, &mut pathfinder, unit)
+            .expect("No path");
+        assert!(path.total_cost().n <= unit.move_points);
+        assert!(route.nodes().len() > path.nodes().len());
+    }
+
+    #[test]
+    fn waypoints_are_removed_only_when_unit_passes_them() {
+        let db = Db::new();
+        let map_size = Size2{w: 10, h: 10};
+        let state = state_with_soldier(&db, &map_size);
+        let unit = &state.units()[&UnitId{id: 0}];
+        let mut pathfinder = Pathfinder::new(&map_size);
+        let mut order = MoveOrder::new(vec![pos(2, 0), pos(9, 0)]);
+        let path = order.next_path(&db, &state, &mut pathfinder, unit)
+            .expect("No path");
+        assert_eq!(order.waypoints().len(), 2);
+        // move was stopped by reaction fire before the first waypoint
+        let stopped = MapPath::new(path.nodes()[.. 2].to_vec());
+        order.update(&stopped);
+        assert_eq!(order.waypoints().len(), 2);
+        order.update(&path);
+        assert_eq!(*order.waypoints(), vec![pos(9, 0)]);
+        order.undo_update();
+        assert_eq!(*order.waypoints(), vec![pos(2, 0), pos(9, 0)]);
+        assert!(!order.is_finished());
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
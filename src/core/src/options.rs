@@ -0,0 +1,130 @@
+// See LICENSE file for copyright and license details.
+
+use common::types::{ZInt, PlayerId, Size2};
+use scenario::{Scenario};
+use game_state::{DEFAULT_LAST_SEEN_TURNS};
+
+#[derive(Clone, PartialEq)]
+pub enum Controller {
+    Human,
+    Ai,
+}
+
+/// Optional game rules
+#[derive(Clone, PartialEq)]
+pub struct Rules {
+    pub reaction_fire: bool,
+    pub vehicle_damage: bool,
+    pub ambushes: bool,
+    /// Units must stop when they enter tiles adjacent to enemies
+    pub zoc: bool,
+    /// Unit sees a tile only if it could be seen from that tile too
+    pub symmetric_los: bool,
+}
+
+impl Rules {
+    pub fn new() -> Rules {
+        Rules {
+            reaction_fire: true,
+            vehicle_damage: true,
+            ambushes: true,
+            zoc: true,
+            symmetric_los: false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CoreOptions {
+    pub scenario: Scenario,
+    /// Player with id N is controlled by players[N]
+    pub players: Vec<Controller>,
+    /// Random seed is chosen by `Core::new` if None
+    pub seed: Option<usize>,
+    pub fow: bool,
+    pub rules: Rules,
+    /// Keep all unfiltered events in `Core::event_log`
+    pub event_log: bool,
+    /// Debug mode: after every command compare state built from
+    /// filtered events with what player must see,
+    /// divergences are collected in `Core::consistency_report`
+    pub check_consistency: bool,
+    /// How many own turns players and AIs remember hidden enemies
+    pub last_seen_turns: ZInt,
+}
+
+impl CoreOptions {
+    pub fn new() -> CoreOptions {
+        CoreOptions {
+            scenario: Scenario::new(),
+            players: vec![Controller::Human, Controller::Human],
+            seed: None,
+            fow: true,
+            rules: Rules::new(),
+            event_log: false,
+            check_consistency: false,
+            last_seen_turns: DEFAULT_LAST_SEEN_TURNS,
+        }
+    }
+
+    pub fn with_scenario(mut self, scenario: Scenario) -> CoreOptions {
+        self.scenario = scenario;
+        self
+    }
+
+    /// Replaces scenario with one generated for new map size
+    pub fn with_map_size(mut self, map_size: Size2) -> CoreOptions {
+        let players_count = self.players.len() as ZInt;
+        self.scenario = Scenario::generate(&map_size, players_count);
+        self
+    }
+
+    /// Replaces scenario with one generated for new players count
+    pub fn with_players(mut self, players: Vec<Controller>) -> CoreOptions {
+        let players_count = players.len() as ZInt;
+        self.scenario = Scenario::generate(&self.scenario.map_size, players_count);
+        self.players = players;
+        self
+    }
+
+    pub fn with_controller(mut self, player_id: &PlayerId, controller: Controller)
+        -> CoreOptions
+    {
+        let index = player_id.id as usize;
+        assert!(index < self.players.len(), "Bad player id: {}", player_id.id);
+        self.players[index] = controller;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: usize) -> CoreOptions {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn with_fow(mut self, fow: bool) -> CoreOptions {
+        self.fow = fow;
+        self
+    }
+
+    pub fn with_event_log(mut self, event_log: bool) -> CoreOptions {
+        self.event_log = event_log;
+        self
+    }
+
+    pub fn with_check_consistency(mut self, check_consistency: bool) -> CoreOptions {
+        self.check_consistency = check_consistency;
+        self
+    }
+
+    pub fn with_rules(mut self, rules: Rules) -> CoreOptions {
+        self.rules = rules;
+        self
+    }
+
+    pub fn with_last_seen_turns(mut self, turns: ZInt) -> CoreOptions {
+        self.last_seen_turns = turns;
+        self
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
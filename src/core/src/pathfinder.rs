@@ -1,11 +1,14 @@
 // See LICENSE file for copyright and license details.
 
-use common::types::{ZInt, MapPos, Size2};
+use std::cmp::{Ordering};
+use std::collections::{BTreeMap, BinaryHeap};
+use common::types::{ZInt, PlayerId, UnitId, MapPos, Size2};
 use db::{Db};
-use unit::{Unit, UnitClass};
-use map::{Map, Terrain};
+use unit::{Unit, UnitType, UnitClass};
+use map::{Map, Terrain, distance};
 use game_state::{GameState};
 use dir::{Dir};
+use command::{MoveMode};
 
 #[derive(Clone)]
 pub struct PathNode {
@@ -20,11 +23,13 @@ pub struct MoveCost{pub n: ZInt}
 #[derive(Clone)]
 pub struct MapPath {
     nodes: Vec<PathNode>,
+    /// Indexes of nodes where unit runs out of move points, see `mark_turns`
+    turn_ends: Vec<usize>,
 }
 
 impl MapPath {
     pub fn new(nodes: Vec<PathNode>) -> MapPath {
-        MapPath{nodes: nodes}
+        MapPath{nodes: nodes, turn_ends: Vec::new()}
     }
 
     // pub fn len(&self) -> ZInt {
@@ -47,11 +52,60 @@ impl MapPath {
             .expect("Path has no nodes")
             .cost
     }
+
+    /// Appends path that starts at destination of this path
+    pub fn join(&mut self, other: &MapPath) {
+        assert!(*self.destination() == other.nodes[0].pos);
+        let cost = self.total_cost().n;
+        for node in &other.nodes[1 ..] {
+            self.nodes.push(PathNode {
+                cost: MoveCost{n: cost + node.cost.n},
+                pos: node.pos.clone(),
+            });
+        }
+        self.turn_ends.clear();
+    }
+
+    /// Splits long path by turns: unit has `move_points` in current turn
+    /// and `max_move_points` in every next turn. Marks are lost
+    /// after the first step that costs more than `max_move_points`.
+    pub fn mark_turns(&mut self, move_points: ZInt, max_move_points: ZInt) {
+        self.turn_ends.clear();
+        let mut move_points = move_points;
+        let mut i = 1;
+        while i < self.nodes.len() {
+            let cost = self.nodes[i].cost.n - self.nodes[i - 1].cost.n;
+            if cost <= move_points {
+                move_points -= cost;
+                i += 1;
+            } else if cost <= max_move_points {
+                self.turn_ends.push(i - 1);
+                move_points = max_move_points;
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn turn_ends(&self) -> &Vec<usize> {
+        &self.turn_ends
+    }
+
+    /// Part of path that can be passed in current turn
+    pub fn first_turn(&self) -> MapPath {
+        let len = match self.turn_ends.first() {
+            Some(&i) => i + 1,
+            None => self.nodes.len(),
+        };
+        MapPath::new(self.nodes[.. len].to_vec())
+    }
 }
 
 #[derive(Clone)]
 pub struct Tile {
     cost: MoveCost,
+    /// Cost plus penalties, paths with lower weight are preferred
+    weight: ZInt,
     parent: Option<Dir>,
 }
 
@@ -62,40 +116,139 @@ impl Tile {
 
 const MAX_COST: MoveCost = MoveCost{n: 30000};
 
-fn tile_cost(db: &Db, state: &GameState, unit: &Unit, pos: &MapPos)
-    -> MoveCost
-{
-    let unit_type = db.unit_type(&unit.type_id);
-    let tile = state.map().tile(pos);
+/// Extra weight of tile for every known enemy that can fire at it
+const THREAT_PENALTY: ZInt = 4;
+
+#[derive(Clone, PartialEq)]
+pub enum PathCostMode {
+    /// Cheapest path in move points
+    Shortest,
+    /// Avoids tiles covered by known enemies` weapons
+    Safe,
+}
+
+/// Move points that unit of `unit_type` spends to enter tile
+/// of `terrain` in `mode`. This is the only place that knows
+/// how move mode changes costs, `Core` checks paths with it too.
+pub fn move_cost(unit_type: &UnitType, terrain: &Terrain, mode: &MoveMode) -> MoveCost {
     let n = match unit_type.class {
-        UnitClass::Infantry => match tile {
+        UnitClass::Infantry => match terrain {
             &Terrain::Plain => 1,
             &Terrain::Trees => 2,
         },
-        UnitClass::Vehicle => match tile {
+        UnitClass::Vehicle => match terrain {
             &Terrain::Plain => 1,
             &Terrain::Trees => 5,
         },
     };
-    MoveCost{n: n}
+    // hunting unit moves carefully
+    let factor = match *mode {
+        MoveMode::Fast => 1,
+        MoveMode::Hunt => 2,
+    };
+    MoveCost{n: n * factor}
+}
+
+/// Move points that `unit` spends to enter tile at `pos` in `mode`.
+/// Unexplored tiles cost as plain: their real terrain must not leak
+/// to the client, `Core` applies real costs when the unit moves.
+fn tile_cost(db: &Db, state: &GameState, unit: &Unit, pos: &MapPos, mode: &MoveMode)
+    -> MoveCost
+{
+    let terrain = state.known_terrain(pos).unwrap_or(&Terrain::Plain);
+    move_cost(db.unit_type(&unit.type_id), terrain, mode)
+}
+
+/// Is tile adjacent to some enemy of `player_id` that has zone of control
+pub fn is_zoc(
+    db: &Db,
+    units: &BTreeMap<UnitId, Unit>,
+    player_id: &PlayerId,
+    pos: &MapPos,
+) -> bool {
+    for (_, unit) in units {
+        if unit.player_id != *player_id
+            && db.unit_type(&unit.type_id).zoc
+            && distance(&unit.pos, pos) == 1
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Tile in priority queue, the cheapest one is popped first
+struct QueueNode {
+    /// Cost of path to `pos` plus heuristic estimate of the rest
+    priority: ZInt,
+    pos: MapPos,
+}
+
+impl PartialEq for QueueNode {
+    fn eq(&self, other: &QueueNode) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl Eq for QueueNode {}
+
+impl PartialOrd for QueueNode {
+    fn partial_cmp(&self, other: &QueueNode) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueueNode {
+    // `BinaryHeap` is a max-heap
+    fn cmp(&self, other: &QueueNode) -> Ordering {
+        other.priority.cmp(&self.priority)
+    }
+}
+
+/// Lower bound of cost from `pos` to `destination`: every step costs at least 1
+fn heuristic(pos: &MapPos, destination: Option<&MapPos>) -> ZInt {
+    match destination {
+        Some(destination) => distance(pos, destination),
+        None => 0,
+    }
 }
 
 pub struct Pathfinder {
-    queue: Vec<MapPos>,
+    queue: BinaryHeap<QueueNode>,
     map: Map<Tile>,
+    is_zoc_enabled: bool,
+    cost_mode: PathCostMode,
+    /// Filled only during search in `PathCostMode::Safe`
+    threat_map: Option<Map<ZInt>>,
 }
 
 impl Pathfinder {
     pub fn new(map_size: &Size2) -> Pathfinder {
         Pathfinder {
-            queue: Vec::new(),
+            queue: BinaryHeap::new(),
             map: Map::new(map_size, Tile {
                 cost: MoveCost{n: 0},
+                weight: 0,
                 parent: None,
             }),
+            is_zoc_enabled: true,
+            cost_mode: PathCostMode::Shortest,
+            threat_map: None,
         }
     }
 
+    pub fn disable_zoc(&mut self) {
+        self.is_zoc_enabled = false;
+    }
+
+    pub fn cost_mode(&self) -> &PathCostMode {
+        &self.cost_mode
+    }
+
+    pub fn set_cost_mode(&mut self, mode: PathCostMode) {
+        self.cost_mode = mode;
+    }
+
     pub fn get_map(&self) -> &Map<Tile> {
         &self.map
     }
@@ -106,18 +259,30 @@ impl Pathfinder {
         state: &GameState,
         unit: &Unit,
         original_pos: &MapPos,
-        neighbour_pos: &MapPos
+        neighbour_pos: &MapPos,
+        destination: Option<&MapPos>,
+        mode: &MoveMode,
     ) {
         let old_cost = self.map.tile(original_pos).cost.clone();
-        let tile_cost = tile_cost(db, state, unit, neighbour_pos);
+        let old_weight = self.map.tile(original_pos).weight;
+        let tile_cost = tile_cost(db, state, unit, neighbour_pos, mode);
+        let penalty = match self.threat_map {
+            Some(ref threat_map) => *threat_map.tile(neighbour_pos) * THREAT_PENALTY,
+            None => 0,
+        };
         let tile = self.map.tile_mut(neighbour_pos);
         let new_cost = MoveCost{n: old_cost.n + tile_cost.n};
+        let new_weight = old_weight + tile_cost.n + penalty;
         let units_count = state.units_at(neighbour_pos).len();
-        if tile.cost.n > new_cost.n && units_count == 0 {
+        if tile.weight > new_weight && units_count == 0 {
             tile.cost = new_cost;
+            tile.weight = new_weight;
             tile.parent = Some(Dir::get_dir_from_to(
                 neighbour_pos, original_pos));
-            self.queue.push(neighbour_pos.clone());
+            self.queue.push(QueueNode {
+                priority: new_weight + heuristic(neighbour_pos, destination),
+                pos: neighbour_pos.clone(),
+            });
         }
     }
 
@@ -125,6 +290,8 @@ impl Pathfinder {
         for pos in self.map.get_iter() {
             let tile = self.map.tile_mut(&pos);
             tile.cost = MAX_COST;
+            // penalties may make weight of reachable tile bigger than MAX_COST
+            tile.weight = ZInt::max_value();
             tile.parent = None;
         }
     }
@@ -135,6 +302,8 @@ impl Pathfinder {
         state: &GameState,
         unit: &Unit,
         pos: MapPos,
+        destination: Option<&MapPos>,
+        mode: &MoveMode,
     ) {
         assert!(self.map.is_inboard(&pos));
         for i in 0 .. 6 {
@@ -142,26 +311,95 @@ impl Pathfinder {
             let neighbour_pos = Dir::get_neighbour_pos(&pos, &dir);
             if self.map.is_inboard(&neighbour_pos) {
                 self.process_neighbour_pos(
-                    db, state, unit, &pos, &neighbour_pos);
+                    db, state, unit, &pos, &neighbour_pos, destination, mode);
             }
         }
     }
 
-    fn push_start_pos_to_queue(&mut self, start_pos: MapPos) {
+    fn push_start_pos_to_queue(&mut self, start_pos: MapPos, destination: Option<&MapPos>) {
         let start_tile = self.map.tile_mut(&start_pos);
         start_tile.cost = MoveCost{n: 0};
+        start_tile.weight = 0;
         start_tile.parent = None;
-        self.queue.push(start_pos);
+        self.queue.push(QueueNode {
+            priority: heuristic(&start_pos, destination),
+            pos: start_pos,
+        });
     }
 
-    pub fn fill_map(&mut self, db: &Db, state: &GameState, unit: &Unit) {
-        assert!(self.queue.len() == 0);
+    /// Dijkstra if `destination` is None, A* otherwise
+    fn search(
+        &mut self,
+        db: &Db,
+        state: &GameState,
+        unit: &Unit,
+        start_pos: &MapPos,
+        destination: Option<&MapPos>,
+        mode: &MoveMode,
+    ) {
+        self.queue.clear();
         self.clean_map();
-        self.push_start_pos_to_queue(unit.pos.clone());
-        while self.queue.len() != 0 {
-            let pos = self.queue.remove(0);
-            self.try_to_push_neighbours(db, state, unit, pos);
+        self.threat_map = match self.cost_mode {
+            PathCostMode::Shortest => None,
+            PathCostMode::Safe => Some(state.threat_map(db, unit)),
+        };
+        self.push_start_pos_to_queue(start_pos.clone(), destination);
+        while let Some(node) = self.queue.pop() {
+            let pos = node.pos;
+            let cost = self.map.tile(&pos).cost.n;
+            // tile was already processed with lower weight
+            if node.priority > self.map.tile(&pos).weight + heuristic(&pos, destination) {
+                continue;
+            }
+            if destination == Some(&pos) {
+                break;
+            }
+            // unit can enter zone of control of known enemy, but must stop there
+            let is_start_pos = cost == 0;
+            if !is_start_pos && self.is_zoc_enabled
+                && is_zoc(db, state.units(), &unit.player_id, &pos)
+            {
+                continue;
+            }
+            self.try_to_push_neighbours(db, state, unit, pos, destination, mode);
         }
+        self.queue.clear();
+        self.threat_map = None;
+    }
+
+    /// Finds cheapest paths from unit`s position to all tiles of map,
+    /// costs of tiles are in move points of `mode`
+    pub fn fill_map(&mut self, db: &Db, state: &GameState, unit: &Unit, mode: &MoveMode) {
+        self.search(db, state, unit, &unit.pos, None, mode);
+    }
+
+    /// Finds cheapest path from unit`s position to one tile.
+    /// Faster than `fill_map`, but after this call
+    /// `get_path` is valid only for `destination`.
+    pub fn find_path(
+        &mut self,
+        db: &Db,
+        state: &GameState,
+        unit: &Unit,
+        destination: &MapPos,
+        mode: &MoveMode,
+    ) -> Option<MapPath> {
+        self.search(db, state, unit, &unit.pos, Some(destination), mode);
+        self.get_path(destination)
+    }
+
+    /// Like `find_path`, but starts from `from` instead of unit`s position
+    pub fn find_path_from(
+        &mut self,
+        db: &Db,
+        state: &GameState,
+        unit: &Unit,
+        from: &MapPos,
+        destination: &MapPos,
+        mode: &MoveMode,
+    ) -> Option<MapPath> {
+        self.search(db, state, unit, from, Some(destination), mode);
+        self.get_path(destination)
     }
 
     /*
@@ -190,7 +428,189 @@ impl Pathfinder {
             path.push(PathNode{cost: cost, pos: pos.clone()});
         }
         path.reverse();
-        Some(MapPath{nodes: path})
+        Some(MapPath::new(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Vector2};
+    use common::types::{ZInt, PlayerId, UnitId, MapPos, Size2};
+    use core::{CoreEvent};
+    use db::{Db};
+    use dir::{Dir, dirs};
+    use map::{Map};
+    use unit::{Unit};
+    use game_state::{GameState};
+    use command::{MoveMode};
+    use super::{
+        Pathfinder,
+        PathCostMode,
+        MapPath,
+        PathNode,
+        MoveCost,
+        MAX_COST,
+        is_zoc,
+        tile_cost,
+    };
+
+    fn pos(x: ZInt, y: ZInt) -> MapPos {
+        MapPos{v: Vector2{x: x, y: y}}
+    }
+
+    /// Units are soldiers, unit with id 0 is the one who moves
+    fn state_with_units(db: &Db, map_size: &Size2, units: &[(MapPos, ZInt)]) -> GameState {
+        let mut state = GameState::new(map_size, &PlayerId{id: 0});
+        state.disable_fow();
+        for (id, &(ref unit_pos, player_id)) in units.iter().enumerate() {
+            state.apply_event(db, &CoreEvent::CreateUnit {
+                unit_id: UnitId{id: id as ZInt},
+                pos: unit_pos.clone(),
+                type_id: db.unit_type_id("soldier"),
+                player_id: PlayerId{id: player_id},
+            });
+        }
+        state
+    }
+
+    /// Old algorithm: FIFO queue that relaxes tiles again and again
+    fn fifo_costs(db: &Db, state: &GameState, unit: &Unit) -> Map<ZInt> {
+        let mut costs = Map::new(state.map().size(), MAX_COST.n);
+        *costs.tile_mut(&unit.pos) = 0;
+        let mut queue = vec![unit.pos.clone()];
+        while !queue.is_empty() {
+            let pos = queue.remove(0);
+            for dir in dirs() {
+                let neighbour_pos = Dir::get_neighbour_pos(&pos, &dir);
+                if !costs.is_inboard(&neighbour_pos)
+                    || !state.units_at(&neighbour_pos).is_empty()
+                {
+                    continue;
+                }
+                let cost = *costs.tile(&pos)
+                    + tile_cost(db, state, unit, &neighbour_pos, &MoveMode::Fast).n;
+                if *costs.tile(&neighbour_pos) > cost {
+                    *costs.tile_mut(&neighbour_pos) = cost;
+                    queue.push(neighbour_pos);
+                }
+            }
+        }
+        costs
+    }
+
+    /// Obstacles in every 7th tile
+    fn obstacles(map_size: &Size2) -> Vec<(MapPos, ZInt)> {
+        let mut units = vec![(pos(0, 0), 0)];
+        for y in 0 .. map_size.h {
+            for x in 0 .. map_size.w {
+                if (x * 3 + y * 5) % 7 == 0 && (x, y) != (0, 0) {
+                    units.push((pos(x, y), 0));
+                }
+            }
+        }
+        units
+    }
+
+    #[test]
+    fn path_stops_in_enemy_zoc() {
+        let db = Db::new();
+        let map_size = Size2{w: 10, h: 10};
+        let player_id = PlayerId{id: 0};
+        let state = state_with_units(&db, &map_size, &[(pos(0, 5), 0), (pos(3, 5), 1)]);
+        let unit = &state.units()[&UnitId{id: 0}];
+        let destination = pos(6, 5);
+        let mut pathfinder = Pathfinder::new(&map_size);
+        pathfinder.fill_map(&db, &state, unit, &MoveMode::Fast);
+        let path = pathfinder.get_path(&destination).expect("No path");
+        let nodes = path.nodes();
+        for node in &nodes[1 .. nodes.len() - 1] {
+            assert!(!is_zoc(&db, state.units(), &player_id, &node.pos));
+        }
+        assert!(pathfinder.get_path(&pos(2, 5)).is_some());
+        let mut pathfinder_no_zoc = Pathfinder::new(&map_size);
+        pathfinder_no_zoc.disable_zoc();
+        pathfinder_no_zoc.fill_map(&db, &state, unit, &MoveMode::Fast);
+        let path_no_zoc = pathfinder_no_zoc.get_path(&destination).expect("No path");
+        assert!(path_no_zoc.total_cost().n < path.total_cost().n);
+    }
+
+    #[test]
+    fn dijkstra_and_a_star_match_old_algorithm() {
+        let db = Db::new();
+        let map_size = Size2{w: 20, h: 20};
+        let state = state_with_units(&db, &map_size, &obstacles(&map_size));
+        let unit = &state.units()[&UnitId{id: 0}];
+        let expected = fifo_costs(&db, &state, unit);
+        let mut pathfinder = Pathfinder::new(&map_size);
+        pathfinder.fill_map(&db, &state, unit, &MoveMode::Fast);
+        for tile_pos in expected.get_iter() {
+            assert_eq!(pathfinder.get_map().tile(&tile_pos).cost().n,
+                *expected.tile(&tile_pos));
+        }
+        let mut a_star = Pathfinder::new(&map_size);
+        for tile_pos in expected.get_iter() {
+            let cost = *expected.tile(&tile_pos);
+            match a_star.find_path(&db, &state, unit, &tile_pos, &MoveMode::Fast) {
+                Some(path) => assert_eq!(path.total_cost().n, cost),
+                None => assert_eq!(cost, MAX_COST.n),
+            }
+        }
+    }
+
+    #[test]
+    fn safe_path_avoids_enemy_fire() {
+        let db = Db::new();
+        let map_size = Size2{w: 12, h: 12};
+        let state = state_with_units(&db, &map_size, &[(pos(0, 6), 0), (pos(6, 9), 1)]);
+        let unit = &state.units()[&UnitId{id: 0}];
+        let threat_map = state.threat_map(&db, unit);
+        let destination = pos(11, 6);
+        let mut pathfinder = Pathfinder::new(&map_size);
+        let shortest = pathfinder
+            .find_path(&db, &state, unit, &destination, &MoveMode::Fast)
+            .expect("No path");
+        pathfinder.set_cost_mode(PathCostMode::Safe);
+        let safe = pathfinder
+            .find_path(&db, &state, unit, &destination, &MoveMode::Fast)
+            .expect("No path");
+        let threats = safe.nodes().iter()
+            .fold(0, |sum, node| sum + *threat_map.tile(&node.pos));
+        assert_eq!(threats, 0);
+        assert!(shortest.total_cost().n <= safe.total_cost().n);
+    }
+
+    #[test]
+    fn hunting_costs_twice_as_much() {
+        let db = Db::new();
+        let map_size = Size2{w: 20, h: 20};
+        let state = state_with_units(&db, &map_size, &obstacles(&map_size));
+        let unit = &state.units()[&UnitId{id: 0}];
+        let mut fast = Pathfinder::new(&map_size);
+        fast.fill_map(&db, &state, unit, &MoveMode::Fast);
+        let mut hunt = Pathfinder::new(&map_size);
+        hunt.fill_map(&db, &state, unit, &MoveMode::Hunt);
+        for tile_pos in state.map().get_iter() {
+            let fast_cost = fast.get_map().tile(&tile_pos).cost().n;
+            let hunt_cost = hunt.get_map().tile(&tile_pos).cost().n;
+            if fast_cost == MAX_COST.n {
+                assert_eq!(hunt_cost, MAX_COST.n);
+            } else {
+                assert_eq!(hunt_cost, fast_cost * 2);
+            }
+        }
+    }
+
+    #[test]
+    fn long_path_is_split_by_turns() {
+        let nodes = (0 .. 10).map(|i| PathNode {
+            cost: MoveCost{n: i},
+            pos: pos(i, 0),
+        }).collect();
+        let mut path = MapPath::new(nodes);
+        path.mark_turns(2, 4);
+        assert_eq!(*path.turn_ends(), vec![2, 6]);
+        assert_eq!(path.first_turn().nodes().len(), 3);
+        assert_eq!(*path.first_turn().destination(), pos(2, 0));
     }
 }
 
@@ -2,6 +2,7 @@
 
 use common::types::{PlayerId};
 
+#[derive(Clone)]
 pub struct Player {
     pub id: PlayerId,
     pub is_ai: bool,
@@ -0,0 +1,719 @@
+// See LICENSE file for copyright and license details.
+
+//! Text protocol of network game: one message per line,
+//! fields are separated by spaces.
+
+use std::str::{SplitWhitespace};
+use cgmath::{Vector2};
+use common::types::{ZInt, PlayerId, UnitId, MapPos, Size2};
+use core::{CoreEvent, FireMode};
+use game_state::{Perspective};
+use command::{Command, MoveMode};
+use pathfinder::{MapPath, PathNode, MoveCost};
+use unit::{UnitTypeId, VehicleDamage};
+
+/// Must be changed on every incompatible change of protocol
+pub const PROTOCOL_VERSION: ZInt = 7;
+
+const SNAPSHOT_SEPARATOR: &'static str = ";";
+
+pub enum Message {
+    /// Client -> server: first message of handshake.
+    /// If `player_id` is None then server assigns any free slot.
+    /// Slot that was already taken needs `token` from its `Welcome`.
+    Join{version: ZInt, player_id: Option<PlayerId>, token: Option<String>},
+    /// Client -> server: first message of observer`s handshake
+    Observe{version: ZInt},
+    /// Server -> client: successful answer to `Join` or `Observe`.
+    /// `player_id` and `token` are None for observers.
+    Welcome {
+        player_id: Option<PlayerId>,
+        /// Secret that is needed to rejoin this slot
+        token: Option<String>,
+        current_player_id: PlayerId,
+        players_count: ZInt,
+        map_size: Size2,
+        fow: bool,
+        /// Client`s fog of war must use the same line of sight
+        symmetric_los: bool,
+    },
+    /// Server -> client: all events of this perspective sent so far.
+    /// Client rebuilds its state by applying them to empty `GameState`.
+    /// Observers receive snapshots of all perspectives.
+    Snapshot{perspective: Perspective, events: Vec<CoreEvent>},
+    /// Client -> server
+    Command{command: Command},
+    /// Server -> client: fog-filtered (or full, for observers) event
+    Event{perspective: Perspective, event: CoreEvent},
+    /// Server -> client: rejected join or command
+    Error{text: String},
+}
+
+struct Writer {
+    parts: Vec<String>,
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer{parts: Vec::new()}
+    }
+
+    fn word(&mut self, s: &str) {
+        self.parts.push(s.to_string());
+    }
+
+    fn int(&mut self, n: ZInt) {
+        self.parts.push(format!("{}", n));
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.int(if b { 1 } else { 0 });
+    }
+
+    fn token(&mut self, token: &Option<String>) {
+        match *token {
+            Some(ref token) => self.word(token),
+            None => self.word("-"),
+        }
+    }
+
+    fn perspective(&mut self, perspective: &Perspective) {
+        match *perspective {
+            Perspective::Full => self.word("full"),
+            Perspective::Player(ref player_id) => self.int(player_id.id),
+        }
+    }
+
+    fn pos(&mut self, pos: &MapPos) {
+        self.int(pos.v.x);
+        self.int(pos.v.y);
+    }
+
+    fn positions(&mut self, positions: &Vec<MapPos>) {
+        self.int(positions.len() as ZInt);
+        for pos in positions {
+            self.pos(pos);
+        }
+    }
+
+    fn mode(&mut self, mode: &MoveMode) {
+        self.word(match *mode {
+            MoveMode::Fast => "fast",
+            MoveMode::Hunt => "hunt",
+        });
+    }
+
+    fn path(&mut self, path: &MapPath) {
+        self.int(path.nodes().len() as ZInt);
+        for node in path.nodes() {
+            self.pos(&node.pos);
+            self.int(node.cost.n);
+        }
+    }
+
+    fn fire_mode(&mut self, mode: &FireMode) {
+        self.word(match *mode {
+            FireMode::Active => "active",
+            FireMode::Reactive => "reactive",
+            FireMode::Defensive => "defensive",
+            FireMode::Melee => "melee",
+        });
+    }
+
+    fn damage(&mut self, damage: &VehicleDamage) {
+        self.word(match *damage {
+            VehicleDamage::Immobilized => "immobilized",
+            VehicleDamage::WeaponDisabled => "weapon_disabled",
+            VehicleDamage::CrewShaken => "crew_shaken",
+            VehicleDamage::Destroyed => "destroyed",
+        });
+    }
+
+    fn command(&mut self, command: &Command) {
+        match *command {
+            Command::Move{ref unit_id, ref path, ref mode} => {
+                self.word("move");
+                self.int(unit_id.id);
+                self.mode(mode);
+                self.path(path);
+            },
+            Command::MoveGroup{ref moves, ref mode} => {
+                self.word("move_group");
+                self.mode(mode);
+                self.int(moves.len() as ZInt);
+                for &(ref unit_id, ref path) in moves {
+                    self.int(unit_id.id);
+                    self.path(path);
+                }
+            },
+            Command::EndTurn => self.word("end_turn"),
+            Command::CreateUnit{ref pos, ref type_id} => {
+                self.word("create_unit");
+                self.pos(pos);
+                self.int(type_id.id);
+            },
+            Command::AttackUnit{ref attacker_id, ref defender_id} => {
+                self.word("attack_unit");
+                self.int(attacker_id.id);
+                self.int(defender_id.id);
+            },
+            Command::Assault{ref attacker_id, ref target_pos} => {
+                self.word("assault");
+                self.int(attacker_id.id);
+                self.pos(target_pos);
+            },
+            Command::Undo => self.word("undo"),
+            Command::SetMoveOrder{ref unit_id, ref waypoints} => {
+                self.word("set_move_order");
+                self.int(unit_id.id);
+                self.positions(waypoints);
+            },
+        }
+    }
+
+    fn event(&mut self, event: &CoreEvent) {
+        match *event {
+            CoreEvent::Move{ref unit_id, ref path, ref mode} => {
+                self.word("move");
+                self.int(unit_id.id);
+                self.mode(mode);
+                self.path(path);
+            },
+            CoreEvent::UndoMove{ref unit_id, ref path, ref mode} => {
+                self.word("undo_move");
+                self.int(unit_id.id);
+                self.mode(mode);
+                self.path(path);
+            },
+            CoreEvent::EndTurn{ref old_id, ref new_id} => {
+                self.word("end_turn");
+                self.int(old_id.id);
+                self.int(new_id.id);
+            },
+            CoreEvent::CreateUnit{ref unit_id, ref pos, ref type_id, ref player_id} => {
+                self.word("create_unit");
+                self.int(unit_id.id);
+                self.pos(pos);
+                self.int(type_id.id);
+                self.int(player_id.id);
+            },
+            CoreEvent::AttackUnit {
+                ref attacker_id,
+                ref defender_id,
+                ref mode,
+                killed,
+                suppression,
+                remove_move_points,
+                ref damage,
+            } => {
+                self.word("attack_unit");
+                self.int(match *attacker_id {
+                    Some(ref id) => id.id,
+                    None => -1,
+                });
+                self.int(defender_id.id);
+                self.fire_mode(mode);
+                self.int(killed);
+                self.int(suppression);
+                self.bool(remove_move_points);
+                match *damage {
+                    Some(ref damage) => self.damage(damage),
+                    None => self.word("none"),
+                }
+            },
+            CoreEvent::ShowUnit {
+                ref unit_id,
+                ref pos,
+                ref type_id,
+                ref player_id,
+                is_immobilized,
+                is_weapon_disabled,
+            } => {
+                self.word("show_unit");
+                self.int(unit_id.id);
+                self.pos(pos);
+                self.int(type_id.id);
+                self.int(player_id.id);
+                self.bool(is_immobilized);
+                self.bool(is_weapon_disabled);
+            },
+            CoreEvent::HideUnit{ref unit_id} => {
+                self.word("hide_unit");
+                self.int(unit_id.id);
+            },
+            CoreEvent::RepairUnit{ref unit_id, ref damage} => {
+                self.word("repair_unit");
+                self.int(unit_id.id);
+                self.damage(damage);
+            },
+            CoreEvent::SetReinforcementPoints{ref player_id, points} => {
+                self.word("set_reinforcement_points");
+                self.int(player_id.id);
+                self.int(points);
+            },
+            CoreEvent::SetMoveOrder{ref unit_id, ref waypoints} => {
+                self.word("set_move_order");
+                self.int(unit_id.id);
+                self.positions(waypoints);
+            },
+        }
+    }
+
+    fn finish(self) -> String {
+        self.parts.join(" ")
+    }
+}
+
+struct Reader<'a> {
+    words: SplitWhitespace<'a>,
+}
+
+impl<'a> Reader<'a> {
+    fn new(s: &'a str) -> Reader<'a> {
+        Reader{words: s.split_whitespace()}
+    }
+
+    fn word(&mut self) -> Result<&'a str, String> {
+        self.words.next().ok_or("Unexpected end of message".to_string())
+    }
+
+    fn int(&mut self) -> Result<ZInt, String> {
+        let word = try!(self.word());
+        word.parse().map_err(|_| format!("Bad number: {}", word))
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        Ok(try!(self.int()) != 0)
+    }
+
+    fn token(&mut self) -> Result<Option<String>, String> {
+        match try!(self.word()) {
+            "-" => Ok(None),
+            word => Ok(Some(word.to_string())),
+        }
+    }
+
+    fn perspective(&mut self) -> Result<Perspective, String> {
+        match try!(self.word()) {
+            "full" => Ok(Perspective::Full),
+            word => {
+                let id = try!(word.parse()
+                    .map_err(|_| format!("Bad perspective: {}", word)));
+                Ok(Perspective::Player(PlayerId{id: id}))
+            },
+        }
+    }
+
+    fn pos(&mut self) -> Result<MapPos, String> {
+        let x = try!(self.int());
+        let y = try!(self.int());
+        Ok(MapPos{v: Vector2{x: x, y: y}})
+    }
+
+    fn positions(&mut self) -> Result<Vec<MapPos>, String> {
+        let len = try!(self.int());
+        let mut positions = Vec::new();
+        for _ in 0 .. len {
+            positions.push(try!(self.pos()));
+        }
+        Ok(positions)
+    }
+
+    fn mode(&mut self) -> Result<MoveMode, String> {
+        match try!(self.word()) {
+            "fast" => Ok(MoveMode::Fast),
+            "hunt" => Ok(MoveMode::Hunt),
+            word => Err(format!("Bad move mode: {}", word)),
+        }
+    }
+
+    fn path(&mut self) -> Result<MapPath, String> {
+        let len = try!(self.int());
+        let mut nodes = Vec::new();
+        for _ in 0 .. len {
+            let pos = try!(self.pos());
+            let cost = try!(self.int());
+            nodes.push(PathNode{cost: MoveCost{n: cost}, pos: pos});
+        }
+        if nodes.is_empty() {
+            return Err("Empty path".to_string());
+        }
+        Ok(MapPath::new(nodes))
+    }
+
+    fn fire_mode(&mut self) -> Result<FireMode, String> {
+        match try!(self.word()) {
+            "active" => Ok(FireMode::Active),
+            "reactive" => Ok(FireMode::Reactive),
+            "defensive" => Ok(FireMode::Defensive),
+            "melee" => Ok(FireMode::Melee),
+            word => Err(format!("Bad fire mode: {}", word)),
+        }
+    }
+
+    fn damage(&mut self) -> Result<Option<VehicleDamage>, String> {
+        match try!(self.word()) {
+            "immobilized" => Ok(Some(VehicleDamage::Immobilized)),
+            "weapon_disabled" => Ok(Some(VehicleDamage::WeaponDisabled)),
+            "crew_shaken" => Ok(Some(VehicleDamage::CrewShaken)),
+            "destroyed" => Ok(Some(VehicleDamage::Destroyed)),
+            "none" => Ok(None),
+            word => Err(format!("Bad vehicle damage: {}", word)),
+        }
+    }
+
+    fn unit_id(&mut self) -> Result<UnitId, String> {
+        Ok(UnitId{id: try!(self.int())})
+    }
+
+    fn player_id(&mut self) -> Result<PlayerId, String> {
+        Ok(PlayerId{id: try!(self.int())})
+    }
+
+    fn type_id(&mut self) -> Result<UnitTypeId, String> {
+        Ok(UnitTypeId{id: try!(self.int())})
+    }
+
+    fn command(&mut self) -> Result<Command, String> {
+        let command = match try!(self.word()) {
+            "move" => Command::Move {
+                unit_id: try!(self.unit_id()),
+                mode: try!(self.mode()),
+                path: try!(self.path()),
+            },
+            "move_group" => {
+                let mode = try!(self.mode());
+                let count = try!(self.int());
+                let mut moves = Vec::new();
+                for _ in 0 .. count {
+                    let unit_id = try!(self.unit_id());
+                    let path = try!(self.path());
+                    moves.push((unit_id, path));
+                }
+                Command::MoveGroup{moves: moves, mode: mode}
+            },
+            "end_turn" => Command::EndTurn,
+            "create_unit" => Command::CreateUnit {
+                pos: try!(self.pos()),
+                type_id: try!(self.type_id()),
+            },
+            "attack_unit" => Command::AttackUnit {
+                attacker_id: try!(self.unit_id()),
+                defender_id: try!(self.unit_id()),
+            },
+            "assault" => Command::Assault {
+                attacker_id: try!(self.unit_id()),
+                target_pos: try!(self.pos()),
+            },
+            "undo" => Command::Undo,
+            "set_move_order" => Command::SetMoveOrder {
+                unit_id: try!(self.unit_id()),
+                waypoints: try!(self.positions()),
+            },
+            word => return Err(format!("Bad command: {}", word)),
+        };
+        Ok(command)
+    }
+
+    fn event(&mut self) -> Result<CoreEvent, String> {
+        let event = match try!(self.word()) {
+            "move" => CoreEvent::Move {
+                unit_id: try!(self.unit_id()),
+                mode: try!(self.mode()),
+                path: try!(self.path()),
+            },
+            "undo_move" => CoreEvent::UndoMove {
+                unit_id: try!(self.unit_id()),
+                mode: try!(self.mode()),
+                path: try!(self.path()),
+            },
+            "end_turn" => CoreEvent::EndTurn {
+                old_id: try!(self.player_id()),
+                new_id: try!(self.player_id()),
+            },
+            "create_unit" => CoreEvent::CreateUnit {
+                unit_id: try!(self.unit_id()),
+                pos: try!(self.pos()),
+                type_id: try!(self.type_id()),
+                player_id: try!(self.player_id()),
+            },
+            "attack_unit" => {
+                let attacker_id = try!(self.int());
+                CoreEvent::AttackUnit {
+                    attacker_id: if attacker_id < 0 {
+                        None
+                    } else {
+                        Some(UnitId{id: attacker_id})
+                    },
+                    defender_id: try!(self.unit_id()),
+                    mode: try!(self.fire_mode()),
+                    killed: try!(self.int()),
+                    suppression: try!(self.int()),
+                    remove_move_points: try!(self.bool()),
+                    damage: try!(self.damage()),
+                }
+            },
+            "show_unit" => CoreEvent::ShowUnit {
+                unit_id: try!(self.unit_id()),
+                pos: try!(self.pos()),
+                type_id: try!(self.type_id()),
+                player_id: try!(self.player_id()),
+                is_immobilized: try!(self.bool()),
+                is_weapon_disabled: try!(self.bool()),
+            },
+            "hide_unit" => CoreEvent::HideUnit {
+                unit_id: try!(self.unit_id()),
+            },
+            "repair_unit" => {
+                let unit_id = try!(self.unit_id());
+                match try!(self.damage()) {
+                    Some(damage) => CoreEvent::RepairUnit {
+                        unit_id: unit_id,
+                        damage: damage,
+                    },
+                    None => return Err("Repair of nothing".to_string()),
+                }
+            },
+            "set_reinforcement_points" => CoreEvent::SetReinforcementPoints {
+                player_id: try!(self.player_id()),
+                points: try!(self.int()),
+            },
+            "set_move_order" => CoreEvent::SetMoveOrder {
+                unit_id: try!(self.unit_id()),
+                waypoints: try!(self.positions()),
+            },
+            word => return Err(format!("Bad event: {}", word)),
+        };
+        Ok(event)
+    }
+
+    fn rest(&mut self) -> String {
+        let words: Vec<&str> = self.words.by_ref().collect();
+        words.join(" ")
+    }
+}
+
+pub fn encode_event(event: &CoreEvent) -> String {
+    let mut writer = Writer::new();
+    writer.event(event);
+    writer.finish()
+}
+
+pub fn decode_event(s: &str) -> Result<CoreEvent, String> {
+    Reader::new(s).event()
+}
+
+pub fn encode_command(command: &Command) -> String {
+    let mut writer = Writer::new();
+    writer.command(command);
+    writer.finish()
+}
+
+pub fn decode_command(s: &str) -> Result<Command, String> {
+    Reader::new(s).command()
+}
+
+/// Encodes message as a single line without trailing newline
+pub fn encode(message: &Message) -> String {
+    let mut writer = Writer::new();
+    match *message {
+        Message::Join{version, ref player_id, ref token} => {
+            writer.word("join");
+            writer.int(version);
+            match *player_id {
+                Some(ref player_id) => writer.int(player_id.id),
+                None => writer.word("any"),
+            }
+            writer.token(token);
+        },
+        Message::Observe{version} => {
+            writer.word("observe");
+            writer.int(version);
+        },
+        Message::Welcome {
+            ref player_id,
+            ref token,
+            ref current_player_id,
+            players_count,
+            ref map_size,
+            fow,
+            symmetric_los,
+        } => {
+            writer.word("welcome");
+            match *player_id {
+                Some(ref player_id) => writer.int(player_id.id),
+                None => writer.word("observer"),
+            }
+            writer.token(token);
+            writer.int(current_player_id.id);
+            writer.int(players_count);
+            writer.int(map_size.w);
+            writer.int(map_size.h);
+            writer.bool(fow);
+            writer.bool(symmetric_los);
+        },
+        Message::Snapshot{ref perspective, ref events} => {
+            writer.word("snapshot");
+            writer.perspective(perspective);
+            let events: Vec<String> = events.iter().map(encode_event).collect();
+            writer.word(&events.join(&format!(" {} ", SNAPSHOT_SEPARATOR)));
+        },
+        Message::Command{ref command} => {
+            writer.word("command");
+            writer.command(command);
+        },
+        Message::Event{ref perspective, ref event} => {
+            writer.word("event");
+            writer.perspective(perspective);
+            writer.event(event);
+        },
+        Message::Error{ref text} => {
+            writer.word("error");
+            writer.word(text);
+        },
+    }
+    writer.finish()
+}
+
+pub fn decode(s: &str) -> Result<Message, String> {
+    let mut reader = Reader::new(s);
+    let message = match try!(reader.word()) {
+        "join" => {
+            let version = try!(reader.int());
+            let player_id = match try!(reader.word()) {
+                "any" => None,
+                word => Some(PlayerId{id: try!(word.parse()
+                    .map_err(|_| format!("Bad player id: {}", word)))}),
+            };
+            Message::Join {
+                version: version,
+                player_id: player_id,
+                token: try!(reader.token()),
+            }
+        },
+        "observe" => Message::Observe{version: try!(reader.int())},
+        "welcome" => Message::Welcome {
+            player_id: match try!(reader.word()) {
+                "observer" => None,
+                word => Some(PlayerId{id: try!(word.parse()
+                    .map_err(|_| format!("Bad player id: {}", word)))}),
+            },
+            token: try!(reader.token()),
+            current_player_id: try!(reader.player_id()),
+            players_count: try!(reader.int()),
+            map_size: Size2{w: try!(reader.int()), h: try!(reader.int())},
+            fow: try!(reader.bool()),
+            symmetric_los: try!(reader.bool()),
+        },
+        "snapshot" => {
+            let perspective = try!(reader.perspective());
+            let rest = reader.rest();
+            let mut events = Vec::new();
+            for part in rest.split(SNAPSHOT_SEPARATOR) {
+                if part.trim().is_empty() {
+                    continue;
+                }
+                events.push(try!(decode_event(part)));
+            }
+            Message::Snapshot{perspective: perspective, events: events}
+        },
+        "command" => Message::Command{command: try!(reader.command())},
+        "event" => Message::Event {
+            perspective: try!(reader.perspective()),
+            event: try!(reader.event()),
+        },
+        "error" => Message::Error{text: reader.rest()},
+        word => return Err(format!("Bad message: {}", word)),
+    };
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Vector2};
+    use common::types::{UnitId, PlayerId, MapPos, Size2};
+    use core::{CoreEvent, FireMode};
+    use command::{Command, MoveMode};
+    use pathfinder::{MapPath, PathNode, MoveCost};
+    use unit::{VehicleDamage, UnitTypeId};
+    use game_state::{Perspective};
+    use super::{encode, decode, Message};
+
+    fn check_round_trip(message: Message) {
+        let line = encode(&message);
+        let decoded = decode(&line).ok().expect("Can`t decode message");
+        assert_eq!(line, encode(&decoded));
+    }
+
+    #[test]
+    fn messages_survive_round_trip() {
+        let path = MapPath::new(vec![
+            PathNode{cost: MoveCost{n: 0}, pos: MapPos{v: Vector2{x: 0, y: 1}}},
+            PathNode{cost: MoveCost{n: 2}, pos: MapPos{v: Vector2{x: 1, y: 1}}},
+        ]);
+        check_round_trip(Message::Join{version: 1, player_id: None, token: None});
+        check_round_trip(Message::Join {
+            version: 1,
+            player_id: Some(PlayerId{id: 1}),
+            token: Some("abc".to_string()),
+        });
+        check_round_trip(Message::Welcome {
+            player_id: Some(PlayerId{id: 1}),
+            token: Some("abc".to_string()),
+            current_player_id: PlayerId{id: 0},
+            players_count: 2,
+            map_size: Size2{w: 10, h: 8},
+            fow: true,
+            symmetric_los: true,
+        });
+        check_round_trip(Message::Command{command: Command::Move {
+            unit_id: UnitId{id: 3},
+            path: path.clone(),
+            mode: MoveMode::Hunt,
+        }});
+        check_round_trip(Message::Command{command: Command::MoveGroup {
+            moves: vec![(UnitId{id: 3}, path.clone()), (UnitId{id: 4}, path.clone())],
+            mode: MoveMode::Fast,
+        }});
+        let waypoints = vec![
+            MapPos{v: Vector2{x: 2, y: 3}},
+            MapPos{v: Vector2{x: 7, y: 1}},
+        ];
+        check_round_trip(Message::Command{command: Command::SetMoveOrder {
+            unit_id: UnitId{id: 3},
+            waypoints: waypoints.clone(),
+        }});
+        let events = vec![
+            CoreEvent::AttackUnit {
+                attacker_id: None,
+                defender_id: UnitId{id: 2},
+                mode: FireMode::Reactive,
+                killed: 1,
+                suppression: 20,
+                remove_move_points: true,
+                damage: Some(VehicleDamage::Immobilized),
+            },
+            CoreEvent::ShowUnit {
+                unit_id: UnitId{id: 4},
+                pos: MapPos{v: Vector2{x: 3, y: 2}},
+                type_id: UnitTypeId{id: 1},
+                player_id: PlayerId{id: 0},
+                is_immobilized: false,
+                is_weapon_disabled: true,
+            },
+            CoreEvent::EndTurn{old_id: PlayerId{id: 0}, new_id: PlayerId{id: 1}},
+            CoreEvent::SetMoveOrder{unit_id: UnitId{id: 3}, waypoints: waypoints},
+        ];
+        check_round_trip(Message::Snapshot {
+            perspective: Perspective::Player(PlayerId{id: 1}),
+            events: events,
+        });
+        check_round_trip(Message::Event {
+            perspective: Perspective::Full,
+            event: CoreEvent::HideUnit{unit_id: UnitId{id: 5}},
+        });
+        check_round_trip(Message::Error{text: "Not your turn".to_string()});
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -0,0 +1,161 @@
+// See LICENSE file for copyright and license details.
+
+use std::cmp;
+use cgmath::{Vector2};
+use common::types::{ZInt, PlayerId, MapPos, Size2};
+
+/// Map tile that gives its holder reinforcement points every turn
+#[derive(Clone)]
+pub struct Objective {
+    pub pos: MapPos,
+    pub income: ZInt,
+}
+
+/// Deployment zone or depot: tiles where player can place reinforcements
+#[derive(Clone)]
+pub struct Zone {
+    pub player_id: PlayerId,
+    pub positions: Vec<MapPos>,
+}
+
+/// Unit that is placed on map at start of game
+#[derive(Clone)]
+pub struct ScenarioUnit {
+    pub pos: MapPos,
+    pub type_name: String,
+    pub player_id: PlayerId,
+}
+
+#[derive(Clone)]
+pub struct Scenario {
+    pub map_size: Size2,
+    pub units: Vec<ScenarioUnit>,
+    pub objectives: Vec<Objective>,
+    pub zones: Vec<Zone>,
+    pub initial_reinforcement_points: ZInt,
+}
+
+/// Initial force of every player, from top to bottom
+const FORCE: [&'static str; 6] = ["tank", "soldier", "scout", "soldier", "tank", "tank"];
+
+fn column(x: ZInt, h: ZInt) -> Vec<MapPos> {
+    let mut positions = Vec::new();
+    for y in 0 .. h {
+        positions.push(MapPos{v: Vector2{x: x, y: y}});
+    }
+    positions
+}
+
+/// Players` columns are spread evenly from left to right map edge
+fn deployment_column(map_size: &Size2, players_count: ZInt, player_id: ZInt) -> ZInt {
+    if players_count == 1 {
+        0
+    } else {
+        player_id * (map_size.w - 1) / (players_count - 1)
+    }
+}
+
+fn get_units(map_size: &Size2, players_count: ZInt) -> Vec<ScenarioUnit> {
+    // small maps get only a part of the force
+    let count = cmp::min(FORCE.len() as ZInt, map_size.h);
+    let mut units = Vec::new();
+    for player_id in 0 .. players_count {
+        let x = deployment_column(map_size, players_count, player_id);
+        for i in 0 .. count {
+            units.push(ScenarioUnit {
+                pos: MapPos{v: Vector2{x: x, y: (i + 1) * map_size.h / (count + 1)}},
+                type_name: FORCE[i as usize].to_string(),
+                player_id: PlayerId{id: player_id},
+            });
+        }
+    }
+    units
+}
+
+fn get_objectives(map_size: &Size2) -> Vec<Objective> {
+    let x = map_size.w / 2;
+    let y = map_size.h / 8;
+    vec![
+        Objective{pos: MapPos{v: Vector2{x: x, y: y}}, income: 5},
+        Objective{pos: MapPos{v: Vector2{x: x, y: map_size.h - 1 - y}}, income: 5},
+        Objective {
+            pos: MapPos{v: Vector2{x: (map_size.w - 1) / 2, y: map_size.h / 2}},
+            income: 10,
+        },
+    ]
+}
+
+impl Scenario {
+    // TODO: read from scenario.json
+    pub fn new() -> Scenario {
+        Scenario::generate(&Size2{w: 10, h: 8}, 2)
+    }
+
+    pub fn min_map_size(players_count: ZInt) -> Size2 {
+        Size2{w: players_count, h: 3}
+    }
+
+    /// Every player gets a column of units on its own deployment zone,
+    /// objectives are placed in the middle of the map
+    pub fn generate(map_size: &Size2, players_count: ZInt) -> Scenario {
+        let min_size = Scenario::min_map_size(players_count);
+        assert!(map_size.w >= min_size.w && map_size.h >= min_size.h,
+            "Map of {} players must be at least {}x{}",
+            players_count, min_size.w, min_size.h);
+        let zones = (0 .. players_count).map(|player_id| Zone {
+            player_id: PlayerId{id: player_id},
+            positions: column(
+                deployment_column(map_size, players_count, player_id),
+                map_size.h,
+            ),
+        }).collect();
+        Scenario {
+            map_size: map_size.clone(),
+            units: get_units(map_size, players_count),
+            objectives: get_objectives(map_size),
+            zones: zones,
+            initial_reinforcement_points: 10,
+        }
+    }
+
+    pub fn is_in_zone(&self, player_id: &PlayerId, pos: &MapPos) -> bool {
+        for zone in &self.zones {
+            if zone.player_id == *player_id && zone.positions.contains(pos) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::types::{Size2};
+    use map::{Map, Terrain};
+    use super::{Scenario};
+
+    #[test]
+    fn generated_scenarios_fit_map() {
+        for players_count in 1 .. 4 {
+            for w in players_count .. 12 {
+                for h in 3 .. 12 {
+                    let size = Size2{w: w, h: h};
+                    let scenario = Scenario::generate(&size, players_count);
+                    let map = Map::new(&size, Terrain::Plain);
+                    let mut positions = Vec::new();
+                    for unit in &scenario.units {
+                        assert!(map.is_inboard(&unit.pos));
+                        assert!(!positions.contains(&unit.pos));
+                        positions.push(unit.pos.clone());
+                    }
+                    for objective in &scenario.objectives {
+                        assert!(map.is_inboard(&objective.pos));
+                    }
+                    assert_eq!(scenario.zones.len(), players_count as usize);
+                }
+            }
+        }
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
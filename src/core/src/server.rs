@@ -0,0 +1,516 @@
+// See LICENSE file for copyright and license details.
+
+//! Network game server: owns authoritative `Core` and sends
+//! to every client only its player`s fog-filtered events.
+//! Observers receive unfiltered events and events of all human players.
+
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::collections::{HashMap};
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use rand::{thread_rng, Rng};
+use common::types::{ZInt, PlayerId};
+use core::{Core, CoreEvent};
+use game_state::{Perspective};
+use command::{Command, CommandError};
+use options::{CoreOptions, Controller};
+use protocol::{Message, PROTOCOL_VERSION, encode, decode};
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct ConnectionId{id: ZInt}
+
+enum Incoming {
+    Connected(ConnectionId, TcpStream),
+    Line(ConnectionId, String),
+    Disconnected(ConnectionId),
+}
+
+#[derive(Clone)]
+enum Role {
+    /// Handshake is not finished yet
+    Unknown,
+    Player(PlayerId),
+    Observer,
+}
+
+/// Length of token that is needed to rejoin already claimed slot
+const SLOT_TOKEN_LEN: usize = 16;
+
+struct Connection {
+    /// Lines for connection`s writer thread, so slow client
+    /// can`t block the server
+    sender: Sender<String>,
+    role: Role,
+}
+
+impl Connection {
+    fn is_player(&self, player_id: &PlayerId) -> bool {
+        match self.role {
+            Role::Player(ref id) => *id == *player_id,
+            _ => false,
+        }
+    }
+
+    fn is_observer(&self) -> bool {
+        match self.role {
+            Role::Observer => true,
+            _ => false,
+        }
+    }
+}
+
+fn read_lines(id: ConnectionId, stream: TcpStream, sender: Sender<Incoming>) {
+    for line in BufReader::new(stream).lines() {
+        match line {
+            Ok(line) => {
+                let _ = sender.send(Incoming::Line(id.clone(), line));
+            },
+            Err(_) => break,
+        }
+    }
+    let _ = sender.send(Incoming::Disconnected(id));
+}
+
+fn write_lines(mut stream: TcpStream, receiver: Receiver<String>) {
+    // ends when connection is dropped by server
+    for line in receiver.iter() {
+        // broken connection will be reported by its reading thread
+        if stream.write_all(line.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+fn accept_connections(listener: TcpListener, sender: Sender<Incoming>) {
+    for (n, stream) in listener.incoming().enumerate() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let id = ConnectionId{id: n as ZInt};
+        let writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => continue,
+        };
+        let _ = sender.send(Incoming::Connected(id.clone(), writer));
+        let sender = sender.clone();
+        thread::spawn(move || read_lines(id, stream, sender));
+    }
+}
+
+pub struct Server {
+    core: Core,
+    local_addr: SocketAddr,
+    receiver: Receiver<Incoming>,
+    connections: HashMap<ConnectionId, Connection>,
+    /// All events that were sent to player. Replayed on (re)connection.
+    histories: HashMap<PlayerId, Vec<CoreEvent>>,
+    /// Tokens of slots that were taken by some client at least once
+    slot_tokens: HashMap<PlayerId, String>,
+    /// Number of `Core::event_log` events that were sent to observers
+    full_events_count: usize,
+    /// Observers see everything, so by default they can join
+    /// only after the game is over
+    allow_observers: bool,
+}
+
+impl Server {
+    pub fn new(options: CoreOptions, address: &str) -> io::Result<Server> {
+        let listener = try!(TcpListener::bind(address));
+        let local_addr = try!(listener.local_addr());
+        let (sender, receiver) = channel();
+        thread::spawn(move || accept_connections(listener, sender));
+        let mut server = Server {
+            core: Core::new(options.with_event_log(true)),
+            local_addr: local_addr,
+            receiver: receiver,
+            connections: HashMap::new(),
+            histories: HashMap::new(),
+            slot_tokens: HashMap::new(),
+            full_events_count: 0,
+            allow_observers: false,
+        };
+        server.flush_events();
+        Ok(server)
+    }
+
+    /// Useful when server was bound to port 0
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.local_addr
+    }
+
+    /// Let observers join during the game
+    pub fn allow_observers(&mut self) {
+        self.allow_observers = true;
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            let incoming = self.receiver.recv()
+                .ok().expect("server: Connections listener died");
+            self.handle_incoming(incoming);
+        }
+    }
+
+    fn human_player_ids(&self) -> Vec<PlayerId> {
+        let mut ids = Vec::new();
+        for (id, controller) in self.core.options().players.iter().enumerate() {
+            if *controller == Controller::Human {
+                ids.push(PlayerId{id: id as ZInt});
+            }
+        }
+        ids
+    }
+
+    fn is_slot_connected(&self, player_id: &PlayerId) -> bool {
+        self.connections.values()
+            .any(|connection| connection.is_player(player_id))
+    }
+
+    fn role(&self, id: &ConnectionId) -> Role {
+        match self.connections.get(id) {
+            Some(connection) => connection.role.clone(),
+            None => Role::Unknown,
+        }
+    }
+
+    fn send(&mut self, id: &ConnectionId, message: &Message) {
+        if let Some(connection) = self.connections.get_mut(id) {
+            let line = format!("{}\n", encode(message));
+            let _ = connection.sender.send(line);
+        }
+    }
+
+    fn send_error(&mut self, id: &ConnectionId, text: &str) {
+        self.send(id, &Message::Error{text: text.to_string()});
+    }
+
+    fn handle_incoming(&mut self, incoming: Incoming) {
+        match incoming {
+            Incoming::Connected(id, stream) => {
+                let (sender, receiver) = channel();
+                thread::spawn(move || write_lines(stream, receiver));
+                self.connections.insert(id, Connection {
+                    sender: sender,
+                    role: Role::Unknown,
+                });
+            },
+            Incoming::Line(id, line) => {
+                match decode(&line) {
+                    Ok(message) => self.handle_message(&id, message),
+                    Err(err) => self.send_error(&id, &err),
+                }
+            },
+            Incoming::Disconnected(id) => {
+                self.connections.remove(&id);
+            },
+        }
+    }
+
+    fn handle_message(&mut self, id: &ConnectionId, message: Message) {
+        match message {
+            Message::Join{version, player_id, token} => {
+                self.handle_join(id, version, player_id, token);
+            },
+            Message::Observe{version} => {
+                self.handle_observe(id, version);
+            },
+            Message::Command{command} => {
+                self.handle_command(id, command);
+            },
+            _ => self.send_error(id, "Unexpected message"),
+        }
+    }
+
+    /// Claimed slot can be taken again only with its token
+    fn get_free_slot(&self, requested: Option<PlayerId>, token: Option<String>)
+        -> Result<PlayerId, String>
+    {
+        let human_ids = self.human_player_ids();
+        match requested {
+            Some(player_id) => {
+                if !human_ids.contains(&player_id) {
+                    Err(format!("Slot {} is not available", player_id.id))
+                } else if self.is_slot_connected(&player_id) {
+                    Err(format!("Slot {} is busy", player_id.id))
+                } else if let Some(slot_token) = self.slot_tokens.get(&player_id) {
+                    if token.as_ref() == Some(slot_token) {
+                        Ok(player_id)
+                    } else {
+                        Err(format!("Wrong token for slot {}", player_id.id))
+                    }
+                } else {
+                    Ok(player_id)
+                }
+            },
+            None => {
+                for player_id in human_ids {
+                    if !self.slot_tokens.contains_key(&player_id) {
+                        return Ok(player_id);
+                    }
+                }
+                Err("No free slots".to_string())
+            },
+        }
+    }
+
+    /// Checks protocol version and that connection has no role yet
+    fn check_handshake(&mut self, id: &ConnectionId, version: ZInt) -> bool {
+        if version != PROTOCOL_VERSION {
+            let text = format!("Protocol version mismatch: server {}, client {}",
+                PROTOCOL_VERSION, version);
+            self.send_error(id, &text);
+            return false;
+        }
+        if let Role::Unknown = self.role(id) {
+            true
+        } else {
+            self.send_error(id, "Already joined");
+            false
+        }
+    }
+
+    fn welcome(&self, player_id: Option<PlayerId>) -> Message {
+        let token = player_id.as_ref()
+            .map(|player_id| self.slot_tokens[player_id].clone());
+        Message::Welcome {
+            player_id: player_id,
+            token: token,
+            current_player_id: self.core.player_id().clone(),
+            players_count: self.core.options().players.len() as ZInt,
+            map_size: self.core.map_size().clone(),
+            fow: self.core.options().fow,
+            symmetric_los: self.core.options().rules.symmetric_los,
+        }
+    }
+
+    fn history(&self, player_id: &PlayerId) -> Vec<CoreEvent> {
+        match self.histories.get(player_id) {
+            Some(events) => events.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    fn handle_observe(&mut self, id: &ConnectionId, version: ZInt) {
+        if !self.check_handshake(id, version) {
+            return;
+        }
+        if !self.allow_observers && self.core.winner().is_none() {
+            self.send_error(id, "Observers are not allowed until the game is over");
+            return;
+        }
+        if let Some(connection) = self.connections.get_mut(id) {
+            connection.role = Role::Observer;
+        }
+        let welcome = self.welcome(None);
+        self.send(id, &welcome);
+        let events = self.core.event_log()[.. self.full_events_count].to_vec();
+        self.send(id, &Message::Snapshot{perspective: Perspective::Full, events: events});
+        for player_id in self.human_player_ids() {
+            let events = self.history(&player_id);
+            let perspective = Perspective::Player(player_id);
+            self.send(id, &Message::Snapshot{perspective: perspective, events: events});
+        }
+    }
+
+    fn handle_join(
+        &mut self,
+        id: &ConnectionId,
+        version: ZInt,
+        requested: Option<PlayerId>,
+        token: Option<String>,
+    ) {
+        if !self.check_handshake(id, version) {
+            return;
+        }
+        let player_id = match self.get_free_slot(requested, token) {
+            Ok(player_id) => player_id,
+            Err(text) => {
+                self.send_error(id, &text);
+                return;
+            },
+        };
+        if !self.slot_tokens.contains_key(&player_id) {
+            let token = thread_rng().gen_ascii_chars().take(SLOT_TOKEN_LEN).collect();
+            self.slot_tokens.insert(player_id.clone(), token);
+        }
+        if let Some(connection) = self.connections.get_mut(id) {
+            connection.role = Role::Player(player_id.clone());
+        }
+        let welcome = self.welcome(Some(player_id.clone()));
+        self.send(id, &welcome);
+        let events = self.history(&player_id);
+        let perspective = Perspective::Player(player_id);
+        self.send(id, &Message::Snapshot{perspective: perspective, events: events});
+    }
+
+    fn handle_command(&mut self, id: &ConnectionId, command: Command) {
+        let player_id = match self.role(id) {
+            Role::Player(player_id) => player_id,
+            Role::Observer => {
+                self.send_error(id, CommandError::ObserverCanNotCommand.description());
+                return;
+            },
+            Role::Unknown => {
+                self.send_error(id, "Join first");
+                return;
+            },
+        };
+        if player_id != *self.core.player_id() {
+            self.send_error(id, CommandError::NotYourTurn.description());
+            return;
+        }
+        if let Err(err) = self.core.check_command(&command) {
+            self.send_error(id, err.description());
+            return;
+        }
+        self.core.do_command(command);
+        self.flush_events();
+    }
+
+    fn send_event(&mut self, perspective: &Perspective, event: &CoreEvent) {
+        let connection_ids: Vec<ConnectionId> = self.connections.iter()
+            .filter(|&(_, c)| c.is_observer() || match *perspective {
+                Perspective::Player(ref player_id) => c.is_player(player_id),
+                Perspective::Full => false,
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        let message = Message::Event {
+            perspective: perspective.clone(),
+            event: event.clone(),
+        };
+        for id in &connection_ids {
+            self.send(id, &message);
+        }
+    }
+
+    /// Sends new events of every human player to its client and observers
+    fn flush_events(&mut self) {
+        for player_id in self.human_player_ids() {
+            let perspective = Perspective::Player(player_id.clone());
+            while let Some(event) = self.core.get_player_event(&player_id) {
+                self.send_event(&perspective, &event);
+                self.histories.entry(player_id.clone())
+                    .or_insert(Vec::new())
+                    .push(event);
+            }
+        }
+        let events = self.core.event_log()[self.full_events_count ..].to_vec();
+        self.full_events_count += events.len();
+        for event in &events {
+            self.send_event(&Perspective::Full, event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration};
+    use common::types::{PlayerId};
+    use core::{CoreEvent};
+    use command::{Command};
+    use options::{CoreOptions};
+    use game_state::{Perspective};
+    use client::{Client};
+    use super::{Server};
+
+    fn connect(address: &str, player_id: Option<PlayerId>, token: Option<String>)
+        -> Client
+    {
+        // server may still not know that previous connection is closed
+        for _ in 0 .. 100 {
+            let client = Client::connect(address, player_id.clone(), token.clone());
+            if let Ok(client) = client {
+                return client;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Can`t connect to {}", address);
+    }
+
+    fn wait_for_end_turn(client: &mut Client) {
+        for _ in 0 .. 500 {
+            while let Some(event) = client.get_event() {
+                if let CoreEvent::EndTurn{..} = event {
+                    return;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("No EndTurn event");
+    }
+
+    #[test]
+    fn two_clients_on_localhost() {
+        let mut server = Server::new(CoreOptions::new(), "127.0.0.1:0")
+            .ok().expect("Can`t start server");
+        let address = format!("{}", server.local_addr());
+        thread::spawn(move || server.run());
+        let mut client_0 = connect(&address, None, None);
+        let client_1 = connect(&address, None, None);
+        assert!(*client_0.player_id() == Some(PlayerId{id: 0}));
+        assert!(*client_1.player_id() == Some(PlayerId{id: 1}));
+        let token = client_1.token().clone();
+        assert!(token.is_some());
+        drop(client_1);
+        client_0.do_command(Command::EndTurn);
+        let mut client_1 = connect(&address, Some(PlayerId{id: 1}), token);
+        wait_for_end_turn(&mut client_1);
+        assert_eq!(client_1.current_player_id().id, 1);
+    }
+
+    #[test]
+    fn claimed_slot_needs_token() {
+        let mut server = Server::new(CoreOptions::new(), "127.0.0.1:0")
+            .ok().expect("Can`t start server");
+        let address = format!("{}", server.local_addr());
+        thread::spawn(move || server.run());
+        let client_0 = connect(&address, None, None);
+        drop(client_0);
+        // wait until server notices that slot is free
+        thread::sleep(Duration::from_millis(100));
+        let slot = Some(PlayerId{id: 0});
+        assert!(Client::connect(&address, slot.clone(), None).is_err());
+        let token = Some("wrong".to_string());
+        assert!(Client::connect(&address, slot, token).is_err());
+    }
+
+    #[test]
+    fn observers_are_not_allowed_by_default() {
+        let mut server = Server::new(CoreOptions::new(), "127.0.0.1:0")
+            .ok().expect("Can`t start server");
+        let address = format!("{}", server.local_addr());
+        thread::spawn(move || server.run());
+        assert!(Client::observe(&address).is_err());
+    }
+
+    #[test]
+    fn observer_gets_full_events() {
+        let mut server = Server::new(CoreOptions::new(), "127.0.0.1:0")
+            .ok().expect("Can`t start server");
+        server.allow_observers();
+        let address = format!("{}", server.local_addr());
+        thread::spawn(move || server.run());
+        let mut client_0 = connect(&address, None, None);
+        let mut observer = Client::observe(&address)
+            .ok().expect("Can`t observe");
+        assert!(observer.player_id().is_none());
+        assert!(*observer.perspective() == Perspective::Full);
+        assert!(observer.check_command(&Command::EndTurn).is_err());
+        client_0.do_command(Command::EndTurn);
+        wait_for_end_turn(&mut observer);
+        observer.set_perspective(Perspective::Player(PlayerId{id: 0}));
+        wait_for_end_turn(&mut observer);
+        assert!(*observer.perspectives() == vec![
+            Perspective::Full,
+            Perspective::Player(PlayerId{id: 0}),
+            Perspective::Player(PlayerId{id: 1}),
+        ]);
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -0,0 +1,390 @@
+// See LICENSE file for copyright and license details.
+
+//! Play-by-file games: after every turn the whole game is exported
+//! to a text file that is sent to the next player.
+//!
+//! Game is stored as random seed and log of all commands,
+//! the next player`s `Core` replays the commands and checks that
+//! they produce exactly the events that the author`s `Core` saw.
+//! Only events filtered for the recipient are written, so hidden
+//! units aren`t revealed by the file. Commands are needed for the replay
+//! and stay readable: a command of a hidden unit tells where it went.
+//!
+//! Every player signs the command log with a secret word (HMAC-SHA256)
+//! when exporting, so the player can later check that nobody
+//! changed the part of the game they have already played.
+
+use std::fs::{File};
+use std::io::{Read, Write};
+use crypto::hmac::{Hmac};
+use crypto::sha2::{Sha256};
+use crypto::mac::{Mac};
+use crypto::util::{fixed_time_eq};
+use common::types::{ZInt, PlayerId, Size2};
+use core::{CoreEvent};
+use command::{Command};
+use options::{CoreOptions, Controller, Rules};
+use protocol::{encode_event, decode_event, encode_command, decode_command};
+
+/// Must be changed on every incompatible change of file format
+pub const TURN_PACKET_VERSION: ZInt = 6;
+
+/// Proves that seed and first `commands_count` commands weren`t changed.
+/// Only the player who knows the secret can check it.
+#[derive(Clone)]
+pub struct Signature {
+    pub player_id: PlayerId,
+    pub commands_count: usize,
+    /// Hex encoded HMAC-SHA256
+    pub mac: String,
+}
+
+#[derive(Clone)]
+pub struct TurnPacket {
+    /// Number of packets exported in this game, including this one
+    pub turn: ZInt,
+    pub author_id: PlayerId,
+    pub map_size: Size2,
+    pub players: Vec<Controller>,
+    pub fow: bool,
+    pub rules: Rules,
+    pub seed: usize,
+    /// Player who must move next
+    pub recipient_id: PlayerId,
+    /// All events since start of the game filtered for recipient
+    pub events: Vec<CoreEvent>,
+    /// All commands since start of the game with their authors
+    pub commands: Vec<(PlayerId, Command)>,
+    /// Last signature of every player
+    pub signatures: Vec<Signature>,
+}
+
+impl TurnPacket {
+    /// Options of new `Core` that can import this packet
+    pub fn options(&self) -> CoreOptions {
+        CoreOptions::new()
+            .with_map_size(self.map_size.clone())
+            .with_players(self.players.clone())
+            .with_fow(self.fow)
+            .with_rules(self.rules.clone())
+            .with_seed(self.seed)
+            .with_event_log(true)
+    }
+}
+
+pub enum TurnPacketError {
+    BadFormat(String),
+    WrongVersion(ZInt),
+    /// Packet belongs to game with other options
+    WrongGame,
+    /// Packet is older than already imported one or some packet was skipped
+    OutOfOrder{expected: ZInt, got: ZInt},
+    /// Packet`s history doesn`t continue already known history
+    Forked,
+    /// Commands signed by importing player were changed
+    Tampered,
+    /// Command can`t be given in current state of the game
+    BadCommand{index: usize},
+    /// Command that isn`t signed by importing player was given
+    /// by somebody else than packet`s author or ai
+    ForeignCommand{index: usize},
+    /// Replayed commands produce other events than the packet has
+    Mismatch,
+}
+
+impl TurnPacketError {
+    pub fn description(&self) -> String {
+        match *self {
+            TurnPacketError::BadFormat(ref text) => format!("Bad turn file: {}", text),
+            TurnPacketError::WrongVersion(version) => {
+                format!("Unsupported turn file version: {}", version)
+            },
+            TurnPacketError::WrongGame => "Turn file is from other game".to_string(),
+            TurnPacketError::OutOfOrder{expected, got} => {
+                format!("Expected turn {}, got turn {}", expected, got)
+            },
+            TurnPacketError::Forked => {
+                "Turn file doesn`t continue current game".to_string()
+            },
+            TurnPacketError::Tampered => {
+                "Your previous turns were changed".to_string()
+            },
+            TurnPacketError::BadCommand{index} => {
+                format!("Turn file has impossible command #{}", index)
+            },
+            TurnPacketError::ForeignCommand{index} => {
+                format!("Turn file has command #{} of other player", index)
+            },
+            TurnPacketError::Mismatch => {
+                "Turn file events don`t match its commands".to_string()
+            },
+        }
+    }
+}
+
+fn encode_command_line(author_id: &PlayerId, command: &Command) -> String {
+    format!("{} {}", author_id.id, encode_command(command))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    for byte in bytes {
+        s.push_str(&format!("{:02x}", byte));
+    }
+    s
+}
+
+/// Hex encoded HMAC-SHA256 of seed and commands keyed with player`s secret
+pub fn sign(secret: &str, seed: usize, commands: &[(PlayerId, Command)]) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(format!("{}\n", seed).as_bytes());
+    for &(ref author_id, ref command) in commands {
+        hmac.input(encode_command_line(author_id, command).as_bytes());
+        hmac.input(b"\n");
+    }
+    to_hex(hmac.result().code())
+}
+
+/// Comparison that takes the same time for all MACs
+/// of the same length, so MAC can`t be guessed byte by byte
+pub fn is_same_mac(a: &str, b: &str) -> bool {
+    fixed_time_eq(a.as_bytes(), b.as_bytes())
+}
+
+fn controller_to_str(controller: &Controller) -> &'static str {
+    match *controller {
+        Controller::Human => "human",
+        Controller::Ai => "ai",
+    }
+}
+
+fn bool_to_str(b: bool) -> &'static str {
+    if b { "1" } else { "0" }
+}
+
+pub fn encode(packet: &TurnPacket) -> String {
+    let mut lines = Vec::new();
+    lines.push(format!("zoc_turn_packet {}", TURN_PACKET_VERSION));
+    lines.push(format!("turn {}", packet.turn));
+    lines.push(format!("author {}", packet.author_id.id));
+    lines.push(format!("map_size {} {}", packet.map_size.w, packet.map_size.h));
+    let players: Vec<&str> = packet.players.iter().map(controller_to_str).collect();
+    lines.push(format!("players {}", players.join(" ")));
+    lines.push(format!("fow {}", bool_to_str(packet.fow)));
+    lines.push(format!("rules {} {} {} {} {}",
+        bool_to_str(packet.rules.reaction_fire),
+        bool_to_str(packet.rules.vehicle_damage),
+        bool_to_str(packet.rules.ambushes),
+        bool_to_str(packet.rules.zoc),
+        bool_to_str(packet.rules.symmetric_los),
+    ));
+    lines.push(format!("seed {}", packet.seed));
+    lines.push(format!("recipient {}", packet.recipient_id.id));
+    for signature in &packet.signatures {
+        lines.push(format!("signature {} {} {}",
+            signature.player_id.id, signature.commands_count, signature.mac));
+    }
+    for &(ref author_id, ref command) in &packet.commands {
+        lines.push(format!("command {}", encode_command_line(author_id, command)));
+    }
+    for event in &packet.events {
+        lines.push(format!("event {}", encode_event(event)));
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+fn parse<T: ::std::str::FromStr>(word: Option<&str>) -> Result<T, TurnPacketError> {
+    let word = try!(word.ok_or(
+        TurnPacketError::BadFormat("Unexpected end of line".to_string())));
+    word.parse().map_err(|_| TurnPacketError::BadFormat(format!("Bad number: {}", word)))
+}
+
+fn parse_bool(word: Option<&str>) -> Result<bool, TurnPacketError> {
+    Ok(try!(parse::<ZInt>(word)) != 0)
+}
+
+pub fn decode(s: &str) -> Result<TurnPacket, TurnPacketError> {
+    let mut lines = s.lines();
+    let header = try!(lines.next().ok_or(
+        TurnPacketError::BadFormat("Empty file".to_string())));
+    let mut words = header.split_whitespace();
+    if words.next() != Some("zoc_turn_packet") {
+        return Err(TurnPacketError::BadFormat("Not a turn file".to_string()));
+    }
+    let version = try!(parse(words.next()));
+    if version != TURN_PACKET_VERSION {
+        return Err(TurnPacketError::WrongVersion(version));
+    }
+    let mut packet = TurnPacket {
+        turn: 0,
+        author_id: PlayerId{id: 0},
+        map_size: Size2{w: 0, h: 0},
+        players: Vec::new(),
+        fow: true,
+        rules: CoreOptions::new().rules,
+        seed: 0,
+        recipient_id: PlayerId{id: 0},
+        events: Vec::new(),
+        commands: Vec::new(),
+        signatures: Vec::new(),
+    };
+    for line in lines {
+        let (key, rest) = match line.find(' ') {
+            Some(n) => (&line[.. n], &line[n + 1 ..]),
+            None => (line, ""),
+        };
+        let mut words = rest.split_whitespace();
+        match key {
+            "" => {},
+            "turn" => packet.turn = try!(parse(words.next())),
+            "author" => packet.author_id = PlayerId{id: try!(parse(words.next()))},
+            "map_size" => packet.map_size = Size2 {
+                w: try!(parse(words.next())),
+                h: try!(parse(words.next())),
+            },
+            "players" => {
+                for word in words {
+                    packet.players.push(match word {
+                        "human" => Controller::Human,
+                        "ai" => Controller::Ai,
+                        _ => return Err(TurnPacketError::BadFormat(
+                            format!("Bad controller: {}", word))),
+                    });
+                }
+            },
+            "fow" => packet.fow = try!(parse_bool(words.next())),
+            "rules" => packet.rules = Rules {
+                reaction_fire: try!(parse_bool(words.next())),
+                vehicle_damage: try!(parse_bool(words.next())),
+                ambushes: try!(parse_bool(words.next())),
+                zoc: try!(parse_bool(words.next())),
+                symmetric_los: try!(parse_bool(words.next())),
+            },
+            "seed" => packet.seed = try!(parse(words.next())),
+            "recipient" => packet.recipient_id = PlayerId{id: try!(parse(words.next()))},
+            "signature" => packet.signatures.push(Signature {
+                player_id: PlayerId{id: try!(parse(words.next()))},
+                commands_count: try!(parse(words.next())),
+                mac: try!(words.next().map(|word| word.to_string()).ok_or(
+                    TurnPacketError::BadFormat("No signature".to_string()))),
+            }),
+            "command" => {
+                let author_id = PlayerId{id: try!(parse(words.next()))};
+                let command_start = rest.find(' ').map_or(rest.len(), |n| n + 1);
+                let command = try!(decode_command(&rest[command_start ..])
+                    .map_err(TurnPacketError::BadFormat));
+                packet.commands.push((author_id, command));
+            },
+            "event" => packet.events.push(try!(
+                decode_event(rest).map_err(TurnPacketError::BadFormat))),
+            _ => return Err(TurnPacketError::BadFormat(format!("Bad line: {}", line))),
+        }
+    }
+    Ok(packet)
+}
+
+pub fn save(path: &str, packet: &TurnPacket) -> Result<(), String> {
+    let mut file = try!(File::create(path)
+        .map_err(|err| format!("Can`t create {}: {}", path, err)));
+    file.write_all(encode(packet).as_bytes())
+        .map_err(|err| format!("Can`t write {}: {}", path, err))
+}
+
+pub fn load(path: &str) -> Result<TurnPacket, String> {
+    let mut file = try!(File::open(path)
+        .map_err(|err| format!("Can`t open {}: {}", path, err)));
+    let mut s = String::new();
+    try!(file.read_to_string(&mut s)
+        .map_err(|err| format!("Can`t read {}: {}", path, err)));
+    decode(&s).map_err(|err| err.description())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::types::{PlayerId};
+    use core::{Core, CoreEvent};
+    use command::{Command};
+    use options::{CoreOptions, Controller};
+    use super::{TurnPacketError, encode, decode};
+
+    #[test]
+    fn turn_packets_are_checked() {
+        let player_0 = PlayerId{id: 0};
+        let player_1 = PlayerId{id: 1};
+        let mut core_0 = Core::new(CoreOptions::new().with_event_log(true));
+        core_0.do_command(Command::EndTurn);
+        let packet = core_0.export_turn_packet(&player_0, "zero");
+        let packet = decode(&encode(&packet)).ok().expect("Can`t decode packet");
+        let mut core_1 = Core::new(packet.options());
+        assert!(core_1.import_turn_packet(&packet, &player_1, "one").is_ok());
+        assert!(*core_1.player_id() == player_1);
+        match core_1.import_turn_packet(&packet, &player_1, "one") {
+            Err(TurnPacketError::OutOfOrder{expected: 2, got: 1}) => {},
+            _ => panic!("Out of order packet was imported"),
+        }
+        core_1.do_command(Command::EndTurn);
+        let packet = core_1.export_turn_packet(&player_1, "one");
+        let mut tampered = packet.clone();
+        tampered.commands[0].0 = player_1.clone();
+        match core_0.import_turn_packet(&tampered, &player_0, "zero") {
+            Err(TurnPacketError::Tampered) => {},
+            _ => panic!("Tampered packet was imported"),
+        }
+        let mut bad_command = packet.clone();
+        bad_command.commands.push((player_1.clone(), Command::EndTurn));
+        let mut core = Core::new(packet.options());
+        match core.import_turn_packet(&bad_command, &player_0, "zero") {
+            Err(TurnPacketError::BadCommand{index: 2}) => {},
+            _ => panic!("Command of wrong player was imported"),
+        }
+        let mut forged = packet.clone();
+        forged.commands.push((player_0.clone(), Command::EndTurn));
+        forged.recipient_id = player_1.clone();
+        let mut core = Core::new(packet.options());
+        match core.import_turn_packet(&forged, &player_0, "zero") {
+            Err(TurnPacketError::ForeignCommand{index: 2}) => {},
+            _ => panic!("Command given for importing player was imported"),
+        }
+        let mut other_rules = packet.clone();
+        other_rules.rules.zoc = !other_rules.rules.zoc;
+        let mut core = Core::new(packet.options());
+        match core.import_turn_packet(&other_rules, &player_0, "zero") {
+            Err(TurnPacketError::WrongGame) => {},
+            _ => panic!("Packet with other rules was imported"),
+        }
+        let mut mismatch = packet.clone();
+        for event in &mut mismatch.events {
+            if let CoreEvent::SetReinforcementPoints{ref mut points, ..} = *event {
+                *points += 100;
+            }
+        }
+        let mut core = Core::new(packet.options());
+        match core.import_turn_packet(&mismatch, &player_0, "zero") {
+            Err(TurnPacketError::Mismatch) => {},
+            _ => panic!("Packet with wrong events was imported"),
+        }
+        assert!(core_0.import_turn_packet(&packet, &player_0, "zero").is_ok());
+        assert!(*core_0.player_id() == player_0);
+    }
+
+    #[test]
+    fn replayed_ai_turns_match_packet_events() {
+        let player_0 = PlayerId{id: 0};
+        let options = CoreOptions::new()
+            .with_controller(&PlayerId{id: 1}, Controller::Ai)
+            .with_seed(3)
+            .with_event_log(true);
+        let mut core = Core::new(options);
+        for _ in 0 .. 3 {
+            core.do_command(Command::EndTurn);
+        }
+        let packet = core.export_turn_packet(&player_0, "zero");
+        assert!(packet.recipient_id == player_0);
+        let packet = decode(&encode(&packet)).ok().expect("Can`t decode packet");
+        let mut replayed = Core::new(packet.options());
+        assert!(replayed.import_turn_packet(&packet, &player_0, "zero").is_ok());
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -11,6 +11,15 @@ pub enum UnitClass {
     Vehicle,
 }
 
+#[derive(Clone, PartialEq)]
+pub enum VehicleDamage {
+    Immobilized,
+    WeaponDisabled,
+    CrewShaken,
+    Destroyed,
+}
+
+#[derive(Clone)]
 pub struct Unit {
     pub id: UnitId,
     pub pos: MapPos,
@@ -21,6 +30,8 @@ pub struct Unit {
     pub reactive_attack_points: Option<ZInt>,
     pub count: ZInt,
     pub morale: ZInt,
+    pub is_immobilized: bool,
+    pub is_weapon_disabled: bool,
 }
 
 pub struct WeaponType {
@@ -49,6 +60,11 @@ pub struct UnitType {
     pub reactive_attack_points: ZInt,
     pub los_range: ZInt,
     pub cover_los_range: ZInt,
+    /// Bonus to chance of spotting enemies in tiles with `Bad` visibility
+    pub spotting: ZInt,
+    pub cost: ZInt,
+    /// Enemies that enter adjacent tiles must stop
+    pub zoc: bool,
 }
 
 // vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -4,15 +4,162 @@
 #[macro_use]
 extern crate android_glue;
 
+extern crate common;
+extern crate core;
 extern crate visualizer;
 
-use visualizer::{Visualizer};
+use std::env;
+use common::types::{PlayerId, Size2, ZInt};
+use core::core::{Core};
+use core::client::{Client};
+use core::options::{CoreOptions, Controller};
+use core::turn_packet;
+use visualizer::{Visualizer, Backend, FileGame};
 
 #[cfg(target_os = "android")]
 android_start!(main);
 
+const USAGE: &'static str = "\
+    Usage: zoc [options]\n\
+    \n\
+    Options:\n\
+    \x20   --map-size WxH       map size\n\
+    \x20   --ai ID              player with this id is controlled by ai\n\
+    \x20   --seed N             seed for random numbers generator\n\
+    \x20   --no-fow             disable fog of war\n\
+    \x20   --no-reaction-fire   disable reaction fire\n\
+    \x20   --no-vehicle-damage  vehicles are destroyed by any hit\n\
+    \x20   --no-ambushes        disable ambushes\n\
+    \x20   --no-zoc             disable zones of control\n\
+    \x20   --symmetric-los      units see each other or neither does\n\
+    \x20   --ghost-turns N      remember hidden enemies for N turns\n\
+    \x20   --connect ADDR       join network game (see `src/server`)\n\
+    \x20   --slot ID            player id to take (or retake) in network\n\
+    \x20                        or play-by-file game\n\
+    \x20   --token WORD         token of network game slot to retake\n\
+    \x20   --observe            watch network game, Tab switches perspective\n\
+    \x20   --save-turn FILE     play-by-file game: save turn to FILE\n\
+    \x20   --load-turn FILE     load turn file, may be repeated: your previous\n\
+    \x20                        turn file first, then the opponent`s one\n\
+    \x20   --secret WORD        signs your turn files\n\
+";
+
+fn parse_int(s: &str) -> ZInt {
+    s.parse().ok().expect(USAGE)
+}
+
+fn parse_map_size(s: &str) -> Size2 {
+    let parts: Vec<&str> = s.split('x').collect();
+    if parts.len() != 2 {
+        panic!("{}", USAGE);
+    }
+    Size2{w: parse_int(parts[0]), h: parse_int(parts[1])}
+}
+
+fn load_file_game(
+    options: CoreOptions,
+    paths: &[String],
+    player_id: &PlayerId,
+    secret: &str,
+) -> Core {
+    let packets: Vec<_> = paths.iter()
+        .map(|path| turn_packet::load(path)
+            .unwrap_or_else(|err| panic!("Can`t load turn: {}", err)))
+        .collect();
+    let options = match packets.first() {
+        Some(packet) => packet.options()
+            .with_last_seen_turns(options.last_seen_turns),
+        None => options.with_event_log(true),
+    };
+    let mut core = Core::new(options);
+    for packet in &packets {
+        if let Err(err) = core.import_turn_packet(packet, player_id, secret) {
+            panic!("Can`t import turn {}: {}", packet.turn, err.description());
+        }
+    }
+    core
+}
+
+fn get_backend() -> Backend {
+    let mut options = CoreOptions::new();
+    let mut address = None;
+    let mut slot = None;
+    let mut token = None;
+    let mut observe = false;
+    let mut save_turn_path = None;
+    let mut load_turn_paths = Vec::new();
+    let mut secret = String::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--map-size" => {
+                let size = parse_map_size(&args.next().expect(USAGE));
+                options = options.with_map_size(size);
+            },
+            "--ai" => {
+                let id = parse_int(&args.next().expect(USAGE));
+                options = options.with_controller(&PlayerId{id: id}, Controller::Ai);
+            },
+            "--seed" => {
+                let seed = parse_int(&args.next().expect(USAGE));
+                options = options.with_seed(seed as usize);
+            },
+            "--no-fow" => options = options.with_fow(false),
+            "--no-reaction-fire" => options.rules.reaction_fire = false,
+            "--no-vehicle-damage" => options.rules.vehicle_damage = false,
+            "--no-ambushes" => options.rules.ambushes = false,
+            "--no-zoc" => options.rules.zoc = false,
+            "--symmetric-los" => options.rules.symmetric_los = true,
+            "--ghost-turns" => {
+                let turns = parse_int(&args.next().expect(USAGE));
+                options = options.with_last_seen_turns(turns);
+            },
+            "--connect" => address = Some(args.next().expect(USAGE)),
+            "--slot" => {
+                let id = parse_int(&args.next().expect(USAGE));
+                slot = Some(PlayerId{id: id});
+            },
+            "--token" => token = Some(args.next().expect(USAGE)),
+            "--observe" => observe = true,
+            "--save-turn" => save_turn_path = Some(args.next().expect(USAGE)),
+            "--load-turn" => load_turn_paths.push(args.next().expect(USAGE)),
+            "--secret" => secret = args.next().expect(USAGE),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    if let Some(path) = save_turn_path {
+        let player_id = slot.unwrap_or(PlayerId{id: 0});
+        let core = load_file_game(options, &load_turn_paths, &player_id, &secret);
+        return Backend::ByFile(FileGame {
+            core: core,
+            player_id: player_id,
+            path: path,
+            secret: secret,
+        });
+    }
+    match address {
+        Some(address) => {
+            let client = if observe {
+                Client::observe(&address)
+            } else {
+                Client::connect(&address, slot, token)
+            };
+            let mut client = client
+                .unwrap_or_else(|err| panic!("Can`t join game: {}", err));
+            if let (&Some(ref player_id), &Some(ref token))
+                = (client.player_id(), client.token())
+            {
+                println!("Rejoin with: --slot {} --token {}", player_id.id, token);
+            }
+            client.set_last_seen_turns(options.last_seen_turns);
+            Backend::Remote(client)
+        },
+        None => Backend::Local(Core::new(options)),
+    }
+}
+
 pub fn main() {
-    let mut visualizer = Visualizer::new();
+    let mut visualizer = Visualizer::new(get_backend());
     while visualizer.is_running() {
         visualizer.tick();
     }
@@ -0,0 +1,73 @@
+// See LICENSE file for copyright and license details.
+
+//! Network game server. Clients join with `zoc --connect ADDR`.
+
+extern crate common;
+extern crate core;
+
+use std::env;
+use common::types::{PlayerId, Size2, ZInt};
+use core::options::{CoreOptions, Controller};
+use core::server::{Server};
+
+const USAGE: &'static str = "\
+    Usage: server [options]\n\
+    \n\
+    Options:\n\
+    \x20   --address ADDR       address to listen (default: 0.0.0.0:5555)\n\
+    \x20   --map-size WxH       map size\n\
+    \x20   --ai ID              player with this id is controlled by ai\n\
+    \x20   --seed N             seed for random numbers generator\n\
+    \x20   --no-fow             disable fog of war\n\
+    \x20   --symmetric-los      units see each other or neither does\n\
+    \x20   --allow-observers    observers can join before the game is over\n\
+";
+
+fn parse_int(s: &str) -> ZInt {
+    s.parse().ok().expect(USAGE)
+}
+
+fn parse_map_size(s: &str) -> Size2 {
+    let parts: Vec<&str> = s.split('x').collect();
+    if parts.len() != 2 {
+        panic!("{}", USAGE);
+    }
+    Size2{w: parse_int(parts[0]), h: parse_int(parts[1])}
+}
+
+pub fn main() {
+    let mut options = CoreOptions::new();
+    let mut address = "0.0.0.0:5555".to_string();
+    let mut allow_observers = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--address" => address = args.next().expect(USAGE),
+            "--map-size" => {
+                let size = parse_map_size(&args.next().expect(USAGE));
+                options = options.with_map_size(size);
+            },
+            "--ai" => {
+                let id = parse_int(&args.next().expect(USAGE));
+                options = options.with_controller(&PlayerId{id: id}, Controller::Ai);
+            },
+            "--seed" => {
+                let seed = parse_int(&args.next().expect(USAGE));
+                options = options.with_seed(seed as usize);
+            },
+            "--no-fow" => options = options.with_fow(false),
+            "--symmetric-los" => options.rules.symmetric_los = true,
+            "--allow-observers" => allow_observers = true,
+            _ => panic!("{}", USAGE),
+        }
+    }
+    let mut server = Server::new(options, &address)
+        .unwrap_or_else(|err| panic!("Can`t listen {}: {}", address, err));
+    if allow_observers {
+        server.allow_observers();
+    }
+    println!("Listening on {}", server.local_addr());
+    server.run();
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
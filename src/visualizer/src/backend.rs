@@ -0,0 +1,157 @@
+// See LICENSE file for copyright and license details.
+
+//! Source of events for visualizer: local `Core`,
+//! remote game server or play-by-file game.
+
+use common::types::{PlayerId, Size2};
+use core::core::{Core, CoreEvent};
+use core::game_state::{Perspective};
+use core::client::{Client};
+use core::command::{Command, CommandError};
+use core::options::{CoreOptions, Controller};
+use core::db::{Db};
+use core::turn_packet;
+
+/// Local `Core` of one player of play-by-file game.
+/// Turn packet is saved to `path` when the player ends turn.
+pub struct FileGame {
+    pub core: Core,
+    pub player_id: PlayerId,
+    pub path: String,
+    pub secret: String,
+}
+
+impl FileGame {
+    fn save_turn(&mut self) {
+        let packet = self.core.export_turn_packet(&self.player_id, &self.secret);
+        match turn_packet::save(&self.path, &packet) {
+            Ok(()) => println!("Turn {} is saved to {}", packet.turn, self.path),
+            Err(err) => println!("Can`t save turn: {}", err),
+        }
+    }
+}
+
+pub enum Backend {
+    Local(Core),
+    /// Visualizer owns only `GameState` of client`s player
+    Remote(Client),
+    ByFile(FileGame),
+}
+
+impl Backend {
+    pub fn db(&self) -> &Db {
+        match *self {
+            Backend::Local(ref core) => core.db(),
+            Backend::Remote(ref client) => client.db(),
+            Backend::ByFile(ref game) => game.core.db(),
+        }
+    }
+
+    pub fn options(&self) -> &CoreOptions {
+        match *self {
+            Backend::Local(ref core) => core.options(),
+            Backend::Remote(ref client) => client.options(),
+            Backend::ByFile(ref game) => game.core.options(),
+        }
+    }
+
+    pub fn map_size(&self) -> &Size2 {
+        match *self {
+            Backend::Local(ref core) => core.map_size(),
+            Backend::Remote(ref client) => client.map_size(),
+            Backend::ByFile(ref game) => game.core.map_size(),
+        }
+    }
+
+    /// Whose view is shown
+    pub fn perspective(&self) -> Perspective {
+        match *self {
+            Backend::Local(ref core) => Perspective::Player(core.player_id().clone()),
+            Backend::Remote(ref client) => client.perspective().clone(),
+            Backend::ByFile(ref game) => Perspective::Player(game.player_id.clone()),
+        }
+    }
+
+    pub fn is_observer(&self) -> bool {
+        match *self {
+            Backend::Local(_) | Backend::ByFile(_) => false,
+            Backend::Remote(ref client) => client.player_id().is_none(),
+        }
+    }
+
+    /// Cycles observer`s view: full map, then every human player in turn.
+    /// Only perspectives that server sends are shown.
+    pub fn switch_perspective(&mut self) {
+        let client = match *self {
+            Backend::Remote(ref mut client) if client.player_id().is_none() => client,
+            _ => return,
+        };
+        let next = {
+            let perspectives = client.perspectives();
+            match perspectives.iter().position(|p| *p == *client.perspective()) {
+                Some(i) => perspectives[(i + 1) % perspectives.len()].clone(),
+                None => return,
+            }
+        };
+        client.set_perspective(next);
+    }
+
+    pub fn is_hot_seat(&self) -> bool {
+        match *self {
+            Backend::Local(ref core) => {
+                let humans_count = core.options().players.iter()
+                    .filter(|controller| **controller == Controller::Human)
+                    .count();
+                humans_count > 1
+            },
+            Backend::Remote(_) | Backend::ByFile(_) => false,
+        }
+    }
+
+    pub fn check_command(&self, command: &Command) -> Result<(), CommandError> {
+        match *self {
+            Backend::Local(ref core) => core.check_command(command),
+            Backend::Remote(ref client) => client.check_command(command),
+            Backend::ByFile(ref game) => {
+                if *game.core.player_id() != game.player_id {
+                    Err(CommandError::NotYourTurn)
+                } else {
+                    game.core.check_command(command)
+                }
+            },
+        }
+    }
+
+    pub fn do_command(&mut self, command: Command) {
+        match *self {
+            Backend::Local(ref mut core) => core.do_command(command),
+            Backend::Remote(ref mut client) => client.do_command(command),
+            Backend::ByFile(ref mut game) => {
+                if *game.core.player_id() != game.player_id {
+                    println!("{}", CommandError::NotYourTurn.description());
+                    return;
+                }
+                let is_end_turn = if let Command::EndTurn = command {
+                    true
+                } else {
+                    false
+                };
+                game.core.do_command(command);
+                if is_end_turn {
+                    game.save_turn();
+                }
+            },
+        }
+    }
+
+    pub fn get_event(&mut self) -> Option<CoreEvent> {
+        match *self {
+            Backend::Local(ref mut core) => core.get_event(),
+            Backend::Remote(ref mut client) => client.get_event(),
+            // after end of turn `Core` switches to the next player
+            Backend::ByFile(ref mut game) => game.core.get_player_event(&game.player_id),
+        }
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
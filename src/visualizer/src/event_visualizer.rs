@@ -6,8 +6,9 @@ use cgmath::{Vector3, Vector, rad};
 use common::types::{MapPos, ZFloat, UnitId, ZInt};
 use core::game_state::GameState;
 use core::core;
-use core::unit::{UnitTypeId};
+use core::unit::{UnitTypeId, VehicleDamage};
 use core::pathfinder::{MapPath};
+use core::db::{Db};
 use zgl::mesh::{MeshId};
 use zgl::types::{Time, WorldPos};
 use geom;
@@ -146,7 +147,7 @@ impl EventVisualizer for EventEndTurnVisualizer {
 }
 
 fn show_unit_at(
-    core: &core::Core,
+    db: &Db,
     scene: &mut Scene,
     id: &UnitId,
     type_id: &UnitTypeId,
@@ -162,7 +163,7 @@ fn show_unit_at(
         pos: to.clone(),
         rot: rot,
         mesh_id: None,
-        children: get_unit_scene_nodes(core, type_id, mesh_id),
+        children: get_unit_scene_nodes(db, type_id, mesh_id),
     });
     scene.nodes.insert(marker_id(id), SceneNode {
         pos: WorldPos{v: to.v.add_v(&vec3_z(geom::HEX_EX_RADIUS / 2.0))},
@@ -177,12 +178,12 @@ pub struct EventCreateUnitVisualizer {
     move_helper: MoveHelper,
 }
 
-fn get_unit_scene_nodes(
-    core: &core::Core,
+pub fn get_unit_scene_nodes(
+    db: &Db,
     type_id: &UnitTypeId,
     mesh_id: &MeshId,
 ) -> Vec<SceneNode> {
-    let count = core.db().unit_type(type_id).count;
+    let count = db.unit_type(type_id).count;
     let mut vec = Vec::new();
     if count == 1 {
         vec![SceneNode {
@@ -207,7 +208,7 @@ fn get_unit_scene_nodes(
 
 impl EventCreateUnitVisualizer {
     pub fn new(
-        core: &core::Core,
+        db: &Db,
         scene: &mut Scene,
         id: UnitId,
         type_id: &UnitTypeId,
@@ -218,7 +219,7 @@ impl EventCreateUnitVisualizer {
         let node_id = unit_id_to_node_id(&id);
         let to = geom::map_pos_to_world_pos(pos);
         let from = WorldPos{v: to.v.sub_v(&vec3_z(geom::HEX_EX_RADIUS / 2.0))};
-        show_unit_at(core, scene, &id, type_id, pos, mesh_id, marker_mesh_id);
+        show_unit_at(db, scene, &id, type_id, pos, mesh_id, marker_mesh_id);
         let move_helper = MoveHelper::new(&from, &to, 1.0);
         let new_node = scene.nodes.get_mut(&node_id)
             .expect("Can`t find created scene node");
@@ -265,6 +266,7 @@ impl EventAttackUnitVisualizer {
         killed: ZInt,
         suppression: ZInt,
         mode: core::FireMode,
+        damage: Option<VehicleDamage>,
         shell_mesh_id: MeshId,
         map_text: &mut MapTextManager,
     ) -> Box<EventVisualizer> {
@@ -281,8 +283,17 @@ impl EventAttackUnitVisualizer {
                 .expect("Can not find attacker")
                 .pos.clone();
             let attacker_map_pos = state.units()[&attacker_id].pos.clone();
-            if let core::FireMode::Reactive = mode {
-                map_text.add_text(&attacker_map_pos, "reaction fire");
+            match mode {
+                core::FireMode::Reactive => {
+                    map_text.add_text(&attacker_map_pos, "reaction fire");
+                },
+                core::FireMode::Defensive => {
+                    map_text.add_text(&attacker_map_pos, "defensive fire");
+                },
+                core::FireMode::Melee => {
+                    map_text.add_text(&attacker_map_pos, "assault");
+                },
+                core::FireMode::Active => {},
             }
             let shell_move = {
                 scene.nodes.insert(SHELL_NODE_ID, SceneNode {
@@ -299,10 +310,21 @@ impl EventAttackUnitVisualizer {
             None
         };
         let is_target_destroyed = state.units()[&defender_id].count - killed <= 0;
-        if killed > 0 {
-            map_text.add_text(&defender_map_pos, &format!("-{}", killed));
-        } else {
-            map_text.add_text(&defender_map_pos, "miss");
+        match damage {
+            Some(VehicleDamage::Immobilized) => {
+                map_text.add_text(&defender_map_pos, "immobilized");
+            },
+            Some(VehicleDamage::WeaponDisabled) => {
+                map_text.add_text(&defender_map_pos, "weapon disabled");
+            },
+            Some(VehicleDamage::CrewShaken) => {
+                map_text.add_text(&defender_map_pos, "crew shaken");
+            },
+            Some(VehicleDamage::Destroyed) | None => if killed > 0 {
+                map_text.add_text(&defender_map_pos, &format!("-{}", killed));
+            } else {
+                map_text.add_text(&defender_map_pos, "miss");
+            },
         }
         let defender_morale = state.units()[&defender_id].morale;
         let is_target_suppressed = defender_morale < 50
@@ -381,7 +403,7 @@ pub struct EventShowUnitVisualizer;
 
 impl EventShowUnitVisualizer {
     pub fn new(
-        core: &core::Core,
+        db: &Db,
         scene: &mut Scene,
         id: UnitId,
         type_id: &UnitTypeId,
@@ -391,7 +413,7 @@ impl EventShowUnitVisualizer {
         map_text: &mut MapTextManager,
     ) -> Box<EventVisualizer> {
         map_text.add_text(&pos, "spotted");
-        show_unit_at(core, scene, &id, type_id, pos, mesh_id, marker_mesh_id);
+        show_unit_at(db, scene, &id, type_id, pos, mesh_id, marker_mesh_id);
         Box::new(EventShowUnitVisualizer)
     }
 }
@@ -434,4 +456,64 @@ impl EventVisualizer for EventHideUnitVisualizer {
     fn end(&mut self, _: &mut Scene, _: &GameState) {}
 }
 
+pub struct EventRepairUnitVisualizer;
+
+impl EventRepairUnitVisualizer {
+    pub fn new(
+        state: &GameState,
+        unit_id: &UnitId,
+        map_text: &mut MapTextManager,
+    ) -> Box<EventVisualizer> {
+        let pos = state.units()[unit_id].pos.clone();
+        map_text.add_text(&pos, "repaired");
+        Box::new(EventRepairUnitVisualizer)
+    }
+}
+
+impl EventVisualizer for EventRepairUnitVisualizer {
+    fn is_finished(&self) -> bool {
+        true
+    }
+
+    fn draw(&mut self, _: &mut Scene, _: &Time) {}
+
+    fn end(&mut self, _: &mut Scene, _: &GameState) {}
+}
+
+pub struct EventSetReinforcementPointsVisualizer;
+
+impl EventSetReinforcementPointsVisualizer {
+    pub fn new() -> Box<EventVisualizer> {
+        Box::new(EventSetReinforcementPointsVisualizer)
+    }
+}
+
+impl EventVisualizer for EventSetReinforcementPointsVisualizer {
+    fn is_finished(&self) -> bool {
+        true
+    }
+
+    fn draw(&mut self, _: &mut Scene, _: &Time) {}
+
+    fn end(&mut self, _: &mut Scene, _: &GameState) {}
+}
+
+pub struct EventSetMoveOrderVisualizer;
+
+impl EventSetMoveOrderVisualizer {
+    pub fn new() -> Box<EventVisualizer> {
+        Box::new(EventSetMoveOrderVisualizer)
+    }
+}
+
+impl EventVisualizer for EventSetMoveOrderVisualizer {
+    fn is_finished(&self) -> bool {
+        true
+    }
+
+    fn draw(&mut self, _: &mut Scene, _: &Time) {}
+
+    fn end(&mut self, _: &mut Scene, _: &GameState) {}
+}
+
 // vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -75,6 +75,12 @@ impl ButtonManager {
         id
     }
 
+    /// Replaces button with new one (with updated label, for example)
+    pub fn set_button(&mut self, id: &ButtonId, button: Button) {
+        assert!(self.buttons.contains_key(id));
+        self.buttons.insert(id.clone(), button);
+    }
+
     // TODO: context: &Context
     pub fn get_clicked_button_id(
         &self,
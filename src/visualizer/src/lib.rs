@@ -10,6 +10,7 @@ extern crate core;
 extern crate zgl;
 
 mod visualizer;
+mod backend;
 mod gui;
 mod scene;
 mod event_visualizer;
@@ -20,5 +21,6 @@ mod move_helper;
 mod geom;
 
 pub use visualizer::{Visualizer};
+pub use backend::{Backend, FileGame};
 
 // vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
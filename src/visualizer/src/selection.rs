@@ -2,7 +2,7 @@
 
 use std::path::{Path};
 use cgmath::{Vector2, rad};
-use common::types::{UnitId};
+use common::types::{ZInt, UnitId};
 use core::game_state::GameState;
 use zgl::misc::{add_quad_to_vec};
 use zgl::mesh::{Mesh, MeshId};
@@ -13,12 +13,16 @@ use geom;
 use scene::{
     Scene,
     SceneNode,
+    NodeId,
     SELECTION_NODE_ID,
+    MIN_GROUP_SELECTION_NODE_ID,
+    MAX_GROUP_SELECTION_NODE_ID,
 };
 
 pub struct SelectionManager {
     unit_id: Option<UnitId>,
     mesh_id: MeshId,
+    group_markers_count: ZInt,
 }
 
 impl SelectionManager {
@@ -26,6 +30,7 @@ impl SelectionManager {
         SelectionManager {
             unit_id: None,
             mesh_id: mesh_id,
+            group_markers_count: 0,
         }
     }
 
@@ -66,8 +71,40 @@ impl SelectionManager {
         scene.nodes.insert(SELECTION_NODE_ID, node);
     }
 
+    /// Markers of units that are selected in addition to the main one
+    pub fn create_group_markers(
+        &mut self,
+        state: &GameState,
+        scene: &mut Scene,
+        unit_ids: &[UnitId],
+    ) {
+        self.remove_group_markers(scene);
+        for unit_id in unit_ids {
+            let node_id = NodeId {
+                id: MIN_GROUP_SELECTION_NODE_ID.id + self.group_markers_count,
+            };
+            assert!(node_id <= MAX_GROUP_SELECTION_NODE_ID);
+            let map_pos = &state.units()[unit_id].pos;
+            scene.nodes.insert(node_id, SceneNode {
+                pos: WorldPos{v: geom::lift(geom::map_pos_to_world_pos(map_pos).v)},
+                rot: rad(0.0),
+                mesh_id: Some(self.mesh_id.clone()),
+                children: Vec::new(),
+            });
+            self.group_markers_count += 1;
+        }
+    }
+
+    fn remove_group_markers(&mut self, scene: &mut Scene) {
+        for i in 0 .. self.group_markers_count {
+            scene.nodes.remove(&NodeId{id: MIN_GROUP_SELECTION_NODE_ID.id + i});
+        }
+        self.group_markers_count = 0;
+    }
+
     pub fn deselect(&mut self, scene: &mut Scene) {
         scene.nodes.remove(&SELECTION_NODE_ID);
+        self.remove_group_markers(scene);
         self.unit_id = None;
     }
 }
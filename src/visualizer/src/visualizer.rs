@@ -5,6 +5,7 @@ use num::{Float};
 use rand::{thread_rng, Rng};
 use std::path::{Path, PathBuf};
 use time::precise_time_ns;
+use std::mem;
 use std::collections::{HashMap};
 use cgmath::{
     Vector,
@@ -26,6 +27,7 @@ use glutin::ElementState::{Pressed, Released};
 use common::types::{Size2, ZInt, UnitId, PlayerId, MapPos, ZFloat};
 use zgl::types::{
     Color3,
+    Color4,
     ColorId,
     ScreenPos,
     VertexCoord,
@@ -41,17 +43,21 @@ use zgl::shader::{Shader};
 use geom;
 use core::map::{Map, distance, Terrain, spiral_iter};
 use core::dir::{Dir, dirs};
-use core::game_state::GameState;
-use core::pathfinder::Pathfinder;
+use core::game_state::{GameState, ReactionFireThreat, Perspective, TileKnowledge};
+use core::pathfinder::{Pathfinder, PathCostMode, MapPath};
+use core::move_order::{MoveOrder};
+use core::group_move::{plan_group_move};
 use core::command::{Command, MoveMode};
-use core::core::{Core, CoreEvent, los};
-use core::unit::{Unit, UnitClass};
+use core::core::{CoreEvent, los};
+use core::options::{CoreOptions};
+use core::unit::{Unit, UnitClass, UnitTypeId};
 use core::db::{Db};
 use zgl::texture::{Texture};
 use zgl::obj;
 use zgl::font_stash::{FontStash};
 use gui::{ButtonManager, Button, ButtonId};
 use scene::{NodeId, Scene, SceneNode, MIN_MAP_OBJECT_NODE_ID};
+use backend::{Backend};
 use event_visualizer::{
     EventVisualizer,
     EventMoveVisualizer,
@@ -60,6 +66,10 @@ use event_visualizer::{
     EventAttackUnitVisualizer,
     EventShowUnitVisualizer,
     EventHideUnitVisualizer,
+    EventRepairUnitVisualizer,
+    EventSetReinforcementPointsVisualizer,
+    EventSetMoveOrderVisualizer,
+    get_unit_scene_nodes,
 };
 use unit_type_visual_info::{
     UnitTypeVisualInfo,
@@ -71,6 +81,7 @@ use map_text::{MapTextManager};
 const BG_COLOR: Color3 = Color3{r: 0.8, g: 0.8, b: 0.8};
 const CAMERA_MOVE_SPEED: ZFloat = geom::HEX_EX_RADIUS * 12.0;
 const CAMERA_MOVE_SPEED_KEY: ZFloat = geom::HEX_EX_RADIUS;
+const UNEXPLORED_TILE_COLOR: Color4 = Color4{r: 0.3, g: 0.3, b: 0.3, a: 1.0};
 
 static VS_SRC: &'static str = "\
     #version 100\n\
@@ -114,12 +125,12 @@ fn get_max_camera_pos(map_size: &Size2) -> WorldPos {
 }
 
 fn gen_tiles<F>(zgl: &Zgl, state: &GameState, tex: &Texture, cond: F) -> Mesh
-    where F: Fn(bool) -> bool
+    where F: Fn(&TileKnowledge) -> bool
 {
     let mut vertex_data = Vec::new();
     let mut tex_data = Vec::new();
     for tile_pos in state.map().get_iter() {
-        if !cond(state.is_tile_visible(&tile_pos)) {
+        if !cond(&state.tile_knowledge(&tile_pos)) {
             continue;
         }
         let pos = geom::map_pos_to_world_pos(&tile_pos);
@@ -141,11 +152,48 @@ fn gen_tiles<F>(zgl: &Zgl, state: &GameState, tex: &Texture, cond: F) -> Mesh
 }
 
 fn generate_visible_tiles_mesh(zgl: &Zgl, state: &GameState, tex: &Texture) -> Mesh {
-    gen_tiles(zgl, state, tex, |vis| vis)
+    gen_tiles(zgl, state, tex, |k| *k == TileKnowledge::Visible)
 }
 
 fn generate_fogged_tiles_mesh(zgl: &Zgl, state: &GameState, tex: &Texture) -> Mesh {
-    gen_tiles(zgl, state, tex, |vis| !vis)
+    gen_tiles(zgl, state, tex, |k| *k == TileKnowledge::Fogged)
+}
+
+fn generate_unexplored_tiles_mesh(zgl: &Zgl, state: &GameState, tex: &Texture) -> Mesh {
+    gen_tiles(zgl, state, tex, |k| *k == TileKnowledge::Unexplored)
+}
+
+/// Shows trees on tiles that were explored since the last call,
+/// `added` marks tiles whose objects are already in scene
+fn add_explored_map_objects(
+    state: &GameState,
+    scene: &mut Scene,
+    added: &mut Map<bool>,
+    trees_mesh_id: &MeshId,
+) {
+    let map_size = state.map().size().clone();
+    for tile_pos in state.map().get_iter() {
+        if *added.tile(&tile_pos) {
+            continue;
+        }
+        let terrain = match state.known_terrain(&tile_pos) {
+            Some(terrain) => terrain,
+            None => continue,
+        };
+        *added.tile_mut(&tile_pos) = true;
+        if let &Terrain::Trees = terrain {
+            let index = tile_pos.v.y * map_size.w + tile_pos.v.x;
+            let node_id = NodeId{id: MIN_MAP_OBJECT_NODE_ID.id + index};
+            let pos = geom::map_pos_to_world_pos(&tile_pos);
+            let rot = rad(thread_rng().gen_range(0.0, PI * 2.0));
+            scene.nodes.insert(node_id, SceneNode {
+                pos: pos,
+                rot: rot,
+                mesh_id: Some(trees_mesh_id.clone()),
+                children: Vec::new(),
+            });
+        }
+    }
 }
 
 fn build_walkable_mesh(zgl: &Zgl, pf: &Pathfinder, map: &Map<Terrain>, move_points: ZInt) -> Mesh {
@@ -167,6 +215,45 @@ fn build_walkable_mesh(zgl: &Zgl, pf: &Pathfinder, map: &Map<Terrain>, move_poin
     mesh
 }
 
+/// Meshes of tiles reachable in Fast and Hunt modes.
+/// Pathfinder is left filled for Fast mode.
+fn build_walkable_meshes(
+    zgl: &Zgl,
+    db: &Db,
+    pf: &mut Pathfinder,
+    state: &GameState,
+    unit: &Unit,
+) -> (Mesh, Mesh) {
+    pf.fill_map(db, state, unit, &MoveMode::Hunt);
+    let hunt_mesh = build_walkable_mesh(zgl, pf, state.map(), unit.move_points);
+    pf.fill_map(db, state, unit, &MoveMode::Fast);
+    let fast_mesh = build_walkable_mesh(zgl, pf, state.map(), unit.move_points);
+    (fast_mesh, hunt_mesh)
+}
+
+/// Lines between path nodes, only risky (or only safe) segments
+fn build_path_mesh(
+    zgl: &Zgl,
+    path: &MapPath,
+    danger: &Vec<Vec<ReactionFireThreat>>,
+    is_risky: bool,
+) -> Mesh {
+    let mut vertex_data = Vec::new();
+    let nodes = path.nodes();
+    for i in 1 .. nodes.len() {
+        if danger[i].is_empty() == is_risky {
+            continue;
+        }
+        let world_pos_from = geom::map_pos_to_world_pos(&nodes[i - 1].pos);
+        let world_pos_to = geom::map_pos_to_world_pos(&nodes[i].pos);
+        vertex_data.push(VertexCoord{v: geom::lift(world_pos_from.v)});
+        vertex_data.push(VertexCoord{v: geom::lift(world_pos_to.v)});
+    }
+    let mut mesh = Mesh::new(zgl, &vertex_data);
+    mesh.set_mode(MeshRenderMode::Lines);
+    mesh
+}
+
 fn get_marker(zgl: &Zgl, tex_path: &Path) -> Mesh {
     let n = 0.2;
     let vertex_data = vec!(
@@ -246,36 +333,57 @@ struct PlayerInfo {
     game_state: GameState,
     pathfinder: Pathfinder,
     scene: Scene,
+    /// Tiles whose map objects are already added to `scene`
+    map_objects: Map<bool>,
 }
 
 struct PlayerInfoManager {
-    info: HashMap<PlayerId, PlayerInfo>,
+    info: HashMap<Perspective, PlayerInfo>,
 }
 
 impl PlayerInfoManager {
-    fn new(map_size: &Size2) -> PlayerInfoManager {
+    fn new(map_size: &Size2, options: &CoreOptions) -> PlayerInfoManager {
         let mut m = HashMap::new();
-        m.insert(PlayerId{id: 0}, PlayerInfo {
-            game_state: GameState::new(map_size, &PlayerId{id: 0}),
-            pathfinder: Pathfinder::new(map_size),
-            scene: Scene::new(),
-        });
-        m.insert(PlayerId{id: 1}, PlayerInfo {
-            game_state: GameState::new(map_size, &PlayerId{id: 1}),
+        for id in 0 .. options.players.len() {
+            let player_id = PlayerId{id: id as ZInt};
+            let mut game_state = GameState::new(map_size, &player_id);
+            if !options.fow {
+                game_state.disable_fow();
+            }
+            game_state.set_rules(&options.rules);
+            game_state.set_last_seen_turns(options.last_seen_turns);
+            let mut pathfinder = Pathfinder::new(map_size);
+            if !options.rules.zoc {
+                pathfinder.disable_zoc();
+            }
+            m.insert(Perspective::Player(player_id), PlayerInfo {
+                game_state: game_state,
+                pathfinder: pathfinder,
+                scene: Scene::new(),
+                    map_objects: Map::new(map_size, false),
+            });
+        }
+        // observer doesn`t own units
+        let mut game_state = GameState::new(map_size, &PlayerId{id: -1});
+        game_state.disable_fow();
+        game_state.set_rules(&options.rules);
+        m.insert(Perspective::Full, PlayerInfo {
+            game_state: game_state,
             pathfinder: Pathfinder::new(map_size),
             scene: Scene::new(),
+            map_objects: Map::new(map_size, false),
         });
         PlayerInfoManager{info: m}
     }
 
-    fn get<'a>(&'a self, player_id: &PlayerId) -> &'a PlayerInfo {
-        &self.info[player_id]
+    fn get<'a>(&'a self, perspective: &Perspective) -> &'a PlayerInfo {
+        &self.info[perspective]
     }
 
-    fn get_mut<'a>(&'a mut self, player_id: &PlayerId) -> &'a mut PlayerInfo {
-        match self.info.get_mut(player_id) {
+    fn get_mut<'a>(&'a mut self, perspective: &Perspective) -> &'a mut PlayerInfo {
+        match self.info.get_mut(perspective) {
             Some(i) => i,
-            None => panic!("Can`t find player_info for id={}", player_id.id),
+            None => panic!("Can`t find player_info for perspective"),
         }
     }
 }
@@ -297,6 +405,7 @@ pub struct Visualizer {
     mouse_pos: ScreenPos,
     is_lmb_pressed: bool,
     is_rmb_pressed: bool,
+    is_shift_pressed: bool,
     win_size: Size2,
     just_pressed_lmb: bool,
     last_press_pos: ScreenPos,
@@ -304,26 +413,48 @@ pub struct Visualizer {
     map_text_manager: MapTextManager,
     button_manager: ButtonManager,
     button_end_turn_id: ButtonId,
+    /// Reinforcement points of current player
+    button_points_id: ButtonId,
+    /// Type of units that are created with `U` key, click to change
+    button_unit_type_id: ButtonId,
+    unit_type_id: UnitTypeId,
+    /// Hot-seat: blank screen between turns of human players
+    handoff_label: Option<Button>,
     last_time: Time,
     player_info: PlayerInfoManager,
-    core: Core,
+    backend: Backend,
     event: Option<CoreEvent>,
     event_visualizer: Option<Box<EventVisualizer>>,
     mesh_ids: MeshIdManager,
     meshes: Vec<Mesh>,
     unit_type_visual_info: UnitTypeVisualInfoManager,
     selected_unit_id: Option<UnitId>,
+    hovered_unit_id: Option<UnitId>,
+    hovered_pos: Option<MapPos>,
     selection_manager: SelectionManager,
     // TODO: move to 'meshes'
     walkable_mesh: Option<Mesh>,
+    hunt_walkable_mesh: Option<Mesh>,
+    path_mesh: Option<Mesh>,
+    risky_path_mesh: Option<Mesh>,
     visible_map_mesh: Mesh,
     fow_map_mesh: Mesh,
+    unexplored_map_mesh: Mesh,
     floor_tex: Texture,
     pick_result: PickResult,
+    /// Waypoints of move order that is being given to selected unit
+    waypoints: Vec<MapPos>,
+    /// Units whose move orders are not executed in this turn yet
+    pending_order_ids: Vec<UnitId>,
+    /// Unit that was moved by the last executed move order
+    order_unit_id: Option<UnitId>,
+    is_waiting_for_order_move: bool,
+    /// Units selected with shift in addition to the selected one
+    group_unit_ids: Vec<UnitId>,
 }
 
 impl Visualizer {
-    pub fn new() -> Visualizer {
+    pub fn new(backend: Backend) -> Visualizer {
         let gl_version = glutin::GlRequest::GlThenGles {
             opengles_version: (2, 0),
             opengl_version: (2, 0)
@@ -348,20 +479,21 @@ impl Visualizer {
             &zgl, "basic_color");
         zgl.set_clear_color(&BG_COLOR);
         let mut camera = Camera::new(&win_size);
-        let core = Core::new();
-        let map_size = core.map_size().clone();
+        let map_size = backend.map_size().clone();
         camera.set_max_pos(get_max_camera_pos(&map_size));
         camera.set_pos(get_initial_camera_pos(&map_size));
-        let player_info = PlayerInfoManager::new(&map_size);
+        let player_info = PlayerInfoManager::new(&map_size, backend.options());
 
         let floor_tex = Texture::new(&zgl, &Path::new("floor.png")); // TODO: !!!
 
         let mut meshes = Vec::new();
 
         let visible_map_mesh = generate_visible_tiles_mesh(
-            &zgl, &player_info.get(core.player_id()).game_state, &floor_tex);
+            &zgl, &player_info.get(&backend.perspective()).game_state, &floor_tex);
         let fow_map_mesh = generate_fogged_tiles_mesh(
-            &zgl, &player_info.get(core.player_id()).game_state, &floor_tex);
+            &zgl, &player_info.get(&backend.perspective()).game_state, &floor_tex);
+        let unexplored_map_mesh = generate_unexplored_tiles_mesh(
+            &zgl, &player_info.get(&backend.perspective()).game_state, &floor_tex);
 
         let trees_mesh_id = add_mesh(
             &mut meshes, load_unit_mesh(&zgl, "trees"));
@@ -375,7 +507,7 @@ impl Visualizer {
             &mut meshes, get_marker(&zgl, &Path::new("flag2.png")));
 
         let unit_type_visual_info
-            = get_unit_type_visual_info(core.db(), &zgl, &mut meshes);
+            = get_unit_type_visual_info(backend.db(), &zgl, &mut meshes);
 
         let font_size = 40.0;
         let mut font_stash = FontStash::new(
@@ -388,6 +520,22 @@ impl Visualizer {
             &mut font_stash,
             ScreenPos{v: Vector2{x: 10, y: 10}})
         );
+        let row_h = win_size.h / 10;
+        let button_points_id = button_manager.add_button(Button::new(
+            &zgl,
+            &win_size,
+            "points",
+            &mut font_stash,
+            ScreenPos{v: Vector2{x: 10, y: 10 + row_h * 2}})
+        );
+        let button_unit_type_id = button_manager.add_button(Button::new(
+            &zgl,
+            &win_size,
+            "buy",
+            &mut font_stash,
+            ScreenPos{v: Vector2{x: 10, y: 10 + row_h}})
+        );
+        let unit_type_id = backend.db().unit_type_id("soldier");
         let mesh_ids = MeshIdManager {
             trees_mesh_id: trees_mesh_id,
             shell_mesh_id: shell_mesh_id,
@@ -405,33 +553,83 @@ impl Visualizer {
             mouse_pos: ScreenPos{v: Vector::from_value(0)},
             is_lmb_pressed: false,
             is_rmb_pressed: false,
+            is_shift_pressed: false,
             win_size: win_size,
             just_pressed_lmb: false,
             last_press_pos: ScreenPos{v: Vector::from_value(0)},
             font_stash: font_stash,
             button_manager: button_manager,
             button_end_turn_id: button_end_turn_id,
+            button_points_id: button_points_id,
+            button_unit_type_id: button_unit_type_id,
+            unit_type_id: unit_type_id,
+            handoff_label: None,
             last_time: Time{n: precise_time_ns()},
             player_info: player_info,
-            core: core,
+            backend: backend,
             event: None,
             event_visualizer: None,
             mesh_ids: mesh_ids,
             meshes: meshes,
             unit_type_visual_info: unit_type_visual_info,
             selected_unit_id: None,
+            hovered_unit_id: None,
+            hovered_pos: None,
             selection_manager: SelectionManager::new(selection_marker_mesh_id),
             walkable_mesh: None,
+            hunt_walkable_mesh: None,
+            path_mesh: None,
+            risky_path_mesh: None,
             map_text_manager: map_text_manager,
             visible_map_mesh: visible_map_mesh,
             fow_map_mesh: fow_map_mesh,
+            unexplored_map_mesh: unexplored_map_mesh,
             floor_tex: floor_tex,
             pick_result: PickResult::None,
+            waypoints: Vec::new(),
+            pending_order_ids: Vec::new(),
+            order_unit_id: None,
+            is_waiting_for_order_move: false,
+            group_unit_ids: Vec::new(),
         };
         visualizer.add_map_objects();
+        visualizer.update_hud();
         visualizer
     }
 
+    fn hud_button(&mut self, label: &str, id: &ButtonId) -> Button {
+        let pos = self.button_manager.buttons()[id].pos().clone();
+        Button::new(&self.zgl, &self.win_size, label, &mut self.font_stash, pos)
+    }
+
+    /// Updates labels with reinforcement points and type of bought units
+    fn update_hud(&mut self) {
+        let points_label = match self.backend.perspective() {
+            Perspective::Player(player_id) => {
+                let state = &self.player_info.get(
+                    &self.backend.perspective()).game_state;
+                format!("points: {}", state.reinforcement_points(&player_id))
+            },
+            Perspective::Full => "points: -".to_string(),
+        };
+        let unit_type_label = {
+            let unit_type = self.backend.db().unit_type(&self.unit_type_id);
+            format!("buy: {} ({})", unit_type.name, unit_type.cost)
+        };
+        let id = self.button_points_id.clone();
+        let button = self.hud_button(&points_label, &id);
+        self.button_manager.set_button(&id, button);
+        let id = self.button_unit_type_id.clone();
+        let button = self.hud_button(&unit_type_label, &id);
+        self.button_manager.set_button(&id, button);
+    }
+
+    fn switch_unit_type(&mut self) {
+        let count = self.backend.db().unit_types_count();
+        self.unit_type_id.id = (self.unit_type_id.id + 1) % count;
+        self.update_hud();
+    }
+
     fn pick_world_pos(&self) -> WorldPos {
         let im = self.camera.mat(&self.zgl).invert()
             .expect("Can`t invert camera matrix");
@@ -465,41 +663,62 @@ impl Visualizer {
     }
 
     fn add_map_objects(&mut self) {
-        let mut node_id = MIN_MAP_OBJECT_NODE_ID.clone();
-
-        for (_, player_info) in self.player_info.info.iter_mut() {
-            let map = &player_info.game_state.map();
-            for tile_pos in map.get_iter() {
-                if let &Terrain::Trees = map.tile(&tile_pos) {
-                    let pos = geom::map_pos_to_world_pos(&tile_pos);
-                    let rot = rad(thread_rng().gen_range(0.0, PI * 2.0));
-                    player_info.scene.nodes.insert(node_id.clone(), SceneNode {
-                        pos: pos.clone(),
-                        rot: rot,
-                        mesh_id: Some(self.mesh_ids.trees_mesh_id.clone()),
-                        children: Vec::new(),
-                    });
-                    node_id.id += 1;
-                }
-            }
+        for (_, i) in self.player_info.info.iter_mut() {
+            add_explored_map_objects(&i.game_state, &mut i.scene,
+                &mut i.map_objects, &self.mesh_ids.trees_mesh_id);
         }
     }
 
+    fn regenerate_map_meshes(&mut self) {
+        let state = &self.player_info.get(&self.backend.perspective()).game_state;
+        self.visible_map_mesh = generate_visible_tiles_mesh(
+            &self.zgl, state, &self.floor_tex);
+        self.fow_map_mesh = generate_fogged_tiles_mesh(
+            &self.zgl, state, &self.floor_tex);
+        self.unexplored_map_mesh = generate_unexplored_tiles_mesh(
+            &self.zgl, state, &self.floor_tex);
+    }
+
     pub fn is_running(&self) -> bool {
         !self.should_close
     }
 
     fn end_turn(&mut self) {
         self.pick_result = PickResult::None;
-        self.core.do_command(Command::EndTurn);
+        self.backend.do_command(Command::EndTurn);
         self.selected_unit_id = None;
-        let i = self.player_info.get_mut(self.core.player_id());
+        self.group_unit_ids.clear();
+        let i = self.player_info.get_mut(&self.backend.perspective());
         self.selection_manager.deselect(&mut i.scene);
         self.walkable_mesh = None;
+        self.hunt_walkable_mesh = None;
+        self.path_mesh = None;
+        self.risky_path_mesh = None;
+        if self.backend.is_hot_seat() {
+            self.show_handoff_screen();
+        }
+        self.update_hud();
+    }
+
+    /// Hides next player`s view until they confirm that they are at the screen
+    fn show_handoff_screen(&mut self) {
+        let player_id = match self.backend.perspective() {
+            Perspective::Player(player_id) => player_id,
+            Perspective::Full => return,
+        };
+        let label = format!("Player {}, press to continue", player_id.id + 1);
+        let pos = ScreenPos{v: Vector2{
+            x: self.win_size.w / 4,
+            y: self.win_size.h / 2,
+        }};
+        self.handoff_label = Some(Button::new(
+            &self.zgl, &self.win_size, &label, &mut self.font_stash, pos));
+        self.hovered_unit_id = None;
+        self.hovered_pos = None;
     }
 
     fn is_tile_occupied(&self, pos: &MapPos) -> bool {
-        let i = self.player_info.get(self.core.player_id());
+        let i = self.player_info.get(&self.backend.perspective());
         i.game_state.is_tile_occupied(pos)
     }
 
@@ -508,20 +727,27 @@ impl Visualizer {
             if self.is_tile_occupied(pos) {
                 return;
             }
-            let cmd = Command::CreateUnit{pos: pos.clone()};
-            self.core.do_command(cmd);
+            let cmd = Command::CreateUnit {
+                pos: pos.clone(),
+                type_id: self.unit_type_id.clone(),
+            };
+            if let Err(err) = self.backend.check_command(&cmd) {
+                self.map_text_manager.add_text(pos, err.description());
+                return;
+            }
+            self.backend.do_command(cmd);
         }
     }
 
     pub fn los(&self, unit: &Unit, from: &MapPos, to: &MapPos) -> bool {
-        let unit_type = self.core.db().unit_type(&unit.type_id);
-        let i = self.player_info.get(self.core.player_id());
+        let unit_type = self.backend.db().unit_type(&unit.type_id);
+        let i = self.player_info.get(&self.backend.perspective());
         let map = i.game_state.map();
-        los(map, unit_type, from, to)
+        los(map, unit_type, from, to, i.game_state.is_los_symmetric())
     }
 
     fn attack_unit(&mut self, attacker_id: &UnitId, defender_id: &UnitId) {
-        let state = &self.player_info.get(self.core.player_id()).game_state;
+        let state = &self.player_info.get(&self.backend.perspective()).game_state;
         let attacker = &state.units()[attacker_id];
         let defender = &state.units()[defender_id];
         if attacker.attack_points <= 0 {
@@ -534,7 +760,7 @@ impl Visualizer {
                 &defender.pos, "Can`t attack when suppressed");
             return;
         }
-        let max_distance = self.core.db().unit_max_attack_dist(attacker);
+        let max_distance = self.backend.db().unit_max_attack_dist(attacker);
         if distance(&attacker.pos, &defender.pos) > max_distance {
             self.map_text_manager.add_text(
                 &defender.pos, "Out of range");
@@ -545,7 +771,7 @@ impl Visualizer {
                 &defender.pos, "No LOS");
             return;
         }
-        self.core.do_command(Command::AttackUnit {
+        self.backend.do_command(Command::AttackUnit {
             attacker_id: attacker_id.clone(),
             defender_id: defender_id.clone(),
         });
@@ -565,22 +791,305 @@ impl Visualizer {
         self.attack_unit(&attacker_id, &defender_id)
     }
 
+    fn try_to_assault(&mut self) {
+        let target_pos = if let PickResult::UnitId(ref id) = self.pick_result {
+            let state = &self.player_info.get(&self.backend.perspective()).game_state;
+            state.units()[id].pos.clone()
+        } else {
+            return;
+        };
+        let attacker_id = match self.selected_unit_id {
+            Some(ref id) => id.clone(),
+            None => return,
+        };
+        let cmd = Command::Assault {
+            attacker_id: attacker_id,
+            target_pos: target_pos.clone(),
+        };
+        if let Err(err) = self.backend.check_command(&cmd) {
+            self.map_text_manager.add_text(&target_pos, err.description());
+            return;
+        }
+        self.backend.do_command(cmd);
+    }
+
     fn select_unit(&mut self) {
         if let PickResult::UnitId(ref unit_id) = self.pick_result {
             self.selected_unit_id = Some(unit_id.clone());
-            let mut i = self.player_info.get_mut(self.core.player_id());
+            self.waypoints.clear();
+            self.group_unit_ids.clear();
+            let mut i = self.player_info.get_mut(&self.backend.perspective());
             let state = &i.game_state;
-            let pf = &mut i.pathfinder;
-            pf.fill_map(self.core.db(), state, &state.units()[unit_id]);
-            self.walkable_mesh = Some(build_walkable_mesh(
-                &self.zgl, pf, state.map(), state.units()[unit_id].move_points));
+            let (fast_mesh, hunt_mesh) = build_walkable_meshes(
+                &self.zgl, self.backend.db(), &mut i.pathfinder,
+                state, &state.units()[unit_id]);
+            self.walkable_mesh = Some(fast_mesh);
+            self.hunt_walkable_mesh = Some(hunt_mesh);
+            self.path_mesh = None;
+            self.risky_path_mesh = None;
+            self.hovered_pos = None;
             let scene = &mut i.scene;
             self.selection_manager.create_selection_marker(
                 state, scene, unit_id);
+            self.selection_manager.create_group_markers(state, scene, &[]);
             // TODO: highlight potential targets
         }
     }
 
+    /// Adds unit to the group of selected units or removes it from there
+    fn toggle_group_unit(&mut self, unit_id: &UnitId) {
+        if self.selected_unit_id.is_none() {
+            self.select_unit();
+            return;
+        }
+        if self.selected_unit_id.as_ref() == Some(unit_id) {
+            return;
+        }
+        if let Some(index) = self.group_unit_ids.iter().position(|id| id == unit_id) {
+            self.group_unit_ids.remove(index);
+        } else {
+            self.group_unit_ids.push(unit_id.clone());
+        }
+        let i = self.player_info.get_mut(&self.backend.perspective());
+        self.selection_manager.create_group_markers(
+            &i.game_state, &mut i.scene, &self.group_unit_ids);
+    }
+
+    fn move_group(&mut self, pos: &MapPos, mode: &MoveMode) {
+        let unit_id = match self.selected_unit_id {
+            Some(ref unit_id) => unit_id.clone(),
+            None => return,
+        };
+        let mut unit_ids = vec![unit_id.clone()];
+        unit_ids.extend(self.group_unit_ids.iter().cloned());
+        let moves = {
+            let i = self.player_info.get_mut(&self.backend.perspective());
+            let db = self.backend.db();
+            let state = &i.game_state;
+            let moves = plan_group_move(
+                db, state, &mut i.pathfinder, &unit_ids, pos, mode);
+            // planning refilled pathfinder for other units
+            i.pathfinder.fill_map(db, state, &state.units()[&unit_id], &MoveMode::Fast);
+            moves
+        };
+        if moves.is_empty() {
+            self.map_text_manager.add_text(pos, "Can not reach this tile");
+            return;
+        }
+        let command = Command::MoveGroup{moves: moves, mode: mode.clone()};
+        if let Err(err) = self.backend.check_command(&command) {
+            self.map_text_manager.add_text(pos, err.description());
+            return;
+        }
+        self.backend.do_command(command);
+    }
+
+    fn undo(&mut self) {
+        if let Err(err) = self.backend.check_command(&Command::Undo) {
+            println!("{}", err.description());
+            return;
+        }
+        self.backend.do_command(Command::Undo);
+    }
+
+    /// Next click on tile gives move order through all added waypoints
+    fn add_waypoint(&mut self) {
+        if self.selected_unit_id.is_none() {
+            return;
+        }
+        self.pick_tile();
+        if let PickResult::Pos(pos) = self.pick_result.clone() {
+            self.waypoints.push(pos.clone());
+            let text = format!("Waypoint {}", self.waypoints.len());
+            self.map_text_manager.add_text(&pos, &text);
+        }
+    }
+
+    fn cancel_move_order(&mut self) {
+        let unit_id = match self.selected_unit_id {
+            Some(ref unit_id) => unit_id.clone(),
+            None => return,
+        };
+        self.waypoints.clear();
+        let destination = {
+            let i = self.player_info.get(&self.backend.perspective());
+            match i.game_state.move_orders().get(&unit_id) {
+                Some(order) => order.destination().clone(),
+                None => return,
+            }
+        };
+        self.map_text_manager.add_text(&destination, "Order cancelled");
+        self.backend.do_command(Command::SetMoveOrder {
+            unit_id: unit_id,
+            waypoints: Vec::new(),
+        });
+    }
+
+    /// Starts, continues or cancels move orders of current player
+    fn update_move_orders(&mut self, event: &CoreEvent) {
+        let player_id = match self.backend.perspective() {
+            Perspective::Player(player_id) => player_id,
+            Perspective::Full => return,
+        };
+        let mut cancelled_order_id = None;
+        {
+            let i = self.player_info.get(&Perspective::Player(player_id.clone()));
+            let orders = i.game_state.move_orders();
+            match *event {
+                CoreEvent::EndTurn{ref new_id, ..} => {
+                    self.order_unit_id = None;
+                    self.is_waiting_for_order_move = false;
+                    self.pending_order_ids = if *new_id == player_id {
+                        let mut ids: Vec<UnitId> = orders.keys().cloned().collect();
+                        ids.sort();
+                        ids.reverse();
+                        ids
+                    } else {
+                        Vec::new()
+                    };
+                },
+                CoreEvent::Move{ref unit_id, ..} => {
+                    if self.order_unit_id.as_ref() == Some(unit_id) {
+                        self.is_waiting_for_order_move = false;
+                    } else {
+                        self.order_unit_id = None;
+                    }
+                },
+                CoreEvent::ShowUnit{player_id: ref owner_id, ..}
+                    if *owner_id != player_id =>
+                {
+                    if let Some(ref unit_id) = self.order_unit_id {
+                        if let Some(order) = orders.get(unit_id) {
+                            self.map_text_manager.add_text(
+                                order.destination(), "Enemy spotted, order cancelled");
+                            cancelled_order_id = Some(unit_id.clone());
+                        }
+                    }
+                },
+                CoreEvent::AttackUnit{ref defender_id, ..} => {
+                    // `GameState` drops the order itself
+                    if let Some(order) = orders.get(defender_id) {
+                        self.map_text_manager.add_text(
+                            order.destination(), "Under fire, order cancelled");
+                    }
+                },
+                _ => {},
+            }
+        }
+        if let Some(unit_id) = cancelled_order_id {
+            self.backend.do_command(Command::SetMoveOrder {
+                unit_id: unit_id,
+                waypoints: Vec::new(),
+            });
+        }
+    }
+
+    /// Moves next unit that has move order. Waits for events
+    /// of previous order`s move, so only one unit moves at a time.
+    fn execute_next_order(&mut self) {
+        if self.is_waiting_for_order_move {
+            return;
+        }
+        let unit_id = match self.pending_order_ids.pop() {
+            Some(unit_id) => unit_id,
+            None => return,
+        };
+        let path = {
+            let i = self.player_info.get_mut(&self.backend.perspective());
+            let state = &i.game_state;
+            let unit = match state.units().get(&unit_id) {
+                Some(unit) => unit,
+                None => return,
+            };
+            // waypoints were updated by events of actually resolved moves
+            let path = match state.move_orders().get(&unit_id) {
+                Some(order) => order.next_path(
+                    self.backend.db(), state, &mut i.pathfinder, unit),
+                None => return,
+            };
+            match path {
+                Some(path) => path,
+                None => return,
+            }
+        };
+        let command = Command::Move {
+            unit_id: unit_id.clone(),
+            path: path,
+            mode: MoveMode::Fast,
+        };
+        if let Err(err) = self.backend.check_command(&command) {
+            println!("Can`t execute move order: {}", err.description());
+            return;
+        }
+        self.order_unit_id = Some(unit_id);
+        self.is_waiting_for_order_move = true;
+        self.backend.do_command(command);
+    }
+
+    /// Switches pathfinding between shortest and safest routes
+    fn toggle_safe_routes(&mut self) {
+        let mode = match *self.player_info.get(&self.backend.perspective())
+            .pathfinder.cost_mode()
+        {
+            PathCostMode::Shortest => PathCostMode::Safe,
+            PathCostMode::Safe => PathCostMode::Shortest,
+        };
+        self.pick_tile();
+        let text_pos = match self.pick_result.clone() {
+            PickResult::Pos(pos) => Some(pos),
+            PickResult::UnitId(unit_id) => {
+                let state = &self.player_info.get(&self.backend.perspective()).game_state;
+                Some(state.units()[&unit_id].pos.clone())
+            },
+            PickResult::None => None,
+        };
+        if let Some(pos) = text_pos {
+            let text = if mode == PathCostMode::Safe {
+                "Safe routes: on"
+            } else {
+                "Safe routes: off"
+            };
+            self.map_text_manager.add_text(&pos, text);
+        }
+        for (_, i) in self.player_info.info.iter_mut() {
+            i.pathfinder.set_cost_mode(mode.clone());
+        }
+        self.path_mesh = None;
+        self.risky_path_mesh = None;
+        if let Some(unit_id) = self.selected_unit_id.clone() {
+            let i = self.player_info.get_mut(&self.backend.perspective());
+            let state = &i.game_state;
+            let unit = &state.units()[&unit_id];
+            let (fast_mesh, hunt_mesh) = build_walkable_meshes(
+                &self.zgl, self.backend.db(), &mut i.pathfinder, state, unit);
+            self.walkable_mesh = Some(fast_mesh);
+            self.hunt_walkable_mesh = Some(hunt_mesh);
+        }
+    }
+
+    /// Observer only: shows game from next player`s point of view
+    fn switch_perspective(&mut self) {
+        if !self.backend.is_observer() || self.event_visualizer.is_some() {
+            return;
+        }
+        {
+            let i = self.player_info.get_mut(&self.backend.perspective());
+            self.selection_manager.deselect(&mut i.scene);
+        }
+        self.selected_unit_id = None;
+        self.group_unit_ids.clear();
+        self.walkable_mesh = None;
+        self.hunt_walkable_mesh = None;
+        self.path_mesh = None;
+        self.risky_path_mesh = None;
+        self.hovered_unit_id = None;
+        self.hovered_pos = None;
+        self.backend.switch_perspective();
+        self.add_map_objects();
+        self.regenerate_map_meshes();
+        self.update_hud();
+    }
+
     fn move_unit(&mut self, pos: &MapPos, move_mode: &MoveMode) {
         let unit_id = match self.selected_unit_id {
             Some(ref unit_id) => unit_id.clone(),
@@ -589,20 +1098,76 @@ impl Visualizer {
         if self.is_tile_occupied(&pos) {
             return;
         }
-        let i = self.player_info.get_mut(self.core.player_id());
+        let is_order = !self.waypoints.is_empty();
+        let i = self.player_info.get_mut(&self.backend.perspective());
         let unit = &i.game_state.units()[&unit_id];
-        if let Some(path) = i.pathfinder.get_path(&pos) {
-            let cost = if let &MoveMode::Hunt = move_mode {
-                path.total_cost().n * 2
-            } else {
-                path.total_cost().n
-            };
-            if cost > unit.move_points {
+        let path = match *move_mode {
+            MoveMode::Fast => i.pathfinder.get_path(&pos),
+            MoveMode::Hunt => {
+                let db = self.backend.db();
+                let state = &i.game_state;
+                let pf = &mut i.pathfinder;
+                let path = pf.find_path(db, state, unit, &pos, &MoveMode::Hunt);
+                // hovered paths are taken from Fast mode map
+                pf.fill_map(db, state, unit, &MoveMode::Fast);
+                path
+            },
+        };
+        if let Some(path) = path {
+            let is_far = path.total_cost().n > unit.move_points;
+            if let &MoveMode::Fast = move_mode {
+                if is_far || is_order {
+                    let mut waypoints = mem::replace(&mut self.waypoints, Vec::new());
+                    waypoints.push(pos.clone());
+                    let path = {
+                        let order = MoveOrder::new(waypoints.clone());
+                        let db = self.backend.db();
+                        let state = &i.game_state;
+                        let pf = &mut i.pathfinder;
+                        let route = match order.route(db, state, pf, unit) {
+                            Some(route) => route,
+                            None => {
+                                self.map_text_manager.add_text(
+                                    &pos, "Can not reach this tile");
+                                return;
+                            },
+                        };
+                        for (n, &index) in route.turn_ends().iter().enumerate() {
+                            let text = format!("Turn {}", n + 1);
+                            self.map_text_manager.add_text(
+                                &route.nodes()[index].pos, &text);
+                        }
+                        order.next_path(db, state, pf, unit)
+                    };
+                    let command = path.map(|path| Command::Move {
+                        unit_id: unit_id.clone(),
+                        path: path,
+                        mode: MoveMode::Fast,
+                    });
+                    if let Some(ref command) = command {
+                        if let Err(err) = self.backend.check_command(command) {
+                            self.map_text_manager.add_text(&pos, err.description());
+                            return;
+                        }
+                    }
+                    // order is stored by `Core`, its events update waypoints
+                    self.backend.do_command(Command::SetMoveOrder {
+                        unit_id: unit_id.clone(),
+                        waypoints: waypoints,
+                    });
+                    if let Some(command) = command {
+                        self.order_unit_id = Some(unit_id);
+                        self.backend.do_command(command);
+                    }
+                    return;
+                }
+            }
+            if is_far {
                 self.map_text_manager.add_text(
                     &pos, "Not enough move points");
                 return;
             }
-            self.core.do_command(Command::Move {
+            self.backend.do_command(Command::Move {
                 unit_id: unit_id,
                 path: path,
                 mode: move_mode.clone(),
@@ -664,8 +1229,79 @@ impl Visualizer {
         }
     }
 
+    /// Show attack odds or reaction fire danger of path under cursor
+    fn handle_hover(&mut self) {
+        if self.is_lmb_pressed
+            || self.is_rmb_pressed
+            || self.event_visualizer.is_some()
+        {
+            return;
+        }
+        let unit_id = match self.selected_unit_id {
+            Some(ref id) => id.clone(),
+            None => return,
+        };
+        self.pick_tile();
+        match self.pick_result.clone() {
+            PickResult::UnitId(defender_id) => {
+                self.hovered_pos = None;
+                self.show_attack_odds(&unit_id, &defender_id);
+            },
+            PickResult::Pos(pos) => {
+                self.hovered_unit_id = None;
+                self.show_path_danger(&unit_id, &pos);
+            },
+            PickResult::None => {},
+        }
+    }
+
+    fn show_attack_odds(&mut self, attacker_id: &UnitId, defender_id: &UnitId) {
+        if self.hovered_unit_id == Some(defender_id.clone()) {
+            return;
+        }
+        self.hovered_unit_id = Some(defender_id.clone());
+        let state = &self.player_info.get(&self.backend.perspective()).game_state;
+        let attacker = &state.units()[attacker_id];
+        let defender = &state.units()[defender_id];
+        if defender.player_id == attacker.player_id {
+            return;
+        }
+        let odds = state.attack_odds(
+            self.backend.db(), attacker_id, defender_id, &attacker.pos);
+        let text = format!(
+            "hit: {}%, pierce: {}%, wound: {}%, -{:.1}",
+            (odds.hit * 100.0) as ZInt,
+            (odds.pierce * 100.0) as ZInt,
+            (odds.wound * 100.0) as ZInt,
+            odds.expected_casualties
+        );
+        self.map_text_manager.add_text(&defender.pos, &text);
+    }
+
+    fn show_path_danger(&mut self, unit_id: &UnitId, pos: &MapPos) {
+        if self.hovered_pos == Some(pos.clone()) {
+            return;
+        }
+        self.hovered_pos = Some(pos.clone());
+        let i = self.player_info.get(&self.backend.perspective());
+        let path = match i.pathfinder.get_path(pos) {
+            Some(path) => path,
+            None => {
+                self.path_mesh = None;
+                self.risky_path_mesh = None;
+                return;
+            },
+        };
+        let danger = i.game_state.reaction_fire_danger(
+            self.backend.db(), unit_id, &path);
+        self.path_mesh = Some(build_path_mesh(
+            &self.zgl, &path, &danger, false));
+        self.risky_path_mesh = Some(build_path_mesh(
+            &self.zgl, &path, &danger, true));
+    }
+
     fn print_unit_info(&self, unit_id: &UnitId) {
-        let state = &self.player_info.get(self.core.player_id()).game_state;
+        let state = &self.player_info.get(&self.backend.perspective()).game_state;
         let unit = state.units().get(unit_id)
             .expect("Can`t find picked unit in current state");
         println!("player_id: {}", unit.player_id.id);
@@ -678,7 +1314,9 @@ impl Visualizer {
         }
         println!("count: {}", unit.count);
         println!("morale: {}", unit.morale);
-        let unit_type = self.core.db().unit_type(&unit.type_id);
+        println!("is_immobilized: {}", unit.is_immobilized);
+        println!("is_weapon_disabled: {}", unit.is_weapon_disabled);
+        let unit_type = self.backend.db().unit_type(&unit.type_id);
         println!("type: name: {}", unit_type.name);
         match unit_type.class {
             UnitClass::Infantry => println!("type: class: Infantry"),
@@ -694,7 +1332,8 @@ impl Visualizer {
         println!("type: reactive_ap: {}", unit_type.reactive_attack_points);
         println!("type: los_range: {}", unit_type.los_range);
         println!("type: cover_los_range: {}", unit_type.cover_los_range);
-        let weapon_type = self.core.db().weapon_type(&unit_type.weapon_type_id);
+        println!("type: spotting: {}", unit_type.spotting);
+        let weapon_type = self.backend.db().weapon_type(&unit_type.weapon_type_id);
         println!("weapon: name: {}", weapon_type.name);
         println!("weapon: damage: {}", weapon_type.damage);
         println!("weapon: ap: {}", weapon_type.ap);
@@ -703,7 +1342,7 @@ impl Visualizer {
     }
 
     fn print_terrain_info(&self, pos: &MapPos) {
-        let state = &self.player_info.get(self.core.player_id()).game_state;
+        let state = &self.player_info.get(&self.backend.perspective()).game_state;
         match state.map().tile(pos) {
             &Terrain::Trees => println!("Trees"),
             &Terrain::Plain => println!("Plain"),
@@ -747,11 +1386,34 @@ impl Visualizer {
             VirtualKeyCode::H => {
                 self.pick_tile();
                 if let PickResult::Pos(pos) = self.pick_result.clone() {
-                    self.move_unit(&pos, &MoveMode::Hunt);
+                    if self.group_unit_ids.is_empty() {
+                        self.move_unit(&pos, &MoveMode::Hunt);
+                    } else {
+                        self.move_group(&pos, &MoveMode::Hunt);
+                    }
                 } else {
                     panic!("Can`t move unit if no pos is selected");
                 }
             },
+            VirtualKeyCode::X => {
+                self.pick_tile();
+                self.try_to_assault();
+            },
+            VirtualKeyCode::Z => {
+                self.undo();
+            },
+            VirtualKeyCode::Tab => {
+                self.switch_perspective();
+            },
+            VirtualKeyCode::R => {
+                self.toggle_safe_routes();
+            },
+            VirtualKeyCode::P => {
+                self.add_waypoint();
+            },
+            VirtualKeyCode::K => {
+                self.cancel_move_order();
+            },
             VirtualKeyCode::C => {
                 let p = self.pick_world_pos();
                 self.add_marker(&p);
@@ -774,25 +1436,34 @@ impl Visualizer {
         if !self.is_tap(&self.mouse_pos) {
             return;
         }
+        if self.backend.is_observer() {
+            return;
+        }
         self.pick_tile();
         if let Some(button_id) = self.get_clicked_button_id() {
             self.handle_event_button_press(&button_id);
         }
         match self.pick_result.clone() {
             PickResult::Pos(pos) => {
-                self.move_unit(&pos, &MoveMode::Fast);
+                if self.group_unit_ids.is_empty() {
+                    self.move_unit(&pos, &MoveMode::Fast);
+                } else {
+                    self.move_group(&pos, &MoveMode::Fast);
+                }
             },
             PickResult::UnitId(unit_id) => {
                 let player_id = {
-                    let state = &self.player_info.get(self.core.player_id()).game_state;
+                    let state = &self.player_info.get(&self.backend.perspective()).game_state;
                     let unit = state.units().get(&unit_id)
                         .expect("Can`t find picked unit in current state");
                     unit.player_id.clone()
                 };
-                if player_id == *self.core.player_id() {
-                    self.select_unit();
-                } else {
+                if self.backend.perspective() != Perspective::Player(player_id) {
                     self.try_to_attack_unit();
+                } else if self.is_shift_pressed {
+                    self.toggle_group_unit(&unit_id);
+                } else {
+                    self.select_unit();
                 }
             },
             PickResult::None => {},
@@ -802,6 +1473,10 @@ impl Visualizer {
     fn handle_event_button_press(&mut self, button_id: &ButtonId) {
         if *button_id == self.button_end_turn_id {
             self.end_turn();
+        } else if *button_id == self.button_unit_type_id {
+            self.switch_unit_type();
+        } else if *button_id == self.button_points_id {
+            // just a label
         } else {
             panic!("BUTTON ID ERROR");
         }
@@ -821,6 +1496,18 @@ impl Visualizer {
     }
 
     fn handle_event(&mut self, event: &Event) {
+        if self.handoff_label.is_some() {
+            match *event {
+                Event::MouseInput(Released, _)
+                    | Event::KeyboardInput(Released, _, _) =>
+                {
+                    self.handoff_label = None;
+                    return;
+                },
+                Event::Closed | Event::Resized(..) => {},
+                _ => return,
+            }
+        }
         match *event {
             Event::Closed => {
                 self.should_close = true;
@@ -838,6 +1525,7 @@ impl Visualizer {
                     self.handle_event_mouse_move(&pos);
                 }
                 self.mouse_pos = pos.clone();
+                self.handle_hover();
             },
             Event::MouseInput(Pressed, MouseButton::Left) => {
                 self.is_lmb_pressed = true;
@@ -853,6 +1541,11 @@ impl Visualizer {
             Event::MouseInput(Released, MouseButton::Right) => {
                 self.is_rmb_pressed = false;
             },
+            Event::KeyboardInput(element_state, _, Some(VirtualKeyCode::LShift))
+                | Event::KeyboardInput(element_state, _, Some(VirtualKeyCode::RShift)) =>
+            {
+                self.is_shift_pressed = element_state == Pressed;
+            },
             Event::KeyboardInput(Released, _, Some(key)) => {
                 self.handle_event_key_press(key);
             },
@@ -871,7 +1564,7 @@ impl Visualizer {
     }
 
     fn scene(&self) -> &Scene {
-        &self.player_info.get(self.core.player_id()).scene
+        &self.player_info.get(&self.backend.perspective()).scene
     }
 
     fn draw_scene_node(
@@ -898,9 +1591,36 @@ impl Visualizer {
         }
     }
 
+    /// Translucent units at places where hidden enemies were seen,
+    /// older ones are more transparent
+    fn draw_ghosts(&self) {
+        let state = &self.player_info.get(&self.backend.perspective()).game_state;
+        let db = self.backend.db();
+        let max_age = self.backend.options().last_seen_turns + 1;
+        for (_, last_seen) in state.last_seen() {
+            let age = state.turn() - last_seen.turn;
+            let alpha = 0.5 * (max_age - age) as ZFloat / max_age as ZFloat;
+            self.shader.set_uniform_color(&self.zgl, &self.basic_color_id,
+                &Color4{r: 1.0, g: 1.0, b: 1.0, a: alpha});
+            let mesh_id = &self.unit_type_visual_info.get(&last_seen.type_id).mesh_id;
+            let node = SceneNode {
+                pos: geom::map_pos_to_world_pos(&last_seen.pos),
+                rot: rad(0.0),
+                mesh_id: None,
+                children: get_unit_scene_nodes(db, &last_seen.type_id, mesh_id),
+            };
+            self.draw_scene_node(&node, self.camera.mat(&self.zgl));
+        }
+        self.shader.set_uniform_color(
+            &self.zgl, &self.basic_color_id, &zgl::WHITE);
+    }
+
     fn draw_map(&mut self) {
         self.shader.set_uniform_mat4f(
             &self.zgl, self.shader.get_mvp_mat(), &self.camera.mat(&self.zgl));
+        self.shader.set_uniform_color(
+            &self.zgl, &self.basic_color_id, &UNEXPLORED_TILE_COLOR);
+        self.unexplored_map_mesh.draw(&self.zgl, &self.shader);
         self.shader.set_uniform_color(
             &self.zgl, &self.basic_color_id, &zgl::GREY);
         self.fow_map_mesh.draw(&self.zgl, &self.shader);
@@ -914,21 +1634,62 @@ impl Visualizer {
             &self.zgl, &self.basic_color_id, &zgl::WHITE);
         self.draw_scene_nodes();
         self.draw_map();
+        self.draw_ghosts();
         if let Some(ref walkable_mesh) = self.walkable_mesh {
             self.shader.set_uniform_color(
                 &self.zgl, &self.basic_color_id, &zgl::BLUE);
             walkable_mesh.draw(&self.zgl, &self.shader);
         }
+        if let Some(ref hunt_walkable_mesh) = self.hunt_walkable_mesh {
+            self.shader.set_uniform_color(
+                &self.zgl, &self.basic_color_id, &zgl::GREEN);
+            hunt_walkable_mesh.draw(&self.zgl, &self.shader);
+        }
+        if let Some(ref path_mesh) = self.path_mesh {
+            self.shader.set_uniform_color(
+                &self.zgl, &self.basic_color_id, &zgl::WHITE);
+            path_mesh.draw(&self.zgl, &self.shader);
+        }
+        if let Some(ref risky_path_mesh) = self.risky_path_mesh {
+            self.shader.set_uniform_color(
+                &self.zgl, &self.basic_color_id, &zgl::RED);
+            risky_path_mesh.draw(&self.zgl, &self.shader);
+        }
         if let Some(ref mut event_visualizer) = self.event_visualizer {
-            let i = self.player_info.get_mut(self.core.player_id());
+            let i = self.player_info.get_mut(&self.backend.perspective());
             event_visualizer.draw(&mut i.scene, dtime);
         }
     }
 
+    fn draw_handoff_screen(&self) {
+        let label = self.handoff_label.as_ref()
+            .expect("No handoff label");
+        let m = self.zgl.get_2d_screen_matrix(&self.win_size);
+        let text_offset = Vector3 {
+            x: label.pos().v.x as ZFloat,
+            y: label.pos().v.y as ZFloat,
+            z: 0.0,
+        };
+        self.shader.set_uniform_mat4f(
+            &self.zgl,
+            self.shader.get_mvp_mat(),
+            &self.zgl.tr(m, &text_offset),
+        );
+        self.shader.set_uniform_color(
+            &self.zgl, &self.basic_color_id, &zgl::BLACK);
+        label.draw(&self.zgl, &self.shader);
+    }
+
     fn draw(&mut self, dtime: &Time) {
         self.zgl.set_clear_color(&BG_COLOR);
         self.zgl.clear_screen();
         self.shader.activate(&self.zgl);
+        if self.handoff_label.is_some() {
+            self.draw_handoff_screen();
+            self.window.swap_buffers()
+                .ok().expect("Can`t swap buffers");
+            return;
+        }
         self.shader.set_uniform_mat4f(
             &self.zgl,
             self.shader.get_mvp_mat(),
@@ -959,7 +1720,7 @@ impl Visualizer {
         let origin_world_pos = geom::map_pos_to_world_pos(&origin);
         let mut closest_map_pos = origin.clone();
         let mut min_dist = (origin_world_pos.v - p.v).length();
-        let state = &self.player_info.get_mut(self.core.player_id()).game_state;
+        let state = &self.player_info.get_mut(&self.backend.perspective()).game_state;
         for map_pos in spiral_iter(&origin, 1) {
             let pos = geom::map_pos_to_world_pos(&map_pos);
             let d = (pos.v - p.v).length();
@@ -993,8 +1754,8 @@ impl Visualizer {
         &mut self,
         event: &CoreEvent,
     ) -> Box<EventVisualizer> {
-        let player_id = self.core.player_id();
-        let mut i = self.player_info.get_mut(player_id);
+        let perspective = self.backend.perspective();
+        let mut i = self.player_info.get_mut(&perspective);
         let scene = &mut i.scene;
         let state = &i.game_state;
         match event {
@@ -1009,6 +1770,19 @@ impl Visualizer {
                     path.clone(),
                 )
             },
+            &CoreEvent::UndoMove{ref unit_id, ref path, ..} => {
+                let type_id = state.units()[unit_id].type_id.clone();
+                let unit_type_visual_info
+                    = self.unit_type_visual_info.get(&type_id);
+                let mut nodes = path.nodes().clone();
+                nodes.reverse();
+                EventMoveVisualizer::new(
+                    scene,
+                    unit_id.clone(),
+                    unit_type_visual_info,
+                    MapPath::new(nodes),
+                )
+            },
             &CoreEvent::EndTurn{..} => {
                 EventEndTurnVisualizer::new()
             },
@@ -1020,7 +1794,7 @@ impl Visualizer {
             } => {
                 let mesh_id = &self.unit_type_visual_info.get(type_id).mesh_id;
                 EventCreateUnitVisualizer::new(
-                    &self.core,
+                    self.backend.db(),
                     scene,
                     unit_id.clone(),
                     type_id,
@@ -1035,6 +1809,7 @@ impl Visualizer {
                 ref killed,
                 ref suppression,
                 ref mode,
+                ref damage,
                 ..
             } => {
                 EventAttackUnitVisualizer::new(
@@ -1045,6 +1820,7 @@ impl Visualizer {
                     killed.clone(),
                     suppression.clone(),
                     mode.clone(),
+                    damage.clone(),
                     self.mesh_ids.shell_mesh_id.clone(),
                     &mut self.map_text_manager,
                 )
@@ -1054,10 +1830,11 @@ impl Visualizer {
                 ref pos,
                 ref type_id,
                 ref player_id,
+                ..
             } => {
                 let mesh_id = &self.unit_type_visual_info.get(type_id).mesh_id;
                 EventShowUnitVisualizer::new(
-                    &self.core,
+                    self.backend.db(),
                     scene,
                     unit_id.clone(),
                     type_id,
@@ -1075,6 +1852,19 @@ impl Visualizer {
                     &mut self.map_text_manager,
                 )
             },
+            &CoreEvent::RepairUnit{ref unit_id, ..} => {
+                EventRepairUnitVisualizer::new(
+                    state,
+                    unit_id,
+                    &mut self.map_text_manager,
+                )
+            },
+            &CoreEvent::SetReinforcementPoints{..} => {
+                EventSetReinforcementPointsVisualizer::new()
+            },
+            &CoreEvent::SetMoveOrder{..} => {
+                EventSetMoveOrderVisualizer::new()
+            },
         }
     }
 
@@ -1085,15 +1875,20 @@ impl Visualizer {
     }
 
     fn start_event_visualization(&mut self, event: CoreEvent) {
+        self.update_move_orders(&event);
         let vis = self.make_event_visualizer(&event);
         self.event = Some(event);
         self.event_visualizer = Some(vis);
         if self.is_event_visualization_finished() {
             self.end_event_visualization();
         } else {
-            let i = &mut self.player_info.get_mut(self.core.player_id());
+            let i = &mut self.player_info.get_mut(&self.backend.perspective());
             self.selection_manager.deselect(&mut i.scene);
             self.walkable_mesh = None;
+            self.hunt_walkable_mesh = None;
+            self.path_mesh = None;
+            self.risky_path_mesh = None;
+            self.hovered_pos = None;
         }
     }
 
@@ -1103,7 +1898,7 @@ impl Visualizer {
         if let Some(CoreEvent::AttackUnit{ref defender_id, ref killed, ..})
             = self.event
         {
-            let mut i = self.player_info.get_mut(self.core.player_id());
+            let mut i = self.player_info.get_mut(&self.backend.perspective());
             let state = &mut i.game_state;
             let selected_unit_id = match self.selected_unit_id {
                 Some(ref id) => id.clone(),
@@ -1119,45 +1914,59 @@ impl Visualizer {
 
     fn end_event_visualization(&mut self) {
         self.attacker_died_from_reaction_fire();
-        let mut i = self.player_info.get_mut(self.core.player_id());
-        let scene = &mut i.scene;
-        let state = &mut i.game_state;
-        if let Some(ref mut event_visualizer) = self.event_visualizer {
-            event_visualizer.end(scene, state);
-        } else {
-            panic!("end_event_visualization: self.event_visualizer == None");
-        }
-        if let Some(ref event) = self.event {
-            state.apply_event(self.core.db(), event);
-        } else {
-            panic!("end_event_visualization: self.event == None");
-        }
-        self.event_visualizer = None;
-        self.event = None;
-        if let Some(ref selected_unit_id) = self.selected_unit_id {
-            if let Some(unit) = state.units().get(selected_unit_id) {
-                // TODO: do this only if this is last unshowed CoreEvent
-                let pf = &mut i.pathfinder;
-                pf.fill_map(self.core.db(), state, unit);
-                self.walkable_mesh = Some(build_walkable_mesh(
-                    &self.zgl, pf, state.map(), unit.move_points));
-                self.selection_manager.create_selection_marker(
-                    state, scene, selected_unit_id);
+        {
+            let mut i = self.player_info.get_mut(&self.backend.perspective());
+            let scene = &mut i.scene;
+            let state = &mut i.game_state;
+            if let Some(ref mut event_visualizer) = self.event_visualizer {
+                event_visualizer.end(scene, state);
+            } else {
+                panic!("end_event_visualization: self.event_visualizer == None");
+            }
+            if let Some(ref event) = self.event {
+                state.apply_event(self.backend.db(), event);
+            } else {
+                panic!("end_event_visualization: self.event == None");
+            }
+            self.event_visualizer = None;
+            self.event = None;
+            if let Some(ref selected_unit_id) = self.selected_unit_id {
+                if let Some(unit) = state.units().get(selected_unit_id) {
+                    // TODO: do this only if this is last unshowed CoreEvent
+                    let (fast_mesh, hunt_mesh) = build_walkable_meshes(
+                        &self.zgl, self.backend.db(), &mut i.pathfinder, state, unit);
+                    self.walkable_mesh = Some(fast_mesh);
+                    self.hunt_walkable_mesh = Some(hunt_mesh);
+                    self.selection_manager.create_selection_marker(
+                        state, scene, selected_unit_id);
+                }
             }
+            self.group_unit_ids.retain(|unit_id| state.units().get(unit_id).is_some());
+            self.selection_manager.create_group_markers(state, scene, &self.group_unit_ids);
+            add_explored_map_objects(
+                state, scene, &mut i.map_objects, &self.mesh_ids.trees_mesh_id);
+            // TODO: recolor terrain objects
+            self.visible_map_mesh = generate_visible_tiles_mesh(
+                &self.zgl, state, &self.floor_tex);
+            self.fow_map_mesh = generate_fogged_tiles_mesh(
+                &self.zgl, state, &self.floor_tex);
+            self.unexplored_map_mesh = generate_unexplored_tiles_mesh(
+                &self.zgl, state, &self.floor_tex);
         }
-        // TODO: recolor terrain objects
-        self.visible_map_mesh = generate_visible_tiles_mesh(
-            &self.zgl, state, &self.floor_tex);
-        self.fow_map_mesh = generate_fogged_tiles_mesh(
-            &self.zgl, state, &self.floor_tex);
+        self.update_hud();
     }
 
     fn logic(&mut self) {
+        // incoming player`s events are shown only after handoff
+        if self.handoff_label.is_some() {
+            return;
+        }
         while self.event_visualizer.is_none() {
             // TODO: convert to iterator
-            if let Some(e) = self.core.get_event() {
+            if let Some(e) = self.backend.get_event() {
                 self.start_event_visualization(e);
             } else {
+                self.execute_next_order();
                 break;
             }
         }
@@ -14,7 +14,8 @@ use std::ffi::{CStr};
 pub const BLACK_3: Color3 = Color3{r: 0.0, g: 0.0, b: 0.0};
 pub const WHITE: Color4 = Color4{r: 1.0, g: 1.0, b: 1.0, a: 1.0};
 pub const BLUE: Color4 = Color4{r: 0.0, g: 0.0, b: 1.0, a: 1.0};
-// pub const RED: Color4 = Color4{r: 1.0, g: 0.0, b: 0.0, a: 1.0};
+pub const GREEN: Color4 = Color4{r: 0.0, g: 1.0, b: 0.0, a: 1.0};
+pub const RED: Color4 = Color4{r: 1.0, g: 0.0, b: 0.0, a: 1.0};
 pub const BLACK: Color4 = Color4{r: 0.0, g: 0.0, b: 0.0, a: 1.0};
 pub const GREY: Color4 = Color4{r: 0.7, g: 0.7, b: 0.7, a: 1.0};
 